@@ -0,0 +1,108 @@
+//! Cost of putting a chunk on the wire and reading it back, the chunk
+//! frames of `frame.rs` against the bincode packet they replaced. Both
+//! sides do what the receive path does: the chunk ends up in an owned
+//! buffer, the receive buffer is reused for the next datagram.
+//! Run with `cargo bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use serde::{Deserialize, Serialize};
+
+#[path = "../src/packet/frame.rs"]
+#[allow(dead_code)]
+mod frame;
+
+const CHUNK_SIZE: usize = 32 * 1024;
+/// Proof length of a chunk in a 1 GiB file.
+const PROOF_LEN: usize = 15;
+
+/// The chunk packet before frames, an owned copy of the chunk and the
+/// filename in every packet.
+#[derive(Serialize, Deserialize)]
+struct OldFilePacket {
+    id: u64,
+    filename: String,
+    filesize: usize,
+    chunk_index: usize,
+    total_chunks: usize,
+    data: Vec<u8>,
+    compressed: bool,
+    proof: Vec<[u8; 32]>,
+}
+
+fn chunk() -> (Vec<u8>, Vec<[u8; 32]>) {
+    let data = (0..CHUNK_SIZE).map(|i| (i * 31 % 251) as u8).collect();
+    let proof = (0..PROOF_LEN).map(|i| [i as u8; 32]).collect();
+    (data, proof)
+}
+
+fn old_packet(data: &[u8], proof: &[[u8; 32]]) -> OldFilePacket {
+    OldFilePacket {
+        id: 7,
+        filename: "holiday-photos-2024.tar".to_string(),
+        filesize: data.len(),
+        chunk_index: 42,
+        total_chunks: 32768,
+        data: data.to_vec(),
+        compressed: false,
+        proof: proof.to_vec(),
+    }
+}
+
+/// An uncompressed chunk out of a frame as `FilePacket::from_frame` takes
+/// it: header fields, an owned copy of the data and the proof.
+fn from_frame(bytes: &[u8]) -> (frame::Header, Vec<u8>, Vec<[u8; 32]>) {
+    let frame = frame::decode(bytes).unwrap();
+    assert!(frame.data.len() == frame.header.raw_len as usize && frame.data.len() <= CHUNK_SIZE);
+    (frame.header, frame.data.to_vec(), frame.proof().collect())
+}
+
+fn encode(c: &mut Criterion) {
+    let (data, proof) = chunk();
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Bytes(CHUNK_SIZE as u64));
+    // Built once, only the encoding is timed
+    let packet = old_packet(&data, &proof);
+    group.bench_function("bincode", |b| {
+        b.iter(|| black_box(bincode::serialize(black_box(&packet)).unwrap()))
+    });
+    let mut buf = Vec::new();
+    group.bench_function("frame", |b| {
+        b.iter(|| {
+            let header = frame::Header {
+                id: 7,
+                index: 42,
+                total: 32768,
+                raw_len: data.len() as u32,
+                compressed: false,
+            };
+            frame::encode(&mut buf, &header, &proof, &data);
+            black_box(&buf);
+        })
+    });
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let (data, proof) = chunk();
+    let old = bincode::serialize(&old_packet(&data, &proof)).unwrap();
+    let header = frame::Header {
+        id: 7,
+        index: 42,
+        total: 32768,
+        raw_len: data.len() as u32,
+        compressed: false,
+    };
+    let mut new = Vec::new();
+    frame::encode(&mut new, &header, &proof, &data);
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Bytes(CHUNK_SIZE as u64));
+    group.bench_function("bincode", |b| {
+        b.iter(|| black_box(bincode::deserialize::<OldFilePacket>(black_box(&old)).unwrap()))
+    });
+    group.bench_function("frame", |b| b.iter(|| black_box(from_frame(black_box(&new)))));
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
@@ -0,0 +1,209 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, fs, io, path::PathBuf};
+
+use crate::user::transfers::DEFAULT_MAX_TRANSFERS;
+
+const CONFIG_DIR: &str = "connect-p2p";
+const CONFIG_FILE: &str = "config.toml";
+const DEFAULT_OUTBOX_DAYS: u64 = 7;
+
+/// What to do when a received file already exists in the download directory.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    Rename,
+    Overwrite,
+    Skip,
+}
+
+/// A folder peers can list with `browse:` and fetch from with `get:`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShareConfig {
+    pub path: PathBuf,
+    /// Every connected peer may browse it and fetch from it without a
+    /// prompt. Gets from a private share are confirmed.
+    #[serde(default)]
+    pub public: bool,
+    /// Full peer fingerprints or keys, or groups of them, that may browse
+    /// a private share. Names are never matched, anyone can pick one.
+    #[serde(default)]
+    pub peers: Vec<String>,
+}
+
+/// Bytes per second, written like `500K`, `2M` or `1.5G`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rate(pub u64);
+
+impl Rate {
+    /// `None` for `0`, `off` or `-`, which lift a limit.
+    pub fn parse(text: &str) -> Result<Option<Rate>, String> {
+        let text = text.trim();
+        if matches!(text, "0" | "off" | "-") {
+            return Ok(None);
+        }
+        let split = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let scale = match unit.trim().to_ascii_uppercase().trim_end_matches(['B', '/', 'S']) {
+            "" => 1.0,
+            "K" | "KI" => 1024.0,
+            "M" | "MI" => 1024.0 * 1024.0,
+            "G" | "GI" => 1024.0 * 1024.0 * 1024.0,
+            _ => return Err(format!("Unknown unit in rate {:?}", text)),
+        };
+        match number.parse::<f64>() {
+            Ok(n) if n > 0.0 => Ok(Some(Rate(((n * scale) as u64).max(1)))),
+            Ok(_) => Ok(None),
+            Err(_) => Err(format!("Invalid rate {:?}", text)),
+        }
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [(&str, u64); 3] = [("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+        match UNITS.iter().find(|(_, size)| self.0 >= *size) {
+            Some((unit, size)) => {
+                let n = self.0 as f64 / *size as f64;
+                write!(f, "{}{}", (n * 100.0).round() / 100.0, unit)
+            }
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl TryFrom<String> for Rate {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, String> {
+        Rate::parse(&text)?.ok_or_else(|| "a rate of 0, leave the limit out instead".to_string())
+    }
+}
+
+impl From<Rate> for String {
+    fn from(rate: Rate) -> String {
+        rate.to_string()
+    }
+}
+
+/// Upload and download caps, unlimited when left out.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateLimit {
+    pub upload: Option<Rate>,
+    pub download: Option<Rate>,
+}
+
+/// Caps on the file data of all transfers together, and of each peer's.
+/// Chat and control packets are never held back.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct LimitConfig {
+    pub upload: Option<Rate>,
+    pub download: Option<Rate>,
+    /// Caps by full peer fingerprint or key. Names are never matched.
+    pub peers: BTreeMap<String, RateLimit>,
+}
+
+/// Settings read from `<config dir>/connect-p2p/config.toml`, every field
+/// falls back to its default when missing.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    pub download_dir: PathBuf,
+    pub allow_hidden: bool,
+    pub on_conflict: ConflictPolicy,
+    /// Outgoing transfers streaming at once, later ones wait in the queue.
+    pub max_transfers: usize,
+    /// Days a queued file or message waits for its peer to connect.
+    pub outbox_days: u64,
+    /// Receive in groups of chunks with repair symbols, for lossy links.
+    pub fec: bool,
+    /// Named sets of peer names or fingerprints for `@group` selectors.
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Shared folders by the name peers see.
+    pub shares: BTreeMap<String, ShareConfig>,
+    /// Transfer rate caps, changed at runtime with `limit:`.
+    pub limits: LimitConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let download_dir = dirs::download_dir()
+            .map(|d| d.join(CONFIG_DIR))
+            .unwrap_or_else(|| PathBuf::from("downloads"));
+        Config {
+            download_dir,
+            allow_hidden: false,
+            on_conflict: ConflictPolicy::Rename,
+            max_transfers: DEFAULT_MAX_TRANSFERS,
+            outbox_days: DEFAULT_OUTBOX_DAYS,
+            fec: false,
+            groups: BTreeMap::new(),
+            shares: BTreeMap::new(),
+            limits: LimitConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join(CONFIG_DIR).join(CONFIG_FILE))
+    }
+
+    pub fn load() -> io::Result<Self> {
+        let path = match Self::path() {
+            Some(p) => p,
+            None => return Ok(Config::default()),
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = Self::path()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let text = toml::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        fs::write(path, text)
+    }
+}
+
+/// Where a state file lives, next to the config.
+pub fn state_path(file: &str) -> Option<PathBuf> {
+    Config::path().and_then(|p| p.parent().map(|d| d.join(file)))
+}
+
+/// Reads a bincode state file kept next to the config, empty when missing.
+pub fn load_state<T: DeserializeOwned + Default>(file: &str) -> io::Result<T> {
+    let path = match state_path(file) {
+        Some(p) => p,
+        None => return Ok(T::default()),
+    };
+    match fs::read(&path) {
+        Ok(bytes) => bincode::deserialize(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Written to a temporary file first so a crash never leaves half a state file.
+pub fn save_state<T: Serialize>(file: &str, state: &T) -> io::Result<()> {
+    let path = state_path(file)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let bytes = bincode::serialize(state)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(tmp, path)
+}
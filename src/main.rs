@@ -1,83 +1,131 @@
+mod config;
 mod packet;
+mod routes;
 mod stun;
+mod transport;
 mod user;
 
-pub type SenderAck = broadcast::Sender<Option<(Packet, SocketAddr)>>;
-pub type ReceiverAck = broadcast::Receiver<Option<(Packet, SocketAddr)>>;
 pub type ReceiverRes = broadcast::Receiver<String>;
 pub type SenderRes = broadcast::Sender<String>;
 
 use bs58;
+use config::Config;
 use crossterm::{
     cursor::MoveTo,
     execute,
     style::{Color, Print, ResetColor, SetForegroundColor},
     terminal::{Clear, ClearType},
 };
-use packet::Packet;
+use packet::{session, Packet};
+use routes::Routes;
+use transport::RecvBatch;
+use user::pipe::Pipe;
 use std::{
     io::{self, stdin, Write},
     net::{IpAddr, SocketAddr},
     sync::Arc,
 };
+use rustyline::{error::ReadlineError, history::DefaultHistory, Editor};
 use tokio::{
     net::UdpSocket,
     signal,
     sync::{broadcast, Mutex},
 };
-use user::User;
+use user::{
+    complete::PeerCompleter,
+    history::History,
+    identity::{self, Identity},
+    outbox::{self, Outbox},
+    sync::{self, SyncDb},
+    User,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    print_heading();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() == 1 && args[0] == "bench" {
+        transport::bench().await?;
+        return Ok(());
+    }
+    let pipe = match Pipe::from_args(&args) {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let name = match &pipe {
+        Some(pipe) => pipe.name.clone(),
+        None => read_name()?,
+    };
 
-    execute!(
-        io::stdout(),
-        SetForegroundColor(Color::Green),
-        Print("Enter your public name: "),
-        ResetColor
-    )?;
+    let (tx, res_rx) = broadcast::channel(16);
+    // Hands every transfer its own packets
+    let routes = Routes::default();
 
-    io::stdout().flush().unwrap();
-    let mut name = String::with_capacity(50);
-    loop {
-        name.clear();
-        stdin().read_line(&mut name)?;
-        let trimmed = name.trim();
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error reading config, using defaults \n{}", e);
+            Config::default()
+        }
+    };
+    let identity = match Identity::load_or_create() {
+        Ok(i) => i,
+        Err(e) => {
+            eprintln!("Error reading identity key, using a temporary one \n{}", e);
+            Identity::ephemeral()
+        }
+    };
 
-        if trimmed.is_empty() {
-            execute!(
-                io::stdout(),
-                SetForegroundColor(Color::Red),
-                Print("\n[!] Name cannot be empty! Please re-enter: "),
-                ResetColor
-            )?;
-            io::stdout().flush().unwrap();
-            continue;
+    let sync = match SyncDb::load() {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Error reading sync state, starting without synced folders \n{}", e);
+            SyncDb::default()
         }
-        break;
-    }
-    execute!(
-        io::stdout(),
-        SetForegroundColor(Color::Cyan),
-        Print(format!("\n✅ Welcome, {}!\n", name.trim())),
-        ResetColor
-    )?;
+    };
 
-    let (tx, res_rx) = broadcast::channel(16);
-    let (file_tx, file_rx) = broadcast::channel(16);
+    let outbox = match Outbox::load() {
+        Ok(outbox) => outbox,
+        Err(e) => {
+            eprintln!("Error reading the outbox, starting with an empty one \n{}", e);
+            Outbox::default()
+        }
+    };
 
-    let user = User::new(name.trim().to_string());
-    let user_lock = Arc::new(Mutex::new(user));
+    let history = match History::load() {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("Error reading the transfer history \n{}", e);
+            History::default()
+        }
+    };
 
     let socket = Arc::new(UdpSocket::bind("[::]:0").await?);
+    transport::set_buffers(&socket);
     let public = stun::get_public(&socket).await?;
-    if let IpAddr::V6(ip) = public.ip() {
-        let octet = ip.octets();
-        let addr = bs58::encode(octet).into_string();
-        let port = bs58::encode(u16::to_be_bytes(public.port())).into_string();
-
-        println!("\nYour Addr: {}/{}\n", addr, port);
+    let fingerprint = identity::fingerprint(&identity.public());
+    let user = User::new(name, config, identity, sync, outbox, history);
+    if let Some(pipe) = pipe {
+        // stdout may carry the stream, so everything else goes to stderr
+        if let Some(addr) = encode_addr(public) {
+            eprintln!("Your Addr: {}", addr);
+        }
+        eprintln!("Fingerprint: {}", fingerprint);
+        let code = match pipe.run(&socket, &user).await {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Stream failed, {}", e);
+                1
+            }
+        };
+        std::process::exit(code);
+    }
+    let user_lock = Arc::new(Mutex::new(user));
+    if let Some(addr) = encode_addr(public) {
+        println!("\nYour Addr: {}", addr);
+        println!("Fingerprint: {}\n", fingerprint);
         "Type 'help:' for help";
         execute!(
             io::stdout(),
@@ -88,22 +136,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let socket_clone = socket.clone();
-    tokio::spawn(sender(socket_clone, user_lock.clone(), tx, file_rx));
+    tokio::spawn(sender(socket_clone, user_lock.clone(), tx, routes.clone()));
     tokio::spawn(handle_ctrl_c(user_lock.clone(), socket.clone()));
+    sync::start_all(&socket, &user_lock).await;
+    tokio::spawn(outbox::run(socket.clone(), user_lock.clone(), routes.clone()));
 
-    let mut buf = [0; 2048];
+    let mut batch = RecvBatch::new();
     loop {
-        match socket.recv_from(&mut buf).await {
-            Ok((size, addr)) => {
-                handle_message(
-                    &socket,
-                    user_lock.clone(),
-                    &buf[..size],
-                    addr,
-                    res_rx.resubscribe(),
-                    file_tx.clone(),
-                )
-                .await;
+        match batch.recv(&socket).await {
+            Ok(()) => {
+                for (bytes, addr) in batch.iter() {
+                    let Some(bytes) = session::open(addr, bytes) else {
+                        continue;
+                    };
+                    handle_message(
+                        &socket,
+                        user_lock.clone(),
+                        &bytes,
+                        addr,
+                        res_rx.resubscribe(),
+                        &routes,
+                    )
+                    .await;
+                }
             },
             Err(e) => println!("Error reciving msg, {}", e),
         }
@@ -111,35 +166,104 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 async fn handle_message(
-    socket: &UdpSocket,
+    socket: &Arc<UdpSocket>,
     user_lock: Arc<Mutex<User>>,
     bytes: &[u8],
     addr: SocketAddr,
     res_rx: ReceiverRes,
-    tx: SenderAck,
+    routes: &Routes,
 ) {
     if let Some(packet) = Packet::deserialize(bytes) {
         match &packet {
-            Packet::Chat(c) => c.display(),
+            Packet::Chat(c) => {
+                if let Some(id) = c.id {
+                    // Acked every time, the sender repeats it until an ack gets through
+                    let _ = Packet::create_ackpacket(id, 0).send_packet(socket, &addr).await;
+                    if !user_lock.lock().await.first_delivery(id) {
+                        return;
+                    }
+                }
+                c.display()
+            }
             Packet::Bind(_) => {
                 packet
                     .handle_binding(&socket, user_lock, addr, res_rx)
                     .await
             }
             Packet::Metadata(pac) => {
-                if let Err(e) = pac.receive_file(&socket, addr, user_lock, res_rx).await {
-                    println!("error reciving first file packet \n{}", e);
-                }
+                // A `get:` waiting for this offer stops waiting
+                routes.deliver(packet.clone(), addr);
+                // Opened before accepting so no chunk is missed, helpers send them too
+                let route = routes.open(pac.id, None);
+                let socket = socket.clone();
+                let pac = pac.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = pac.receive_file(&socket, addr, user_lock, res_rx, route).await {
+                        println!("error reciving first file packet \n{}", e);
+                    }
+                });
             }
-            Packet::MdRes(_) => {
-                if let Err(e) = tx.send(Some((packet.clone(), addr))) {
-                    eprintln!("Error transmitting MdRes packet: {}", e)
-                }
+            Packet::Want(want) => {
+                let route = routes.open(want.id, Some(addr));
+                let socket = socket.clone();
+                let want = want.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = user::handle_want(socket, user_lock, addr, want, route).await {
+                        println!("Error serving chunks to {}, {}", addr, e);
+                    }
+                });
             }
-            Packet::Ack(_) => {
-                if let Err(e) = tx.send(Some((packet.clone(), addr))) {
-                    eprintln!("Error transmitting ack packet: {}", e)
-                }
+            Packet::Browse(browse) => {
+                let socket = socket.clone();
+                let browse = browse.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = user::handle_browse(&socket, &user_lock, addr, &browse).await {
+                        println!("Error listing shares for {}, {}", addr, e);
+                    }
+                });
+            }
+            Packet::Get(get) => {
+                let (socket, routes) = (socket.clone(), routes.clone());
+                let get = get.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = user::handle_get(socket, user_lock, addr, get, res_rx, routes).await {
+                        println!("Error serving request from {}, {}", addr, e);
+                    }
+                });
+            }
+            Packet::Sync(manifest) => {
+                let socket = socket.clone();
+                let manifest = manifest.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = sync::handle_sync(&socket, &user_lock, addr, manifest).await {
+                        println!("Error syncing with {}, {}", addr, e);
+                    }
+                });
+            }
+            Packet::File(_)
+            | Packet::Listing(_)
+            | Packet::Group(_)
+            | Packet::Repair(_)
+            | Packet::Zero(_)
+            | Packet::Delta(_)
+            | Packet::Reject(_)
+            | Packet::Error(_)
+            | Packet::Complete(_)
+            | Packet::Have(_)
+            | Packet::MdRes(_)
+            | Packet::Ack(_) => {
+                // Nothing waits for packets of finished or unknown transfers
+                routes.deliver(packet, addr);
+            }
+            Packet::Transfer(t) => user_lock.lock().await.remote_transfer(addr, t),
+            Packet::Room(room) => {
+                let socket = socket.clone();
+                let room = room.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = room.handle(&socket, addr, user_lock).await {
+                        eprintln!("Error handling room packet, {}", e);
+                    }
+                });
             }
             _ => println!("working on this part"),
         }
@@ -150,19 +274,35 @@ async fn sender(
     socket: Arc<UdpSocket>,
     user_lock: Arc<Mutex<User>>,
     tx: SenderRes,
-    file_rx: ReceiverAck,
+    routes: Routes,
 ) {
-    let mut buf = String::new();
+    let mut editor: Editor<PeerCompleter, DefaultHistory> = match Editor::new() {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("Failed to open the terminal, {}", e);
+            return;
+        }
+    };
+    editor.set_helper(Some(PeerCompleter::new(user_lock.clone())));
     loop {
-        let ack_rx = file_rx.resubscribe();
         let socket_clone = socket.clone();
-        buf.clear();
-        print!(">");
-        io::stdout().flush().unwrap();
-        if stdin().read_line(&mut buf).is_err() {
-            eprintln!("Failed to read from stdin");
-            continue;
+        // Hands the worker over so tasks spawned by the last command keep running
+        let line = tokio::task::block_in_place(|| editor.readline(">"));
+        let mut buf = match line {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                exit(&user_lock, &socket).await;
+                return;
+            }
+            Err(e) => {
+                eprintln!("Failed to read from stdin, {}", e);
+                continue;
+            }
+        };
+        if !buf.trim().is_empty() {
+            let _ = editor.add_history_entry(buf.as_str());
         }
+        buf.push('\n');
         let mut user = {
             let lock = user_lock.lock().await;
             lock.clone()
@@ -173,7 +313,7 @@ async fn sender(
             }
             continue;
         }
-        user.handle_input(socket_clone, buf.clone(), user_lock.clone(), ack_rx)
+        user.handle_input(socket_clone, buf.clone(), user_lock.clone(), routes.clone())
             .await;
     }
 }
@@ -184,13 +324,65 @@ async fn handle_ctrl_c(user_lock: Arc<Mutex<User>>, socket: Arc<UdpSocket>) {
         if let Err(e) = signal::ctrl_c().await {
             eprintln!("Error listening for shutdown signal: {}", e);
         }
-        println!("\nExiting... Disconnecting peers.");
+        exit(&user_lock_clone, &socket).await;
+    });
+}
 
-        let user = user_lock_clone.lock().await;
-        user.disconnect_all(&socket).await;
+async fn exit(user_lock: &Mutex<User>, socket: &UdpSocket) {
+    println!("\nExiting... Disconnecting peers.");
 
-        std::process::exit(0);
-    });
+    let user = user_lock.lock().await;
+    user.disconnect_all(socket).await;
+
+    std::process::exit(0);
+}
+
+/// The base58 form peers type after `con:`.
+fn encode_addr(addr: SocketAddr) -> Option<String> {
+    let IpAddr::V6(ip) = addr.ip() else {
+        return None;
+    };
+    let port = bs58::encode(u16::to_be_bytes(addr.port())).into_string();
+    Some(format!("{}/{}", bs58::encode(ip.octets()).into_string(), port))
+}
+
+/// Asks for the public name shown to peers.
+fn read_name() -> io::Result<String> {
+    print_heading();
+
+    execute!(
+        io::stdout(),
+        SetForegroundColor(Color::Green),
+        Print("Enter your public name: "),
+        ResetColor
+    )?;
+
+    io::stdout().flush().unwrap();
+    let mut name = String::with_capacity(50);
+    loop {
+        name.clear();
+        stdin().read_line(&mut name)?;
+        let trimmed = name.trim();
+
+        if trimmed.is_empty() {
+            execute!(
+                io::stdout(),
+                SetForegroundColor(Color::Red),
+                Print("\n[!] Name cannot be empty! Please re-enter: "),
+                ResetColor
+            )?;
+            io::stdout().flush().unwrap();
+            continue;
+        }
+        break;
+    }
+    execute!(
+        io::stdout(),
+        SetForegroundColor(Color::Cyan),
+        Print(format!("\n✅ Welcome, {}!\n", name.trim())),
+        ResetColor
+    )?;
+    Ok(name.trim().to_string())
 }
 
 fn print_heading() {
@@ -0,0 +1,159 @@
+use crossterm::{
+    execute,
+    style::{Color, Print, ResetColor, SetForegroundColor},
+};
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::dir::{hash_file, sanitize_path};
+
+/// Files listed per `browse:` page, keeps a listing well inside one datagram.
+pub const PAGE_LEN: usize = 20;
+/// Longer paths are left out of listings for the same reason.
+const MAX_PATH_LEN: usize = 1024;
+/// How long `browse:` waits for the listing.
+pub const BROWSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long `get:` waits for the owner to offer the file, hashing and a
+/// confirmation prompt on the owner's side included.
+pub const GET_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Asks a peer for one page of the folders it shares with us, pages start at 1.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BrowsePacket {
+    pub id: u64,
+    pub page: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListEntry {
+    /// `<share>/<path inside the share>`, what `get:` expects.
+    pub path: String,
+    pub size: u64,
+    pub hash: [u8; 32],
+}
+
+/// Answer to a `BrowsePacket`, `total` counts the files of every page.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListingPacket {
+    pub id: u64,
+    pub page: u32,
+    pub pages: u32,
+    pub total: u64,
+    pub entries: Vec<ListEntry>,
+}
+
+/// Requests a file or folder from a peer's shares. The owner answers with a
+/// regular offer carrying the same `id`, or with a reject or error.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetPacket {
+    pub id: u64,
+    pub path: String,
+    /// Set when `path` is inside a folder synced with the owner rather than a share.
+    pub folder: Option<String>,
+}
+
+impl ListingPacket {
+    /// Builds the requested page, only the files on it are hashed.
+    pub async fn build(id: u64, page: u32, files: &[(String, PathBuf, u64)]) -> io::Result<Self> {
+        let pages = files.len().div_ceil(PAGE_LEN).max(1) as u32;
+        let page = page.clamp(1, pages);
+        let start = (page as usize - 1) * PAGE_LEN;
+        let mut entries = Vec::with_capacity(PAGE_LEN);
+        for (path, full, size) in files.iter().skip(start).take(PAGE_LEN) {
+            entries.push(ListEntry {
+                path: path.clone(),
+                size: *size,
+                hash: hash_file(full).await?,
+            });
+        }
+        Ok(ListingPacket {
+            id,
+            page,
+            pages,
+            total: files.len() as u64,
+            entries,
+        })
+    }
+
+    pub fn display(&self, from: &str) {
+        if self.entries.is_empty() {
+            println!("{} shares nothing with you", from);
+            return;
+        }
+        let mut text = format!(
+            "\nShared by {}, page {}/{} ({} files)\n",
+            from, self.page, self.pages, self.total
+        );
+        for entry in self.entries.iter() {
+            let hash = blake3::Hash::from(entry.hash).to_hex();
+            text.push_str(&format!(
+                "  {:>10}  {}  {}\n",
+                HumanBytes(entry.size).to_string(),
+                &hash[..16],
+                entry.path
+            ));
+        }
+        if self.page < self.pages {
+            text.push_str(&format!("  next page: browse:{} {}\n", from, self.page + 1));
+        }
+        if let Err(e) = execute!(
+            io::stdout(),
+            SetForegroundColor(Color::Cyan),
+            Print(text),
+            ResetColor
+        ) {
+            println!("Error Printing listing, {}", e);
+        }
+    }
+}
+
+/// Files below a shared folder as `(<share>/<path>, full path, size)`.
+/// Hidden entries and symlinks are never listed.
+pub async fn list_share(share: &str, root: &Path) -> io::Result<Vec<(String, PathBuf, u64)>> {
+    let mut files = Vec::new();
+    let mut stack = vec![(root.to_path_buf(), share.to_string())];
+    while let Some((dir, rel)) = stack.pop() {
+        let mut read_dir = tokio::fs::read_dir(&dir).await?;
+        while let Some(child) = read_dir.next_entry().await? {
+            let name = child.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+            let child_rel = format!("{}/{}", rel, name);
+            let metadata = tokio::fs::symlink_metadata(child.path()).await?;
+            if metadata.is_dir() {
+                stack.push((child.path(), child_rel));
+            } else if metadata.is_file() && child_rel.len() <= MAX_PATH_LEN {
+                files.push((child_rel, child.path(), metadata.len()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Maps the path inside a share from a `get:` request to a file or folder
+/// below `root`. Hidden components, `..` and symlinks leading out are refused.
+pub async fn resolve(root: &Path, rel: &str) -> io::Result<PathBuf> {
+    let not_shared = || io::Error::new(io::ErrorKind::NotFound, "not shared");
+    let rel = rel.trim_matches('/');
+    let path = if rel.is_empty() {
+        root.to_path_buf()
+    } else {
+        match sanitize_path(rel, false) {
+            Ok(Some(rel)) => root.join(rel),
+            _ => return Err(not_shared()),
+        }
+    };
+    let root = tokio::fs::canonicalize(root).await?;
+    let path = tokio::fs::canonicalize(&path).await.map_err(|_| not_shared())?;
+    if !path.starts_with(&root) {
+        return Err(not_shared());
+    }
+    Ok(path)
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+    sync::{Arc, Mutex, OnceLock},
+};
+use tokio::sync::OnceCell;
+
+use super::compress;
+use super::file::CHUNK_SIZE;
+use super::frame::{self, Header};
+use super::source::Source;
+use super::tree::{ChainingValue, HashTree};
+
+/// Chunks kept per offer, 16 MiB.
+const CAPACITY: usize = 512;
+
+/// A chunk read once and sent to every peer that asks for it.
+pub struct Chunk {
+    pub raw: Vec<u8>,
+    packed: OnceLock<Option<Vec<u8>>>,
+    pub proof: Vec<ChainingValue>,
+}
+
+impl Chunk {
+    /// Compressed on first use, `None` when that would not make it smaller.
+    fn packed(&self) -> Option<&[u8]> {
+        self.packed.get_or_init(|| compress::compress(&self.raw)).as_deref()
+    }
+
+    /// Writes the chunk as a frame into `buf`, straight from the cache.
+    pub fn encode(&self, buf: &mut Vec<u8>, id: u64, index: usize, total_chunks: usize, compress: bool) {
+        let packed = if compress { self.packed() } else { None };
+        let header = Header {
+            id,
+            index: index as u32,
+            total: total_chunks as u32,
+            raw_len: self.raw.len() as u32,
+            compressed: packed.is_some(),
+        };
+        frame::encode(buf, &header, &self.proof, packed.unwrap_or(&self.raw));
+    }
+}
+
+type Slot = Arc<OnceCell<Arc<Chunk>>>;
+
+#[derive(Default)]
+struct Lru {
+    slots: HashMap<usize, (Slot, u64)>,
+    /// Last use of each chunk, oldest first.
+    order: BTreeMap<u64, usize>,
+    clock: u64,
+}
+
+impl Lru {
+    fn slot(&mut self, index: usize) -> Slot {
+        self.clock += 1;
+        let slot = match self.slots.get_mut(&index) {
+            Some((slot, used)) => {
+                self.order.remove(used);
+                *used = self.clock;
+                slot.clone()
+            }
+            None => {
+                let slot = Slot::default();
+                self.slots.insert(index, (slot.clone(), self.clock));
+                slot
+            }
+        };
+        self.order.insert(self.clock, index);
+        while self.slots.len() > CAPACITY {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.slots.remove(&oldest);
+            }
+        }
+        slot
+    }
+}
+
+/// Reads the content of an offer for every peer it is sent to, so a file
+/// sent to several peers is read and compressed once while they keep
+/// roughly the same pace. The least recently used chunks are dropped past
+/// `CAPACITY`, a peer far behind the others reads its chunks again.
+pub struct ChunkCache {
+    source: Source,
+    tree: Arc<HashTree>,
+    chunks: Mutex<Lru>,
+}
+
+impl fmt::Debug for ChunkCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkCache")
+            .field("source", &self.source)
+            .field("size", &self.tree.size())
+            .finish()
+    }
+}
+
+impl ChunkCache {
+    pub fn new(source: Source, tree: Arc<HashTree>) -> Self {
+        ChunkCache {
+            source,
+            tree,
+            chunks: Mutex::new(Lru::default()),
+        }
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    pub fn tree(&self) -> &HashTree {
+        &self.tree
+    }
+
+    /// Chunk `index`, 1-based. Peers asking for it at the same time wait for one read.
+    pub async fn get(&self, index: usize) -> io::Result<Arc<Chunk>> {
+        let slot = self.chunks.lock().expect("chunk cache poisoned").slot(index);
+        let chunk = slot
+            .get_or_try_init(|| async {
+                let mut raw = vec![0; CHUNK_SIZE];
+                let n = self.source.read_at((index as u64 - 1) * CHUNK_SIZE as u64, &mut raw).await?;
+                raw.truncate(n);
+                Ok::<_, io::Error>(Arc::new(Chunk {
+                    raw,
+                    packed: OnceLock::new(),
+                    proof: self.tree.proof(index - 1),
+                }))
+            })
+            .await?;
+        Ok(chunk.clone())
+    }
+}
@@ -12,6 +12,8 @@ pub struct ChatPacket {
     username: String,
     message: String,
     time: u64,
+    /// Set on a message from the outbox, the receiver acks it by this id.
+    pub id: Option<u64>,
 }
 
 impl ChatPacket {
@@ -21,11 +23,26 @@ impl ChatPacket {
             username: name,
             message: message.trim().to_string(),
             time,
+            id: None,
         }
     }
+    /// A message written earlier, shown with the time it was written.
+    pub fn written_at(name: String, message: String, time: i64, id: u64) -> Self {
+        ChatPacket {
+            username: name,
+            message: message.trim().to_string(),
+            time: time as u64,
+            id: Some(id),
+        }
+    }
+
     pub fn display(&self) {
-        let time = Local.timestamp_opt(self.time as i64, 0).unwrap();
-        let msg = format!("[{}] {}: {} \n", time.format("%H:%M:%S"), self.username, self.message);
+        let time = Local
+            .timestamp_opt(self.time as i64, 0)
+            .single()
+            .map(|t| t.format("%H:%M:%S").to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let msg = format!("[{}] {}: {} \n", time, self.username, self.message);
         if let Err(e) = execute!(
             io::stdout(),
             SetForegroundColor(Color::Yellow),
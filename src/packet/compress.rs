@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+
+use super::{file::CHUNK_SIZE, source::Source};
+
+/// Chunks sampled from an offer to decide whether compressing it pays off.
+const SAMPLES: u64 = 8;
+
+/// Chunk compression a sender offers and the receiver takes up in its answer.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Lz4,
+}
+
+/// Compresses a few chunks spread over the content. Already compressed
+/// formats (archives, media) don't shrink and are sent as they are.
+pub async fn sample(source: &Source, size: u64) -> io::Result<Option<Compression>> {
+    let chunks = size.div_ceil(CHUNK_SIZE as u64);
+    if chunks == 0 {
+        return Ok(None);
+    }
+    let mut buf = vec![0; CHUNK_SIZE];
+    let (mut raw, mut packed) = (0, 0);
+    for i in 0..SAMPLES.min(chunks) {
+        let index = i * chunks / SAMPLES.min(chunks);
+        let n = source.read_at(index * CHUNK_SIZE as u64, &mut buf).await?;
+        raw += n;
+        packed += lz4_flex::block::compress(&buf[..n]).len();
+    }
+    // Worth it when the samples shrink by a tenth
+    Ok((packed * 10 < raw * 9).then_some(Compression::Lz4))
+}
+
+/// Compressed chunk, `None` when it would not be smaller.
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let packed = lz4_flex::block::compress(data);
+    (packed.len() < data.len()).then_some(packed)
+}
+
+/// `len` is the chunk's raw size, chunks never exceed `CHUNK_SIZE`.
+pub fn decompress(data: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+    if len > CHUNK_SIZE {
+        return Err(invalid(format!("chunk of {} bytes", len)));
+    }
+    let raw = lz4_flex::block::decompress(data, len).map_err(|e| invalid(e.to_string()))?;
+    if raw.len() != len {
+        return Err(invalid("chunk size mismatch".to_string()));
+    }
+    Ok(raw)
+}
@@ -0,0 +1,448 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Smallest block the receiver signs.
+const MIN_BLOCK: u64 = 2 * 1024;
+/// Signatures of an old version must fit in the accepting `MetadataRes`,
+/// larger files get larger blocks instead.
+const MAX_BLOCKS: u64 = 2500;
+/// The sender keeps the delta of a new version in memory while serving it,
+/// bigger files go in full.
+pub const MAX_DELTA_SIZE: u64 = 256 * 1024 * 1024;
+/// Deltas encoded or served at once, further receivers of an update get
+/// the full content instead of waiting.
+const MAX_DELTAS: usize = 2;
+/// Bytes of the new version read at a time while encoding.
+const READ_AHEAD: usize = 1024 * 1024;
+/// How long the receiver waits for the sender to pick delta or full content.
+pub const DELTA_TIMEOUT: Duration = Duration::from_secs(30);
+
+const OP_COPY: u8 = 0;
+const OP_LITERAL: u8 = 1;
+/// Literal runs are split so a decoder never buffers more than this.
+const MAX_LITERAL: usize = 1024 * 1024;
+
+static DELTAS: Semaphore = Semaphore::const_new(MAX_DELTAS);
+
+/// Weak rolling checksum and truncated BLAKE3 of one block of the old version.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BlockSig {
+    pub weak: u32,
+    pub strong: [u8; 16],
+}
+
+/// Sent by a receiver that already has an older version of an offered file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Signatures {
+    pub block: u32,
+    pub blocks: Vec<BlockSig>,
+}
+
+/// The sender diffed the new version against the receiver's signatures and
+/// serves the encoded delta, verified chunk by chunk against `hash`, instead
+/// of the content. `id` is the offer's.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeltaPacket {
+    pub id: u64,
+    pub hash: [u8; 32],
+    pub size: u64,
+}
+
+/// rsync's rolling checksum, two 16 bit sums over a window of bytes.
+#[derive(Default)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let mut sum = Rolling {
+            len: data.len() as u32,
+            ..Default::default()
+        };
+        for (i, x) in data.iter().enumerate() {
+            sum.a = sum.a.wrapping_add(*x as u32);
+            sum.b = sum.b.wrapping_add((data.len() - i) as u32 * *x as u32);
+        }
+        sum
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+
+    /// Slides the window one byte forward.
+    fn roll(&mut self, out: u8, inp: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inp as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+}
+
+fn strong(data: &[u8]) -> [u8; 16] {
+    let mut hash = [0; 16];
+    hash.copy_from_slice(&blake3::hash(data).as_bytes()[..16]);
+    hash
+}
+
+fn block_size(size: u64) -> u64 {
+    size.div_ceil(MAX_BLOCKS).max(MIN_BLOCK).next_multiple_of(1024)
+}
+
+impl Signatures {
+    /// Signs every whole block of the file at `path`, a short tail is left out.
+    pub async fn of_file(path: &Path) -> io::Result<Self> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let mut file = std::fs::File::open(&path)?;
+            let block = block_size(file.metadata()?.len());
+            let mut buf = vec![0; block as usize];
+            let mut blocks = Vec::new();
+            loop {
+                match file.read_exact(&mut buf) {
+                    Ok(()) => blocks.push(BlockSig {
+                        weak: Rolling::new(&buf).digest(),
+                        strong: strong(&buf),
+                    }),
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(Signatures {
+                block: block as u32,
+                blocks,
+            })
+        })
+        .await?
+    }
+}
+
+/// A slot to encode and serve one delta in, held until the transfer ends.
+/// `None` while `MAX_DELTAS` others are in use.
+pub fn slot() -> Option<SemaphorePermit<'static>> {
+    DELTAS.try_acquire().ok()
+}
+
+/// Encodes the new version at `path` as references to blocks of the old
+/// version and literal data. `None` when the delta would not be smaller.
+/// The file is read through a window of a few blocks and pending literal
+/// bytes, only the delta is kept whole.
+pub async fn encode(path: PathBuf, sigs: Signatures) -> io::Result<Option<Vec<u8>>> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let size = file.metadata()?.len();
+        let block = sigs.block as usize;
+        let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, sig) in sigs.blocks.iter().enumerate() {
+            index.entry(sig.weak).or_default().push(i);
+        }
+
+        let mut data = Vec::new();
+        let mut eof = false;
+        let mut out = Vec::new();
+        let mut copy: Option<(u64, u32)> = None;
+        let mut literal = 0;
+        let mut pos = 0;
+        fill(&mut file, &mut data, block + 1, &mut eof)?;
+        let mut sum = (data.len() >= block).then(|| Rolling::new(&data[..block]));
+        while let Some(rolling) = sum.as_mut() {
+            let window = &data[pos..pos + block];
+            let found = index.get(&rolling.digest()).and_then(|candidates| {
+                let hash = strong(window);
+                candidates.iter().find(|i| sigs.blocks[**i].strong == hash)
+            });
+            if let Some(&i) = found {
+                push_literal(&mut out, &data[literal..pos], &mut copy);
+                copy = match copy {
+                    Some((start, count)) if start + count as u64 == i as u64 => Some((start, count + 1)),
+                    Some(run) => {
+                        push_copy(&mut out, run);
+                        Some((i as u64, 1))
+                    }
+                    None => Some((i as u64, 1)),
+                };
+                pos += block;
+                literal = pos;
+                fill(&mut file, &mut data, pos + block + 1, &mut eof)?;
+                sum = (data.len() - pos >= block).then(|| Rolling::new(&data[pos..pos + block]));
+            } else {
+                fill(&mut file, &mut data, pos + block + 1, &mut eof)?;
+                if pos + block >= data.len() {
+                    break;
+                }
+                rolling.roll(data[pos], data[pos + block]);
+                pos += 1;
+                // Unmatched bytes go out as they pile up instead of staying in the window
+                if pos - literal == MAX_LITERAL {
+                    push_literal(&mut out, &data[literal..pos], &mut copy);
+                    literal = pos;
+                }
+            }
+            if out.len() as u64 >= size {
+                return Ok(None);
+            }
+            if literal >= READ_AHEAD {
+                data.drain(..literal);
+                pos -= literal;
+                literal = 0;
+            }
+        }
+        push_literal(&mut out, &data[literal..], &mut copy);
+        if let Some(run) = copy {
+            push_copy(&mut out, run);
+        }
+        Ok(((out.len() as u64) < size).then_some(out))
+    })
+    .await?
+}
+
+/// Reads on until `data` holds `need` bytes or the file ends.
+fn fill(file: &mut std::fs::File, data: &mut Vec<u8>, need: usize, eof: &mut bool) -> io::Result<()> {
+    while data.len() < need && !*eof {
+        let len = data.len();
+        data.resize(len + READ_AHEAD, 0);
+        let read = file.read(&mut data[len..])?;
+        data.truncate(len + read);
+        *eof = read == 0;
+    }
+    Ok(())
+}
+
+fn push_copy(out: &mut Vec<u8>, (start, count): (u64, u32)) {
+    out.push(OP_COPY);
+    out.extend_from_slice(&start.to_le_bytes());
+    out.extend_from_slice(&count.to_le_bytes());
+}
+
+/// Ends a running block reference first so the ops stay in content order.
+fn push_literal(out: &mut Vec<u8>, data: &[u8], copy: &mut Option<(u64, u32)>) {
+    if data.is_empty() {
+        return;
+    }
+    if let Some(run) = copy.take() {
+        push_copy(out, run);
+    }
+    for part in data.chunks(MAX_LITERAL) {
+        out.push(OP_LITERAL);
+        out.extend_from_slice(&(part.len() as u32).to_le_bytes());
+        out.extend_from_slice(part);
+    }
+}
+
+/// One step of rebuilding the new version.
+pub enum Op {
+    /// Bytes of the old version, as offset and length.
+    Copy(u64, u64),
+    Literal(Vec<u8>),
+}
+
+/// Decodes delta bytes as they arrive in order.
+pub struct Patch {
+    pub basis: PathBuf,
+    block: u64,
+    pending: Vec<u8>,
+}
+
+impl Patch {
+    pub fn new(basis: PathBuf, sigs: &Signatures) -> Self {
+        Patch {
+            basis,
+            block: sigs.block as u64,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    /// Next complete op, `None` until more bytes arrive.
+    pub fn next_op(&mut self) -> io::Result<Option<Op>> {
+        let (op, used) = match self.pending.first() {
+            None => return Ok(None),
+            Some(&OP_COPY) if self.pending.len() >= 13 => {
+                let start = u64::from_le_bytes(self.pending[1..9].try_into().unwrap());
+                let count = u32::from_le_bytes(self.pending[9..13].try_into().unwrap()) as u64;
+                (Op::Copy(start * self.block, count * self.block), 13)
+            }
+            Some(&OP_LITERAL) if self.pending.len() >= 5 => {
+                let len = u32::from_le_bytes(self.pending[1..5].try_into().unwrap()) as usize;
+                if len > MAX_LITERAL {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "delta literal too long"));
+                }
+                if self.pending.len() < 5 + len {
+                    return Ok(None);
+                }
+                (Op::Literal(self.pending[5..5 + len].to_vec()), 5 + len)
+            }
+            Some(&OP_COPY) | Some(&OP_LITERAL) => return Ok(None),
+            Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad delta op")),
+        };
+        self.pending.drain(..used);
+        Ok(Some(op))
+    }
+
+    /// False when the delta ended in the middle of an op.
+    pub fn finished(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that don't repeat, so every block of them is distinct.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    fn temp_file(data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("connect-p2p-delta-{:x}", rand::random::<u64>()));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// Rebuilds the new version from the old one, feeding the delta in
+    /// uneven pieces like chunks arriving. Returns it and the bytes copied.
+    fn apply(basis: &[u8], sigs: &Signatures, delta: &[u8]) -> (Vec<u8>, usize) {
+        let mut patch = Patch::new(PathBuf::new(), sigs);
+        let (mut out, mut copied) = (Vec::new(), 0);
+        for piece in delta.chunks(777) {
+            patch.push(piece);
+            while let Some(op) = patch.next_op().unwrap() {
+                match op {
+                    Op::Literal(bytes) => out.extend_from_slice(&bytes),
+                    Op::Copy(offset, len) => {
+                        out.extend_from_slice(&basis[offset as usize..(offset + len) as usize]);
+                        copied += len as usize;
+                    }
+                }
+            }
+        }
+        assert!(patch.finished());
+        (out, copied)
+    }
+
+    async fn round_trip(old: &[u8], new: &[u8]) -> (Vec<u8>, usize, usize) {
+        let (old_path, new_path) = (temp_file(old), temp_file(new));
+        let sigs = Signatures::of_file(&old_path).await.unwrap();
+        let delta = encode(new_path.clone(), sigs.clone()).await.unwrap();
+        std::fs::remove_file(old_path).unwrap();
+        std::fs::remove_file(new_path).unwrap();
+        let delta = delta.expect("delta should be smaller than the file");
+        let (rebuilt, copied) = apply(old, &sigs, &delta);
+        (rebuilt, copied, delta.len())
+    }
+
+    #[test]
+    fn rolling_matches_a_fresh_sum() {
+        let data = noise(5000, 3);
+        let window = 2048;
+        let mut sum = Rolling::new(&data[..window]);
+        for pos in 0..data.len() - window {
+            sum.roll(data[pos], data[pos + window]);
+            assert_eq!(sum.digest(), Rolling::new(&data[pos + 1..pos + 1 + window]).digest(), "at {}", pos);
+        }
+    }
+
+    #[tokio::test]
+    async fn unchanged_file_is_all_copies() {
+        let old = noise(64 * 1024, 1);
+        let (rebuilt, copied, len) = round_trip(&old, &old).await;
+        assert_eq!(rebuilt, old);
+        assert_eq!(copied, old.len());
+        assert_eq!(len, 13);
+    }
+
+    #[tokio::test]
+    async fn insert_delete_and_last_block_change() {
+        let old = noise(200 * 1024, 2);
+        let block = block_size(old.len() as u64) as usize;
+        assert_eq!(old.len() % block, 0, "the last block is signed");
+
+        let mut new = old.clone();
+        // Changed in the last block
+        let last = new.len() - block / 2;
+        new[last] ^= 0xff;
+        // Deleted from the middle
+        new.drain(120_000..123_000);
+        // Inserted near the start, off any block boundary
+        let inserted = noise(100, 9);
+        new.splice(10_001..10_001, inserted);
+
+        let (rebuilt, copied, len) = round_trip(&old, &new).await;
+        assert_eq!(rebuilt, new);
+        // Only the blocks around the three edits go as literals
+        assert!(copied >= old.len() - 6 * block, "copied {} of {}", copied, old.len());
+        assert!(len < 7 * block, "delta of {} bytes", len);
+    }
+
+    #[tokio::test]
+    async fn unsigned_tail_is_sent_as_literal() {
+        let old = noise(10 * 1024 + 300, 4);
+        let mut new = old.clone();
+        new.extend_from_slice(b"appended");
+        let (rebuilt, copied, _) = round_trip(&old, &new).await;
+        assert_eq!(rebuilt, new);
+        assert_eq!(copied, 10 * 1024);
+    }
+
+    #[test]
+    fn bad_ops_are_refused() {
+        let sigs = Signatures { block: 2048, blocks: Vec::new() };
+        let mut patch = Patch::new(PathBuf::new(), &sigs);
+        patch.push(&[7]);
+        assert!(patch.next_op().is_err());
+
+        let mut patch = Patch::new(PathBuf::new(), &sigs);
+        patch.push(&[OP_LITERAL]);
+        patch.push(&(MAX_LITERAL as u32 + 1).to_le_bytes());
+        assert!(patch.next_op().is_err());
+
+        let mut patch = Patch::new(PathBuf::new(), &sigs);
+        patch.push(&[OP_COPY, 0, 0]);
+        assert!(patch.next_op().unwrap().is_none());
+        assert!(!patch.finished());
+    }
+
+    #[tokio::test]
+    async fn edits_across_read_windows() {
+        // Several windows long, with a changed run longer than one literal op
+        let old = noise(3 * READ_AHEAD + 5000, 5);
+        let mut new = old.clone();
+        new[READ_AHEAD - 10..READ_AHEAD + 10].fill(0);
+        new.splice(2 * READ_AHEAD..2 * READ_AHEAD, noise(MAX_LITERAL + 3000, 6));
+        new.truncate(new.len() - 7000);
+        let (rebuilt, copied, len) = round_trip(&old, &new).await;
+        assert_eq!(rebuilt, new);
+        assert!(copied > old.len() - 3 * 64 * 1024, "copied {}", copied);
+        assert!(len < MAX_LITERAL + 3000 + 3 * 64 * 1024, "delta {}", len);
+    }
+
+    #[test]
+    fn delta_slots_are_capped() {
+        let slots: Vec<_> = std::iter::from_fn(slot).take(MAX_DELTAS + 1).collect();
+        assert_eq!(slots.len(), MAX_DELTAS);
+        drop(slots);
+        assert!(slot().is_some());
+    }
+}
@@ -0,0 +1,512 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use super::file::{file_mode, permissions, sanitize_filename};
+
+/// Largest manifest a receiver will buffer.
+pub const MAX_MANIFEST_LEN: u64 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EntryKind {
+    Dir,
+    File { size: u64, hash: [u8; 32] },
+    Symlink { target: String },
+}
+
+/// One item of a directory offer, `path` is relative to the offered
+/// directory and always uses `/` as separator.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub modified: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// Walks `root` without following symlinks and hashes every regular file.
+    /// Returns the manifest along with the files and their sizes in the order
+    /// they are streamed. Without `hidden`, dotfiles and everything below a
+    /// dot directory are left out.
+    pub async fn scan(root: &Path, hidden: bool) -> io::Result<(Self, Vec<(PathBuf, u64)>)> {
+        let mut manifest = Manifest::default();
+        let mut files = Vec::new();
+        let mut stack = vec![(root.to_path_buf(), String::new())];
+
+        while let Some((dir, rel)) = stack.pop() {
+            let mut children = Vec::new();
+            let mut read_dir = tokio::fs::read_dir(&dir).await?;
+            while let Some(child) = read_dir.next_entry().await? {
+                children.push(child);
+            }
+            children.sort_by_key(|c| c.file_name());
+
+            let mut subdirs = Vec::new();
+            for child in children {
+                let name = child.file_name().to_string_lossy().to_string();
+                if !hidden && name.starts_with('.') {
+                    continue;
+                }
+                let path = child.path();
+                let child_rel = if rel.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", rel, name)
+                };
+                let metadata = tokio::fs::symlink_metadata(&path).await?;
+                let kind = if metadata.is_symlink() {
+                    let target = tokio::fs::read_link(&path).await?;
+                    EntryKind::Symlink {
+                        target: target.to_string_lossy().replace('\\', "/"),
+                    }
+                } else if metadata.is_dir() {
+                    subdirs.push((path.clone(), child_rel.clone()));
+                    EntryKind::Dir
+                } else if metadata.is_file() {
+                    let hash = hash_file(&path).await?;
+                    files.push((path.clone(), metadata.len()));
+                    EntryKind::File {
+                        size: metadata.len(),
+                        hash,
+                    }
+                } else {
+                    continue;
+                };
+                manifest.entries.push(Entry {
+                    path: child_rel,
+                    kind,
+                    mode: file_mode(&metadata),
+                    modified: modified_secs(&metadata),
+                });
+            }
+            // Reversed so the stack pops them in name order
+            stack.extend(subdirs.into_iter().rev());
+        }
+        Ok((manifest, files))
+    }
+
+    pub fn file_count(&self) -> u64 {
+        self.entries
+            .iter()
+            .filter(|e| matches!(e.kind, EntryKind::File { .. }))
+            .count() as u64
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("failed to Serialize manifest")
+    }
+}
+
+pub fn modified_secs(metadata: &std::fs::Metadata) -> Option<i64> {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+}
+
+pub async fn hash_file(path: &Path) -> io::Result<[u8; 32]> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(std::fs::File::open(path)?)?;
+        Ok(*hasher.finalize().as_bytes())
+    })
+    .await?
+}
+
+/// Checks every component of a relative manifest path. `None` means the
+/// path is hidden and should be skipped.
+pub fn sanitize_path(rel: &str, allow_hidden: bool) -> io::Result<Option<PathBuf>> {
+    let mut path = PathBuf::new();
+    let mut hidden = false;
+    for part in rel.split('/') {
+        match sanitize_filename(part, true) {
+            Ok(clean) => {
+                hidden |= clean.starts_with('.');
+                path.push(clean);
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?}: {}", rel, e),
+                ))
+            }
+        }
+    }
+    if hidden && !allow_hidden {
+        return Ok(None);
+    }
+    Ok(Some(path))
+}
+
+/// Symlink targets must be relative and may not point above the offered
+/// directory. Depth is only counted lexically, so neither the link's own
+/// directory nor its target may pass through another link of the manifest:
+/// that link's target would change where a later `..` leads.
+fn symlink_allowed(link: &Path, target: &str, links: &HashSet<PathBuf>) -> bool {
+    let mut resolved = PathBuf::new();
+    for component in link.parent().unwrap_or(Path::new("")).components() {
+        resolved.push(component);
+        if links.contains(&resolved) {
+            return false;
+        }
+    }
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(part) => {
+                resolved.push(part);
+                if links.contains(&resolved) {
+                    return false;
+                }
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return false;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+struct OpenFile {
+    entry: Entry,
+    file: Option<File>,
+    path: PathBuf,
+    hasher: blake3::Hasher,
+    remaining: u64,
+}
+
+/// Rebuilds a directory offer under `root` from the in order content stream.
+pub struct DirWriter {
+    root: PathBuf,
+    manifest_len: usize,
+    manifest: Vec<u8>,
+    allow_hidden: bool,
+    pending: VecDeque<(Entry, Option<PathBuf>)>,
+    links: Vec<(PathBuf, String)>,
+    dirs: Vec<(PathBuf, Entry)>,
+    current: Option<OpenFile>,
+    pub files: usize,
+    pub skipped: usize,
+}
+
+impl DirWriter {
+    pub async fn new(root: PathBuf, manifest_len: u64, allow_hidden: bool) -> io::Result<Self> {
+        if manifest_len > MAX_MANIFEST_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "directory manifest is too large",
+            ));
+        }
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(DirWriter {
+            root,
+            manifest_len: manifest_len as usize,
+            manifest: Vec::with_capacity(manifest_len as usize),
+            allow_hidden,
+            pending: VecDeque::new(),
+            links: Vec::new(),
+            dirs: Vec::new(),
+            current: None,
+            files: 0,
+            skipped: 0,
+        })
+    }
+
+    pub async fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        if self.manifest.len() < self.manifest_len {
+            let n = data.len().min(self.manifest_len - self.manifest.len());
+            self.manifest.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.manifest.len() == self.manifest_len {
+                self.apply_manifest().await?;
+            }
+        }
+        self.advance().await?;
+        while !data.is_empty() {
+            let current = match self.current.as_mut() {
+                Some(c) => c,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "more data than the manifest lists",
+                    ))
+                }
+            };
+            let n = data.len().min(current.remaining as usize);
+            if let Some(file) = current.file.as_mut() {
+                file.write_all(&data[..n]).await?;
+            }
+            current.hasher.update(&data[..n]);
+            current.remaining -= n as u64;
+            data = &data[n..];
+            self.advance().await?;
+        }
+        Ok(())
+    }
+
+    async fn apply_manifest(&mut self) -> io::Result<()> {
+        let manifest: Manifest = bincode::deserialize(&self.manifest)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid manifest"))?;
+        let mut entries = Vec::with_capacity(manifest.entries.len());
+        let mut links = HashSet::new();
+        for entry in manifest.entries {
+            let rel = sanitize_path(&entry.path, self.allow_hidden)?;
+            if let (EntryKind::Symlink { .. }, Some(rel)) = (&entry.kind, &rel) {
+                links.insert(rel.clone());
+            }
+            entries.push((entry, rel));
+        }
+        for (entry, rel) in entries {
+            let path = rel.as_ref().map(|r| self.root.join(r));
+            match (&entry.kind, path) {
+                (EntryKind::File { .. }, path) => self.pending.push_back((entry, path)),
+                (_, None) => self.skipped += 1,
+                (EntryKind::Dir, Some(path)) => {
+                    tokio::fs::create_dir_all(&path).await?;
+                    self.dirs.push((path, entry));
+                }
+                (EntryKind::Symlink { target }, Some(path)) => {
+                    if !symlink_allowed(rel.as_deref().unwrap_or(Path::new("")), target, &links) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("{:?} points outside the directory or through another link", entry.path),
+                        ));
+                    }
+                    self.links.push((path, target.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the current file once complete and opens the next one,
+    /// stepping over empty files.
+    async fn advance(&mut self) -> io::Result<()> {
+        if self.manifest.len() < self.manifest_len {
+            return Ok(());
+        }
+        loop {
+            match self.current.take() {
+                Some(current) if current.remaining > 0 => {
+                    self.current = Some(current);
+                    return Ok(());
+                }
+                Some(current) => self.close(current).await?,
+                None => {}
+            }
+            let (entry, path) = match self.pending.pop_front() {
+                Some(next) => next,
+                None => return Ok(()),
+            };
+            let size = match entry.kind {
+                EntryKind::File { size, .. } => size,
+                _ => 0,
+            };
+            let file = match &path {
+                Some(p) => {
+                    if let Some(parent) = p.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    Some(File::create(p).await?)
+                }
+                None => None,
+            };
+            self.current = Some(OpenFile {
+                entry,
+                file,
+                path: path.unwrap_or_default(),
+                hasher: blake3::Hasher::new(),
+                remaining: size,
+            });
+        }
+    }
+
+    async fn close(&mut self, current: OpenFile) -> io::Result<()> {
+        let expected = match current.entry.kind {
+            EntryKind::File { hash, .. } => hash,
+            _ => return Ok(()),
+        };
+        if current.hasher.finalize() != blake3::Hash::from(expected) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} does not match its hash", current.entry.path),
+            ));
+        }
+        let file = match current.file {
+            Some(f) => f,
+            None => {
+                self.skipped += 1;
+                return Ok(());
+            }
+        };
+        file.sync_all().await?;
+        if let Some(t) = current.entry.modified {
+            let time = UNIX_EPOCH + Duration::from_secs(t.max(0) as u64);
+            file.into_std().await.set_modified(time)?;
+        }
+        let perms = permissions(current.entry.mode, &current.path).await?;
+        tokio::fs::set_permissions(&current.path, perms).await?;
+        self.files += 1;
+        Ok(())
+    }
+
+    /// Checks that every listed file arrived, then creates symlinks and
+    /// applies directory permissions. Links come last so no file is ever
+    /// written through one.
+    pub async fn finish(&mut self) -> io::Result<()> {
+        self.advance().await?;
+        if self.manifest.len() < self.manifest_len || self.current.is_some() || !self.pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "directory content ended early",
+            ));
+        }
+        for (path, target) in self.links.drain(..) {
+            #[cfg(unix)]
+            tokio::fs::symlink(&target, &path).await?;
+            #[cfg(not(unix))]
+            {
+                let _ = (path, target);
+                self.skipped += 1;
+            }
+        }
+        for (path, entry) in self.dirs.drain(..).rev() {
+            let perms = permissions(entry.mode | 0o700, &path).await?;
+            tokio::fs::set_permissions(&path, perms).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links(paths: &[&str]) -> HashSet<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("connect-p2p-dir-{:x}", rand::random::<u64>()))
+    }
+
+    fn link(path: &str, target: &str) -> Entry {
+        Entry {
+            path: path.to_string(),
+            kind: EntryKind::Symlink { target: target.to_string() },
+            mode: 0o777,
+            modified: None,
+        }
+    }
+
+    fn dir(path: &str) -> Entry {
+        Entry {
+            path: path.to_string(),
+            kind: EntryKind::Dir,
+            mode: 0o755,
+            modified: None,
+        }
+    }
+
+    /// Receives a manifest without file content into a fresh directory.
+    async fn receive(entries: Vec<Entry>) -> io::Result<PathBuf> {
+        let bytes = Manifest { entries }.serialize();
+        let root = temp_dir();
+        let mut writer = DirWriter::new(root.clone(), bytes.len() as u64, false).await?;
+        let res = async {
+            writer.write(&bytes).await?;
+            writer.finish().await
+        }
+        .await;
+        match res {
+            Ok(()) => Ok(root),
+            Err(e) => {
+                tokio::fs::remove_dir_all(&root).await?;
+                Err(e)
+            }
+        }
+    }
+
+    #[test]
+    fn links_inside_the_directory() {
+        let none = HashSet::new();
+        assert!(symlink_allowed(Path::new("l"), "file", &none));
+        assert!(symlink_allowed(Path::new("a/l"), "../file", &none));
+        assert!(symlink_allowed(Path::new("a/b/l"), "./../../c/./d", &none));
+        assert!(symlink_allowed(Path::new("l"), ".", &none));
+    }
+
+    #[test]
+    fn links_above_the_directory() {
+        let none = HashSet::new();
+        assert!(!symlink_allowed(Path::new("l"), "..", &none));
+        assert!(!symlink_allowed(Path::new("a/l"), "../..", &none));
+        assert!(!symlink_allowed(Path::new("l"), "a/../../x", &none));
+        assert!(!symlink_allowed(Path::new("l"), "/etc", &none));
+    }
+
+    #[test]
+    fn chained_links() {
+        // `a -> .` then `s -> a/..` is the directory's parent
+        let chain = links(&["a", "s"]);
+        assert!(symlink_allowed(Path::new("a"), ".", &chain));
+        assert!(!symlink_allowed(Path::new("s"), "a/..", &chain));
+        assert!(!symlink_allowed(Path::new("s"), "a", &chain));
+        // A link placed behind another one
+        assert!(!symlink_allowed(Path::new("a/l"), "..", &links(&["a", "a/l"])));
+    }
+
+    #[tokio::test]
+    async fn chained_links_are_refused() {
+        let err = receive(vec![link("a", "."), link("s", "a/..")]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = receive(vec![dir("d"), link("x", "."), link("x/l", "..")]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn plain_links_are_created() {
+        let root = receive(vec![dir("d"), link("d/up", ".."), link("here", "d")]).await.unwrap();
+        assert_eq!(std::fs::read_link(root.join("d/up")).unwrap(), Path::new(".."));
+        assert_eq!(std::fs::read_link(root.join("here")).unwrap(), Path::new("d"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn served_manifests_leave_out_dotfiles() {
+        let root = temp_dir();
+        for dir in ["docs/.git", ".ssh", "docs/sub"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [".env", ".ssh/id_ed25519", "docs/.git/config", "docs/sub/.secret", "docs/a.txt", "b.txt"] {
+            std::fs::write(root.join(file), file).unwrap();
+        }
+        let (manifest, files) = Manifest::scan(&root, false).await.unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["b.txt", "docs", "docs/a.txt", "docs/sub"]);
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|(path, _)| !path.to_string_lossy().contains("/.")));
+
+        let (manifest, files) = Manifest::scan(&root, true).await.unwrap();
+        assert_eq!(manifest.entries.len(), 10);
+        assert_eq!(files.len(), 6);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,332 @@
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io, ops::RangeInclusive};
+
+use super::cache::ChunkCache;
+use super::file::CHUNK_SIZE;
+use super::tree::{self, ChainingValue};
+
+/// Chunks per group, a power of two so every full group is a subtree of
+/// the hash tree and can be verified as a whole once rebuilt.
+pub const GROUP: usize = 16;
+/// Most repair symbols asked for per group.
+const MAX_REPAIR: usize = GROUP / 2;
+/// Loss assumed before any group was measured.
+const INITIAL_LOSS: f64 = 0.05;
+/// Weight of the newest group in the loss estimate.
+const LOSS_WEIGHT: f64 = 0.25;
+
+/// Asks for every chunk of a group followed by `repair` repair symbols,
+/// with FEC the receiver requests groups instead of single chunks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupPacket {
+    pub id: u64,
+    pub group: usize,
+    pub repair: u8,
+}
+
+/// Reed-Solomon parity over the chunks of a group, each padded to
+/// `CHUNK_SIZE`. `proof` verifies the rebuilt group against the root hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RepairPacket {
+    pub id: u64,
+    pub group: usize,
+    pub index: u8,
+    pub parity: u8,
+    pub data: Vec<u8>,
+    pub proof: Vec<ChainingValue>,
+}
+
+/// 1-based chunk indices in `group`, the last group may be shorter. Empty
+/// past the last group, the group number comes from the peer.
+pub fn group_chunks(group: usize, total_chunks: usize) -> RangeInclusive<usize> {
+    let first = group.saturating_mul(GROUP).saturating_add(1);
+    first..=first.saturating_add(GROUP - 1).min(total_chunks)
+}
+
+fn rs_error(e: reed_solomon_erasure::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// Computes `count` repair symbols of a group from the cached chunks.
+pub async fn repairs(
+    chunks: &ChunkCache,
+    id: u64,
+    group: usize,
+    count: u8,
+) -> io::Result<Vec<RepairPacket>> {
+    let tree = chunks.tree();
+    let indices = group_chunks(group, tree.total_chunks());
+    let parity = (count as usize).min(MAX_REPAIR);
+    let data_shards = indices.clone().count();
+    if data_shards == 0 || parity == 0 {
+        return Ok(Vec::new());
+    }
+    let offset = (*indices.start() as u64 - 1) * CHUNK_SIZE as u64;
+    let mut shards = Vec::with_capacity(data_shards + parity);
+    let mut len = 0;
+    for index in indices {
+        let mut shard = chunks.get(index).await?.raw.clone();
+        len += shard.len() as u64;
+        shard.resize(CHUNK_SIZE, 0);
+        shards.push(shard);
+    }
+    shards.resize(data_shards + parity, vec![0; CHUNK_SIZE]);
+    ReedSolomon::new(data_shards, parity)
+        .and_then(|rs| rs.encode(&mut shards))
+        .map_err(rs_error)?;
+    let proof = tree.subtree_proof(offset, len);
+    Ok(shards
+        .into_iter()
+        .skip(data_shards)
+        .enumerate()
+        .map(|(index, data)| RepairPacket {
+            id,
+            group,
+            index: index as u8,
+            parity: parity as u8,
+            data,
+            proof: proof.clone(),
+        })
+        .collect())
+}
+
+struct Pending {
+    /// Data shards then parity shards, padded to `CHUNK_SIZE`.
+    shards: Vec<Option<Vec<u8>>>,
+    parity: usize,
+    proof: Vec<ChainingValue>,
+}
+
+/// Receiver side state of the groups being fetched. Keeps the chunks of
+/// incomplete groups and rebuilds lost ones once enough repair symbols
+/// arrived. The number of repair symbols asked for follows the measured loss.
+pub struct Recovery {
+    total: usize,
+    size: u64,
+    groups: HashMap<usize, Pending>,
+    loss: f64,
+    pub rebuilt: usize,
+}
+
+impl Recovery {
+    pub fn new(total_chunks: usize, size: u64) -> Self {
+        Recovery {
+            total: total_chunks,
+            size,
+            groups: HashMap::new(),
+            loss: INITIAL_LOSS,
+            rebuilt: 0,
+        }
+    }
+
+    /// Repair symbols to ask for with the next group, half again the
+    /// expected loss and at least one.
+    pub fn repair_count(&self) -> u8 {
+        let expected = self.loss * GROUP as f64 * 1.5;
+        (expected.ceil() as usize).clamp(1, MAX_REPAIR) as u8
+    }
+
+    pub fn start(&mut self, group: usize) {
+        let chunks = group_chunks(group, self.total).count();
+        self.groups.entry(group).or_insert_with(|| Pending {
+            shards: vec![None; chunks],
+            parity: 0,
+            proof: Vec::new(),
+        });
+    }
+
+    /// Keeps a verified chunk of a group being fetched.
+    pub fn chunk(&mut self, index: usize, data: &[u8]) {
+        let group = (index - 1) / GROUP;
+        let first = group * GROUP + 1;
+        let complete = match self.groups.get_mut(&group) {
+            Some(pending) => {
+                let mut shard = data.to_vec();
+                shard.resize(CHUNK_SIZE, 0);
+                pending.shards[index - first] = Some(shard);
+                pending.shards[..pending.shards.len() - pending.parity]
+                    .iter()
+                    .all(Option::is_some)
+            }
+            None => false,
+        };
+        if complete {
+            self.finish(group, 0);
+        }
+    }
+
+    /// Takes a repair symbol, returns the chunks it let us rebuild. They
+    /// are only returned once the whole group matches the root hash.
+    pub fn repair(&mut self, root: &[u8; 32], repair: RepairPacket) -> Vec<(usize, Vec<u8>)> {
+        let chunks = group_chunks(repair.group, self.total);
+        let data_shards = chunks.clone().count();
+        let parity = repair.parity as usize;
+        let pending = match self.groups.get_mut(&repair.group) {
+            Some(pending) => pending,
+            None => return Vec::new(),
+        };
+        if parity == 0
+            || parity > MAX_REPAIR
+            || repair.index as usize >= parity
+            || repair.data.len() != CHUNK_SIZE
+            || (pending.parity != 0 && pending.parity != parity)
+        {
+            return Vec::new();
+        }
+        if pending.parity == 0 {
+            pending.parity = parity;
+            pending.shards.resize(data_shards + parity, None);
+            pending.proof = repair.proof;
+        }
+        pending.shards[data_shards + repair.index as usize] = Some(repair.data);
+        if pending.shards.iter().filter(|s| s.is_some()).count() < data_shards {
+            return Vec::new();
+        }
+
+        let missing: Vec<usize> = (0..data_shards).filter(|i| pending.shards[*i].is_none()).collect();
+        let mut shards = pending.shards.clone();
+        let rebuilt = ReedSolomon::new(data_shards, parity)
+            .and_then(|rs| rs.reconstruct_data(&mut shards))
+            .is_ok();
+        let offset = (*chunks.start() as u64 - 1) * CHUNK_SIZE as u64;
+        let len = (self.size - offset).min((data_shards * CHUNK_SIZE) as u64) as usize;
+        let content: Vec<u8> = shards[..data_shards]
+            .iter()
+            .flat_map(|s| s.as_deref().unwrap_or_default().iter().copied())
+            .take(len)
+            .collect();
+        if !rebuilt
+            || content.len() != len
+            || !tree::verify_range(root, self.size, offset, &content, &pending.proof)
+        {
+            // A bad symbol, the missing chunks are requested again instead
+            pending.shards.truncate(data_shards);
+            pending.parity = 0;
+            return Vec::new();
+        }
+        self.rebuilt += missing.len();
+        self.finish(repair.group, missing.len());
+        missing
+            .into_iter()
+            .map(|i| {
+                let start = i * CHUNK_SIZE;
+                let end = (start + CHUNK_SIZE).min(len);
+                (chunks.start() + i, content[start..end].to_vec())
+            })
+            .collect()
+    }
+
+    /// Gives up on rebuilding a group, its missing chunks are requested one by one.
+    pub fn abandon(&mut self, group: usize) {
+        if let Some(pending) = self.groups.get(&group) {
+            let data_shards = pending.shards.len() - pending.parity;
+            let lost = pending.shards[..data_shards].iter().filter(|s| s.is_none()).count();
+            self.finish(group, lost);
+        }
+    }
+
+    fn finish(&mut self, group: usize, lost: usize) {
+        if let Some(pending) = self.groups.remove(&group) {
+            let sent = pending.shards.len() - pending.parity;
+            let sample = lost as f64 / sent as f64;
+            self.loss = self.loss * (1.0 - LOSS_WEIGHT) + sample * LOSS_WEIGHT;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{source::Source, tree::HashTree};
+    use std::sync::Arc;
+
+    /// 21 chunks, the last group holds 5 and ends in a short chunk.
+    const SIZE: usize = 20 * CHUNK_SIZE + CHUNK_SIZE / 2;
+
+    async fn cache() -> (Vec<u8>, ChunkCache) {
+        let data: Vec<u8> = (0..SIZE).map(|i| (i * 7 % 251) as u8).collect();
+        let source = Source::Memory(Arc::new(data.clone()));
+        let tree = Arc::new(HashTree::from_source(&source).await.unwrap());
+        (data, ChunkCache::new(source, tree))
+    }
+
+    fn chunk(data: &[u8], index: usize) -> &[u8] {
+        let start = (index - 1) * CHUNK_SIZE;
+        &data[start..(start + CHUNK_SIZE).min(data.len())]
+    }
+
+    #[test]
+    fn groups_cover_the_chunks_and_nothing_past_them() {
+        assert_eq!(group_chunks(0, 21), 1..=16);
+        assert_eq!(group_chunks(1, 21), 17..=21);
+        assert!(group_chunks(2, 21).is_empty());
+        assert!(group_chunks(usize::MAX, 21).is_empty());
+        assert!(group_chunks(0, 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn lost_chunks_are_rebuilt_up_to_the_parity() {
+        let (data, chunks) = cache().await;
+        let root = chunks.tree().root();
+        let parity = 4;
+        let symbols = repairs(&chunks, 1, 1, parity).await.unwrap();
+        for lost in 1..=parity as usize {
+            let mut recovery = Recovery::new(21, SIZE as u64);
+            recovery.start(1);
+            for index in 17 + lost..=21 {
+                recovery.chunk(index, chunk(&data, index));
+            }
+            for symbol in &symbols[..lost - 1] {
+                assert!(recovery.repair(&root, symbol.clone()).is_empty());
+            }
+            let rebuilt = recovery.repair(&root, symbols[lost - 1].clone());
+            let expected: Vec<(usize, Vec<u8>)> =
+                (17..17 + lost).map(|i| (i, chunk(&data, i).to_vec())).collect();
+            assert_eq!(rebuilt, expected);
+            assert_eq!(recovery.rebuilt, lost);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_bad_symbol_resets_the_group() {
+        let (data, chunks) = cache().await;
+        let root = chunks.tree().root();
+        let symbols = repairs(&chunks, 1, 1, 2).await.unwrap();
+        let mut recovery = Recovery::new(21, SIZE as u64);
+        recovery.start(1);
+        for index in 18..=21 {
+            recovery.chunk(index, chunk(&data, index));
+        }
+        let mut bad = symbols[0].clone();
+        bad.data[0] ^= 1;
+        assert!(recovery.repair(&root, bad).is_empty());
+        // Forgotten, the good symbol rebuilds the chunk on its own
+        assert_eq!(recovery.groups[&1].parity, 0);
+        let rebuilt = recovery.repair(&root, symbols[1].clone());
+        assert_eq!(rebuilt, vec![(17, chunk(&data, 17).to_vec())]);
+    }
+
+    #[test]
+    fn repair_count_follows_the_loss() {
+        let total = 64 * GROUP;
+        let mut recovery = Recovery::new(total, (total * CHUNK_SIZE) as u64);
+        assert_eq!(recovery.repair_count(), 2);
+        for group in 0..32 {
+            recovery.start(group);
+            for index in group_chunks(group, total) {
+                recovery.chunk(index, &[1]);
+            }
+        }
+        assert_eq!(recovery.repair_count(), 1);
+        for group in 32..64 {
+            recovery.start(group);
+            // Half of every group lost
+            for index in group_chunks(group, total).step_by(2) {
+                recovery.chunk(index, &[1]);
+            }
+            recovery.abandon(group);
+        }
+        assert_eq!(recovery.repair_count(), MAX_REPAIR as u8);
+    }
+}
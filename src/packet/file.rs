@@ -1,83 +1,444 @@
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use chrono::{Local, TimeZone};
+use crossterm::{
+    execute,
+    style::{Color, Print, ResetColor, SetForegroundColor},
+};
+use indicatif::{HumanBytes, ProgressBar, ProgressState, ProgressStyle};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
-use std::io::{self, Write};
+use std::io::{self, SeekFrom, Write};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use fs4::tokio::AsyncFileExt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::net::UdpSocket;
-use tokio::time::timeout;
+use tokio::time::{timeout, timeout_at, Instant};
 
-use crate::user::User;
+use crate::config::ConflictPolicy;
+use crate::user::{
+    history::{Outcome, Record},
+    transfers::{Direction, Shared},
+    User,
+};
+use crate::routes::Route;
 use crate::ReceiverRes;
 
+use super::compress::{self, Compression};
+use super::delta::{Op, Patch, Signatures, DELTA_TIMEOUT};
+use super::dir::{modified_secs, DirWriter, Manifest};
+use super::source::Source;
+use super::fec::Recovery;
+use super::frame::{self, Frame, Header};
+use super::receipt::Receipt;
+use super::sparse;
+use super::swarm::{Piece, Request, Swarm};
+use super::transfer::{ErrorCode, TransferState};
+use super::tree::{self, ChainingValue};
 use super::Packet;
 
-const PACKET_SIZE: usize = 65 * 1024;
-const FILE_TIMEOUT: Duration = Duration::from_secs(4);
+/// Power of two so every chunk is a whole BLAKE3 subtree, see `tree.rs`.
+pub const CHUNK_SIZE: usize = 32 * 1024;
+pub const PACKET_SIZE: usize = 65 * 1024;
+/// How long the receiver has to answer a file offer.
+pub const OFFER_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_NAME_LEN: usize = 255;
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
 
+/// Checks a file name offered by a peer, it must be a single plain path
+/// component. Control characters are replaced, anything that could escape
+/// the download directory or clash with a device name is refused.
+pub fn sanitize_filename(name: &str, allow_hidden: bool) -> io::Result<String> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, reason.to_string());
+
+    if name.contains(['/', '\\', ':', '\0']) {
+        return Err(invalid("file name contains a path separator"));
+    }
+    let clean: String = name
+        .chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect();
+    let clean = clean.trim_end_matches(['.', ' ']).trim_start();
+    if clean.is_empty() || clean == "." || clean == ".." {
+        return Err(invalid("file name is empty"));
+    }
+    if clean.len() > MAX_NAME_LEN {
+        return Err(invalid("file name is too long"));
+    }
+    if clean.starts_with('.') && !allow_hidden {
+        return Err(invalid("hidden file names are not allowed"));
+    }
+    let stem = clean.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        return Err(invalid("file name is reserved"));
+    }
+    Ok(clean.to_string())
+}
+
+#[cfg(unix)]
+pub fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+/// Mode bits of a peer's file that are applied here: only rwx, and never
+/// write access for group or others. Setuid, setgid and sticky are dropped.
+pub fn received_mode(mode: u32) -> u32 {
+    mode & 0o777 & !0o022
+}
+
+#[cfg(unix)]
+pub async fn permissions(mode: u32, _path: &Path) -> io::Result<std::fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(std::fs::Permissions::from_mode(received_mode(mode)))
+}
+
+#[cfg(not(unix))]
+pub async fn permissions(mode: u32, path: &Path) -> io::Result<std::fs::Permissions> {
+    let mut perms = tokio::fs::metadata(path).await?.permissions();
+    perms.set_readonly(mode & 0o222 == 0);
+    Ok(perms)
+}
+
+fn mode_string(mode: u32) -> String {
+    (0..9)
+        .map(|i| {
+            if mode & (0o400 >> i) == 0 {
+                '-'
+            } else {
+                ['r', 'w', 'x'][i % 3]
+            }
+        })
+        .collect()
+}
+
+/// Next free `name (n).ext` beside `path`.
+fn unique_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut n = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{} ({}){}", stem, n, ext));
+        if !candidate.exists() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// Where a transfer writes until the content verified, the transfer id keeps
+/// two transfers of the same name apart.
+fn part_path(path: &Path, id: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.part", id));
+    path.with_file_name(name)
+}
+
+/// Moves a completed `.part` file to its final name, applying the conflict
+/// policy again in case the name was taken while receiving.
+async fn finalize(part: &Path, path: &Path, policy: ConflictPolicy) -> io::Result<PathBuf> {
+    let dest = match policy {
+        ConflictPolicy::Rename if path.exists() => unique_path(path),
+        ConflictPolicy::Skip if path.exists() => {
+            discard(part).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} appeared while receiving, skipped", path.display()),
+            ));
+        }
+        // A directory can't be renamed over a non empty one
+        ConflictPolicy::Overwrite if path.is_dir() && !path.is_symlink() => {
+            tokio::fs::remove_dir_all(path).await?;
+            path.to_path_buf()
+        }
+        _ => path.to_path_buf(),
+    };
+    tokio::fs::rename(part, &dest).await?;
+    Ok(dest)
+}
+
+async fn discard(part: &Path) -> io::Result<()> {
+    if part.is_dir() {
+        tokio::fs::remove_dir_all(part).await
+    } else {
+        tokio::fs::remove_file(part).await
+    }
+}
+
+/// Space left on the filesystem `path` would be written to.
+fn free_space(path: &Path) -> Option<u64> {
+    fs4::available_space(path.parent()?).ok()
+}
+
+/// Reserves the whole file up front, so a full disk shows before any chunk
+/// arrives. Filesystems without fallocate grow the file as it is written.
+async fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    if size == 0 {
+        return Ok(());
+    }
+    match file.allocate(size).await {
+        Err(e) if ErrorCode::from_io(&e) == ErrorCode::NoSpace => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Writes a chunk at its place in the file.
+async fn write_chunk(file: &mut File, index: usize, data: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start((index - 1) as u64 * CHUNK_SIZE as u64)).await?;
+    file.write_all(data).await
+}
+
+/// The sender and every peer still serving chunks.
+fn notify(sender: SocketAddr, swarm: &Swarm) -> Vec<SocketAddr> {
+    let mut peers = swarm.peers();
+    if !peers.contains(&sender) {
+        peers.push(sender);
+    }
+    peers
+}
+
+/// Where the chunks of an accepted offer end up.
+enum Sink {
+    File(File),
+    Dir(Box<DirWriter>),
+}
+
+impl Sink {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::File(file) => file.write_all(data).await,
+            Sink::Dir(dir) => dir.write(data).await,
+        }
+    }
+}
+
+/// Rebuilds content from the next delta bytes, returns how much was written.
+async fn apply_delta(
+    patch: &mut Patch,
+    data: &[u8],
+    sink: &mut Sink,
+    hasher: &mut blake3::Hasher,
+) -> io::Result<u64> {
+    patch.push(data);
+    let basis = Source::File(patch.basis.clone());
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut written = 0;
+    while let Some(op) = patch.next_op()? {
+        match op {
+            Op::Literal(bytes) => {
+                sink.write(&bytes).await?;
+                hasher.update(&bytes);
+                written += bytes.len() as u64;
+            }
+            Op::Copy(mut offset, len) => {
+                let end = offset + len;
+                while offset < end {
+                    let want = ((end - offset) as usize).min(CHUNK_SIZE);
+                    let n = basis.read_at(offset, &mut buf[..want]).await?;
+                    if n < want {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "delta refers past the end of the old version",
+                        ));
+                    }
+                    sink.write(&buf[..n]).await?;
+                    hasher.update(&buf[..n]);
+                    offset += n as u64;
+                    written += n as u64;
+                }
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// Present in offers for a whole directory, see `dir.rs`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirOffer {
+    pub manifest_len: u64,
+    pub files: u64,
+}
+
+/// A received chunk, unpacked and copied out of the receive buffer. On the
+/// wire it is a chunk frame, see `frame.rs`.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FilePacket {
-    pub filename: String,
-    pub filesize: usize,
+    pub id: u64,
     pub chunk_index: usize,
     pub total_chunks: usize,
     pub data: Vec<u8>,
-    pub hash: Option<String>,
+    pub proof: Vec<ChainingValue>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileMetadata {
+    /// Random id shared by both sides, names the transfer in acks and control packets.
+    pub id: u64,
+    pub sender: String,
     pub filename: String,
+    pub size: u64,
     pub total_chunks: usize,
-    pub key: String,
+    pub hash: [u8; 32],
+    pub modified: Option<i64>,
+    pub mode: u32,
+    pub mime: String,
+    pub dir: Option<DirOffer>,
+    /// Set when the content samples compress well.
+    pub compression: Option<Compression>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MetadataRes {
+    pub id: u64,
     pub total_chunks: usize,
-    pub key: String,
+    pub hash: [u8; 32],
+    /// Signatures of an older version the receiver already has, see `delta.rs`.
+    pub basis: Option<Signatures>,
+    /// The offer's compression, taken up so the sender may compress chunks.
+    pub compression: Option<Compression>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AckPacket {
+    pub id: u64,
     pub chunk_index: usize,
 }
 
 impl AckPacket {
-    pub fn new(chunk_index: usize) -> Self {
-        AckPacket { chunk_index }
+    pub fn new(id: u64, chunk_index: usize) -> Self {
+        AckPacket { id, chunk_index }
     }
 }
 
 impl MetadataRes {
-    pub fn new(file: FileMetadata) -> Self {
+    pub fn new(file: FileMetadata, basis: Option<Signatures>) -> Self {
         MetadataRes {
+            id: file.id,
             total_chunks: file.total_chunks,
-            key: file.key,
+            hash: file.hash,
+            basis,
+            compression: file.compression,
         }
     }
 
     pub fn verify(&self, packet: &Packet) -> bool {
         if let Packet::Metadata(file) = packet {
-            return (self.key == file.key) && (self.total_chunks == file.total_chunks);
+            return (self.id == file.id)
+                && (self.hash == file.hash)
+                && (self.total_chunks == file.total_chunks);
         }
         false
     }
 }
 
 impl FileMetadata {
-    pub fn new(filename: String, total_chunks: usize) -> Self {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(filename.as_bytes());
-        let key = hasher.finalize().to_string();
+    pub fn new(
+        sender: String,
+        filename: String,
+        size: u64,
+        hash: [u8; 32],
+        metadata: &std::fs::Metadata,
+    ) -> Self {
+        let total_chunks = size.div_ceil(CHUNK_SIZE as u64) as usize;
+        let mime = mime_guess::from_path(&filename)
+            .first_or_octet_stream()
+            .to_string();
         FileMetadata {
+            id: rand::random(),
+            sender,
             filename,
+            size,
             total_chunks,
-            key,
+            hash,
+            modified: modified_secs(metadata),
+            mode: file_mode(metadata),
+            mime,
+            dir: None,
+            compression: None,
+        }
+    }
+
+    pub fn new_dir(
+        sender: String,
+        filename: String,
+        size: u64,
+        hash: [u8; 32],
+        metadata: &std::fs::Metadata,
+        manifest: &Manifest,
+        manifest_len: u64,
+    ) -> Self {
+        let mut offer = FileMetadata::new(sender, filename, size, hash, metadata);
+        offer.mime = "inode/directory".to_string();
+        offer.dir = Some(DirOffer {
+            manifest_len,
+            files: manifest.file_count(),
+        });
+        offer
+    }
+
+    fn display_offer(&self, from: &str, filename: &str) {
+        let modified = self
+            .modified
+            .and_then(|t| Local.timestamp_opt(t, 0).single())
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let kind = match &self.dir {
+            Some(dir) => format!("{} ({} files)", self.mime, dir.files),
+            None => self.mime.clone(),
+        };
+        let offer = format!(
+            "\nFile offer from {}\n  name:     {}\n  size:     {} ({} bytes)\n  type:     {}\n  modified: {}\n  mode:     {}\n  blake3:   {}\n",
+            from,
+            filename,
+            HumanBytes(self.size),
+            self.size,
+            kind,
+            modified,
+            mode_string(self.mode),
+            blake3::Hash::from(self.hash).to_hex(),
+        );
+        if let Err(e) = execute!(
+            io::stdout(),
+            SetForegroundColor(Color::Cyan),
+            Print(offer),
+            ResetColor
+        ) {
+            println!("Error Printing file offer, {}", e);
+        }
+    }
+
+    /// Applies the sender's modification time and permission bits.
+    async fn restore_attributes(&self, file: File, path: &Path) -> io::Result<()> {
+        if let Some(t) = self.modified {
+            let time = UNIX_EPOCH + Duration::from_secs(t.max(0) as u64);
+            file.into_std().await.set_modified(time)?;
         }
+        tokio::fs::set_permissions(path, permissions(self.mode, path).await?).await
+    }
+
+    /// Adds an offer turned down here to the history.
+    async fn record_rejected(&self, user_lock: &Mutex<User>, addr: SocketAddr, filename: &str, reason: Option<String>) {
+        let mut user = user_lock.lock().await;
+        let peer = user.peer_name(&addr).unwrap_or_else(|| self.sender.clone());
+        let record = Record::rejected(Direction::Receive, filename.to_string(), peer, addr, self.size, self.hash, reason);
+        user.record(record);
     }
 
     pub async fn receive_file(
@@ -86,128 +447,577 @@ impl FileMetadata {
         addr: SocketAddr,
         user_lock: Arc<Mutex<User>>,
         mut res_rx: ReceiverRes,
+        mut route: Route,
     ) -> std::io::Result<()> {
-        user_lock.lock().await.req_res();
-        print!("File Sending req from {} : [y/n] -> ", self.filename);
-        io::stdout().flush().unwrap();
-        let mut res = false;
-        loop {
-            if let Ok(Ok(input)) = timeout(FILE_TIMEOUT, res_rx.recv()).await {
-                let ans = input
-                    .trim()
-                    .chars()
-                    .nth(0)
-                    .unwrap()
-                    .to_lowercase()
-                    .to_string();
-                if ans == 'y'.to_string() {
-                    res = true;
-                    break;
-                } else if ans == 'n'.to_string(){
-                    break;
-                }
+        let config = user_lock.lock().await.config().clone();
+        let filename = match sanitize_filename(&self.filename, config.allow_hidden) {
+            Ok(name) => name,
+            Err(e) => {
+                println!("Refused file {:?} from {}, {}", self.filename, addr, e);
+                self.record_rejected(&user_lock, addr, &self.filename, Some(e.to_string())).await;
+                let packet = Packet::create_error(self.id, ErrorCode::InvalidName, e.to_string());
+                return packet.send_packet(socket, &addr).await;
+            }
+        };
+
+        let mut user = user_lock.lock().await;
+        // The name bound at connect time is trusted, the one in the offer is only shown
+        let from = match user.peer_name(&addr) {
+            Some(name) if name == self.sender => name,
+            Some(name) => format!("{} (calls itself {})", name, self.sender),
+            None => format!("{} (not connected, {})", self.sender, addr),
+        };
+        // Offers answering our own `get:` need no confirmation
+        let requested = match user.gets.get(&self.id) {
+            Some((asked, dest)) if *asked == addr => Some(dest.clone()),
+            _ => None,
+        };
+        if requested.is_some() {
+            user.gets.remove(&self.id);
+        }
+        drop(user);
+
+        // A synced file replaces its copy in the synced folder
+        let synced = requested.clone().flatten();
+        let path = match &synced {
+            Some(dest) => dest.clone(),
+            None => {
+                tokio::fs::create_dir_all(&config.download_dir).await?;
+                config.download_dir.join(&filename)
+            }
+        };
+        if let Some(free) = free_space(&path).filter(|free| *free < self.size) {
+            let reason = format!("needs {} but only {} is free", HumanBytes(self.size), HumanBytes(free));
+            println!("Refused {} from {}, {}", filename, from, reason);
+            self.record_rejected(&user_lock, addr, &filename, Some(reason.clone())).await;
+            let packet = Packet::create_error(self.id, ErrorCode::NoSpace, reason);
+            return packet.send_packet(socket, &addr).await;
+        }
+        let exists = path.exists();
+        let mut res = None;
+        if synced.is_some() {
+            res = Some(ConflictPolicy::Overwrite);
+        } else if requested.is_some() {
+            println!("{} sends {} ({})", from, filename, HumanBytes(self.size));
+            res = Some(config.on_conflict);
+        } else {
+            user_lock.lock().await.req_res();
+            self.display_offer(&from, &filename);
+            if exists {
+                print!("{} already exists : [y/n/r(ename)/o(verwrite)] -> ", filename);
             } else {
+                print!("Accept file : [y/n] -> ");
+            }
+            io::stdout().flush().unwrap();
+            while let Ok(Ok(input)) = timeout(OFFER_TIMEOUT, res_rx.recv()).await {
+                let ans = input.trim().chars().next().map(|c| c.to_ascii_lowercase());
+                match ans {
+                    Some('y') => res = Some(config.on_conflict),
+                    Some('r') if exists => res = Some(ConflictPolicy::Rename),
+                    Some('o') if exists => res = Some(ConflictPolicy::Overwrite),
+                    Some('n') => {}
+                    _ => continue,
+                }
                 break;
             }
+            user_lock.lock().await.req_resolve();
         }
-        user_lock.lock().await.req_resolve();
 
-        if !res {
-            println!("Connection Denied");
-            return Ok(())
-        }
+        let policy = match res {
+            Some(ConflictPolicy::Skip) if exists => {
+                println!("Skipped, {} already exists", filename);
+                self.record_rejected(&user_lock, addr, &filename, Some("already exists".to_string())).await;
+                return Packet::create_reject(self.id).send_packet(socket, &addr).await;
+            }
+            Some(policy) => policy,
+            None => {
+                println!("Connection Denied");
+                self.record_rejected(&user_lock, addr, &filename, None).await;
+                return Packet::create_reject(self.id).send_packet(socket, &addr).await;
+            }
+        };
+
+        let mut job = {
+            let mut user = user_lock.lock().await;
+            let peer = user.peer_name(&addr).unwrap_or_else(|| self.sender.clone());
+            let size = self.size;
+            let mut job = user.transfers.add(self.id, filename.clone(), peer, addr, Direction::Receive, size);
+            job.throttle = user.throttle(&addr, Direction::Receive);
+            job.key = user.peer_key(&addr);
+            job
+        };
+        println!("Transfer {}: receiving {}", job.id, filename);
 
-        let packet = Packet::create_file_res(self.clone());
-        packet.send_packet(socket, &addr).await?;
+        // An older version lets the sender send only what changed
+        let basis = if self.dir.is_none() && self.size > 0 && path.is_file() && !path.is_symlink() {
+            Signatures::of_file(&path).await.ok().filter(|s| !s.blocks.is_empty())
+        } else {
+            None
+        };
+        let part = part_path(&path, job.id);
+        // Set where the transfer stops early, `remote` when the sender reported it
+        let mut failure: Option<(ErrorCode, String)> = None;
+        let mut remote = false;
+        let mut cancelled = false;
+        let mut swarm = Swarm::new(self.total_chunks, addr, config.fec);
+        let res: io::Result<()> = async {
+            let packet = Packet::create_file_res(self.clone(), basis.clone());
+            packet.send_packet(socket, &addr).await?;
 
-        let pb = ProgressBar::new(self.total_chunks as u64 * 59 * 1024);
-        pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-          .unwrap()
-          .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-          .progress_chars("#>-"));
+            let pb = ProgressBar::new(self.size);
+            pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+              .unwrap()
+              .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+              .progress_chars("#>-"));
 
-        let mut buf = [0; PACKET_SIZE];
-        let mut file = File::create(self.filename.clone()).await?;
-        let mut index = 1;
-        let mut attempt = 0;
-        loop {
-            if attempt >= 3 {
-                break;
+            let mut sink = match &self.dir {
+                Some(dir) => {
+                    let writer = DirWriter::new(part.clone(), dir.manifest_len, config.allow_hidden);
+                    Sink::Dir(Box::new(writer.await?))
+                }
+                None => {
+                    let file = File::create(&part).await?;
+                    preallocate(&file, self.size).await?;
+                    Sink::File(file)
+                }
+            };
+            let mut hasher = blake3::Hasher::new();
+
+            // Chunks are checked against the delta instead when the sender diffs
+            let (mut root, mut size) = (self.hash, self.size);
+            let mut patch = None;
+            if let Some(sigs) = &basis {
+                let mut deadline = Instant::now() + DELTA_TIMEOUT;
+                loop {
+                    if job.state() != TransferState::Running {
+                        if !job.wait_running().await {
+                            cancelled = true;
+                            break;
+                        }
+                        deadline = Instant::now() + DELTA_TIMEOUT;
+                    }
+                    tokio::select! {
+                        received = timeout_at(deadline, route.recv()) => match received {
+                            Ok(Some((Packet::Delta(delta), from))) if from == addr => {
+                                println!(
+                                    "Transfer {}: receiving {} of changes against the existing {}",
+                                    job.id,
+                                    HumanBytes(delta.size),
+                                    filename
+                                );
+                                swarm = Swarm::new(delta.size.div_ceil(CHUNK_SIZE as u64) as usize, addr, config.fec);
+                                (root, size) = (delta.hash, delta.size);
+                                patch = Some(Patch::new(path.clone(), sigs));
+                                break;
+                            }
+                            // The sender went for the whole content
+                            Ok(Some((Packet::File(f), from))) if from == addr && f.verify_chunk(&self.hash, self.size) =>
+                            {
+                                break
+                            }
+                            Ok(Some((Packet::Error(err), from))) if from == addr => {
+                                failure = Some((err.code, err.reason));
+                                remote = true;
+                                break;
+                            }
+                            Ok(_) => continue,
+                            Err(_) => {
+                                failure = Some((ErrorCode::Timeout, "no answer to the block signatures".to_string()));
+                                break;
+                            }
+                        },
+                        _ = job.changed() => continue,
+                    }
+                }
             }
-            match timeout(FILE_TIMEOUT, socket.recv_from(&mut buf)).await {
-                Ok(Ok((size, _))) => {
-                    if let Some(packet) = Packet::deserialize(&buf[..size]) {
-                        match packet {
-                            Packet::File(f) => {
-                                if (f.chunk_index == index) && f.verify_chunk() {
-                                    if let Err(e) = file.write_all(&f.data).await {
-                                        println!("Error writing file. \n{}", e);
-                                        attempt += 1;
-                                        continue;
-                                    }
-                                    index += 1;
-                                    pb.set_position(index as u64 * 59 * 1024);
-                                } else {
-                                    println!("f.chunk_index==index: {}", f.chunk_index == index);
-                                    println!("file verification: {}", f.verify_chunk());
+
+            // Chunks of a whole file go straight to their offset, deltas and
+            // directories are streams and wait for the chunks before them
+            let positional = self.dir.is_none() && patch.is_none();
+            let chunk_len = |index: usize| (size - (index as u64 - 1) * CHUNK_SIZE as u64).min(CHUNK_SIZE as u64) as usize;
+            let mut recovery = config
+                .fec
+                .then(|| Recovery::new(size.div_ceil(CHUNK_SIZE as u64) as usize, size));
+
+            // Peers that already have the same content can serve chunks too
+            let others = user_lock.lock().await.connected_addrs();
+            if patch.is_none() {
+                let want = Packet::create_want(self.id, self.hash, self.size, Some(Compression::Lz4));
+                for peer in others.iter().filter(|p| **p != addr) {
+                    if let Err(e) = want.send_packet(socket, peer).await {
+                        println!("Error asking {} for chunks, {}", peer, e);
+                    }
+                }
+            }
+            loop {
+                if swarm.done() || failure.is_some() || cancelled {
+                    break;
+                }
+                if job.state() != TransferState::Running {
+                    if !job.wait_running().await {
+                        cancelled = true;
+                        break;
+                    }
+                    swarm.reset();
+                }
+                // Received data is paid for before more is asked for
+                job.throttle.ready().await;
+                for (peer, request) in swarm.assign() {
+                    let packet = match (request, recovery.as_mut()) {
+                        (Request::Group(group), Some(recovery)) => {
+                            recovery.start(group);
+                            Packet::create_group(self.id, group, recovery.repair_count())
+                        }
+                        (Request::Group(group), None) => Packet::create_group(self.id, group, 0),
+                        (Request::Chunk(index), _) => Packet::create_ackpacket(self.id, index),
+                    };
+                    if let Err(e) = packet.send_packet(socket, &peer).await {
+                        println!("Error requesting {:?} \n{}", request, e);
+                    }
+                }
+                if swarm.is_empty() {
+                    let reason = format!("no peer sent chunk {}", swarm.next_write());
+                    failure = Some((ErrorCode::Timeout, reason));
+                    break;
+                }
+                tokio::select! {
+                    received = timeout_at(swarm.deadline(), route.recv()) => match received {
+                        Ok(Some((Packet::File(f), from))) if swarm.has_peer(from) => {
+                            if !f.verify_chunk(&root, size) {
+                                println!("Chunk {} from {} does not match the file hash", f.chunk_index, from);
+                                swarm.rejected(from, f.chunk_index);
+                                continue;
+                            }
+                            job.throttle.consume(f.data.len() as u64);
+                            if let Some(recovery) = recovery.as_mut() {
+                                recovery.chunk(f.chunk_index, &f.data);
+                            }
+                            swarm.received(from, f.chunk_index, Piece::Data(f.data));
+                        }
+                        Ok(Some((Packet::Zero(z), from))) if swarm.has_peer(from) => {
+                            let chunks = z.chunks();
+                            if !z.verify(&root, size) {
+                                println!("Zero range at chunk {} from {} does not match the file hash", chunks.start(), from);
+                                swarm.rejected(from, *chunks.start());
+                                continue;
+                            }
+                            if let (Sink::File(file), true) = (&mut sink, positional) {
+                                file.flush().await?;
+                                // Where holes can't be punched the range stays allocated, it reads as zeros either way
+                                let _ = sparse::punch_hole(file, z.offset, z.len);
+                            }
+                            for index in chunks {
+                                if let Some(recovery) = recovery.as_mut() {
+                                    recovery.chunk(index, &vec![0; chunk_len(index)]);
                                 }
+                                swarm.received(from, index, Piece::Zeros);
                             }
-                            _ => println!("Other Peer Want to contact"),
+                        }
+                        Ok(Some((Packet::Repair(r), from))) if swarm.has_peer(from) => {
+                            let Some(recovery) = recovery.as_mut() else { continue };
+                            job.throttle.consume(r.data.len() as u64);
+                            let (group, last) = (r.group, r.index + 1 == r.parity);
+                            let rebuilt = recovery.repair(&root, r);
+                            for (index, data) in rebuilt {
+                                swarm.received(from, index, Piece::Data(data));
+                            }
+                            // Every symbol is in and the group is still short
+                            if last {
+                                recovery.abandon(group);
+                                swarm.settle(from, group);
+                            }
+                        }
+                        Ok(Some((Packet::Have(have), from))) if have.hash == self.hash => {
+                            if others.contains(&from) && swarm.add_peer(from) {
+                                let peer = user_lock.lock().await.peer_name(&from).unwrap_or_default();
+                                println!("Transfer {}: also fetching from {}", job.id, peer);
+                            }
+                        }
+                        Ok(Some((Packet::Error(err), from))) if swarm.has_peer(from) => {
+                            swarm.remove_peer(from);
+                            if swarm.is_empty() {
+                                failure = Some((err.code, err.reason));
+                                remote = true;
+                            }
+                        }
+                        Ok(_) => continue,
+                        Err(_) => {
+                            for group in swarm.expire() {
+                                if let Some(recovery) = recovery.as_mut() {
+                                    recovery.abandon(group);
+                                }
+                            }
+                        }
+                    },
+                    _ = job.changed() => continue,
+                }
+                loop {
+                    let written = if let (Sink::File(file), true) = (&mut sink, positional) {
+                        match swarm.take_any() {
+                            Some((index, Piece::Data(data))) => {
+                                write_chunk(file, index, &data).await.map(|_| data.len() as u64)
+                            }
+                            // Punched as a hole when it arrived
+                            Some((index, Piece::Zeros)) => Ok(chunk_len(index) as u64),
+                            None => break,
+                        }
+                    } else {
+                        let index = swarm.next_write();
+                        let data = match swarm.take_ready() {
+                            Some(Piece::Data(data)) => data,
+                            Some(Piece::Zeros) => vec![0; chunk_len(index)],
+                            None => break,
+                        };
+                        match patch.as_mut() {
+                            Some(patch) => apply_delta(patch, &data, &mut sink, &mut hasher).await,
+                            None => sink.write(&data).await.map(|_| {
+                                hasher.update(&data);
+                                data.len() as u64
+                            }),
+                        }
+                    };
+                    match written {
+                        Ok(n) => {
+                            pb.inc(n);
+                            job.progress(n);
+                        }
+                        Err(e) => {
+                            failure = Some((ErrorCode::from_io(&e), format!("writing failed, {}", e)));
+                            break;
                         }
                     }
                 }
-                Ok(Err(e)) => {
-                    println!("Error getting chunk index {} /retrying \n{}", index, e);
-                    attempt += 1;
+            }
+            pb.finish_and_clear();
+
+            if cancelled {
+                drop(sink);
+                discard(&part).await?;
+                println!("Transfer {} of {} cancelled, partial file removed", job.id, filename);
+                return Ok(());
+            }
+            if failure.is_some() {
+                return Ok(());
+            }
+            if patch.as_ref().is_some_and(|p| !p.finished()) {
+                failure = Some((ErrorCode::Verify, "delta ended in the middle of an op".to_string()));
+                return Ok(());
+            }
+            // Every chunk written at its offset was verified against the offered hash
+            let hash = if positional {
+                blake3::Hash::from(self.hash)
+            } else {
+                hasher.finalize()
+            };
+            if hash != blake3::Hash::from(self.hash) {
+                failure = Some((ErrorCode::Verify, "content does not match the offered hash".to_string()));
+                return Ok(());
+            }
+            let summary = match sink {
+                Sink::File(file) => {
+                    // Trailing zeros are never written when the file wasn't preallocated
+                    if positional {
+                        file.set_len(self.size).await?;
+                    }
+                    file.sync_all().await?;
+                    if let Err(e) = self.restore_attributes(file, &part).await {
+                        println!("Could not restore time and permissions of {}, {}", filename, e);
+                    }
+                    String::new()
                 }
-                Err(_) => {
-                    println!("Reciving window timeout chunk index {} /retrying", index,);
-                    attempt += 1;
+                Sink::Dir(mut dir) => {
+                    if let Err(e) = dir.finish().await {
+                        let code = match e.kind() {
+                            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => ErrorCode::Verify,
+                            _ => ErrorCode::from_io(&e),
+                        };
+                        failure = Some((code, e.to_string()));
+                        return Ok(());
+                    }
+                    if dir.skipped > 0 {
+                        format!(", {} files verified, {} skipped", dir.files, dir.skipped)
+                    } else {
+                        format!(", {} files verified", dir.files)
+                    }
                 }
+            };
+            let dest = finalize(&part, &path, policy).await?;
+            // Only the sender gets a receipt, it names the sender's key
+            let receipt = {
+                let user = user_lock.lock().await;
+                user.peer_key(&addr).map(|key| {
+                    Receipt::new(user.identity(), self.id, self.filename.clone(), self.hash, self.size, key)
+                })
+            };
+            for peer in notify(addr, &swarm) {
+                let receipt = receipt.clone().filter(|_| peer == addr);
+                Packet::create_complete(self.id, *hash.as_bytes(), receipt)
+                    .send_packet(socket, &peer)
+                    .await?;
             }
-            let ack = Packet::create_ackpacket(index);
-            if let Err(e) = ack.send_packet(socket, &addr).await {
-                println!("Error sending ack for chunk {} \n{}", index, e);
+            if self.dir.is_none() {
+                let shared = Shared {
+                    name: filename.to_string(),
+                    source: Source::File(dest.clone()),
+                    chunks: None,
+                };
+                user_lock.lock().await.share(self.hash, shared);
             }
-            if index > self.total_chunks {
-                println!("Recived File");
-                break;
+            if let Some(recovery) = recovery.as_ref().filter(|r| r.rebuilt > 0) {
+                println!("Transfer {}: rebuilt {} lost chunks from repair symbols", job.id, recovery.rebuilt);
+            }
+            println!(
+                "Recived {} (verified {}{})",
+                dest.display(),
+                hash.to_hex(),
+                summary
+            );
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = res {
+            failure = Some((ErrorCode::from_io(&e), e.to_string()));
+        }
+        let (outcome, reason) = match &failure {
+            _ if cancelled => (Outcome::Cancelled, None),
+            Some((code, reason)) => (Outcome::Failed, Some(format!("{}: {}", code, reason))),
+            None => (Outcome::Verified, None),
+        };
+        {
+            let mut user = user_lock.lock().await;
+            user.transfers.remove(job.id);
+            user.record(Record::of(&job, self.hash, outcome, reason));
+        }
+        if let Some((code, reason)) = failure {
+            if part.exists() {
+                discard(&part).await?;
+            }
+            if remote {
+                println!("Transfer of {} failed on the sender, {}: {}", filename, code, reason);
+            } else {
+                println!("Transfer of {} failed, {}: {}, partial file removed", filename, code, reason);
+                let packet = Packet::create_error(self.id, code, reason);
+                for peer in notify(addr, &swarm) {
+                    packet.send_packet(socket, &peer).await?;
+                }
             }
         }
-        pb.finish_and_clear();
         Ok(())
     }
 }
 
 impl FilePacket {
-    pub fn new_chunk(
-        filename: String,
-        chunk_index: usize,
-        total_chunks: usize,
-        data: Vec<u8>,
-    ) -> Self {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(&data);
-        let hash = Some(hasher.finalize().to_string());
-
-        FilePacket {
-            filename,
-            filesize: data.len(),
-            chunk_index,
-            total_chunks,
+    /// Takes a frame out of the receive buffer, decompressing it on the way.
+    pub fn from_frame(frame: &Frame) -> io::Result<Self> {
+        let len = frame.header.raw_len as usize;
+        let data = if frame.header.compressed {
+            compress::decompress(frame.data, len)?
+        } else if frame.data.len() == len && len <= CHUNK_SIZE {
+            frame.data.to_vec()
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk size mismatch"));
+        };
+        Ok(FilePacket {
+            id: frame.header.id,
+            chunk_index: frame.header.index as usize,
+            total_chunks: frame.header.total as usize,
             data,
-            hash,
+            proof: frame.proof().collect(),
+        })
+    }
+
+    /// Writes the chunk as an uncompressed frame.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let header = Header {
+            id: self.id,
+            index: self.chunk_index as u32,
+            total: self.total_chunks as u32,
+            raw_len: self.data.len() as u32,
+            compressed: false,
+        };
+        frame::encode(buf, &header, &self.proof, &self.data);
+    }
+
+    /// Checks the chunk against the root hash from the file offer.
+    pub fn verify_chunk(&self, root: &[u8; 32], size: u64) -> bool {
+        self.chunk_index >= 1
+            && tree::verify_chunk(root, size, self.chunk_index - 1, &self.data, &self.proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_names_pass() {
+        assert_eq!(sanitize_filename("report.pdf", false).unwrap(), "report.pdf");
+        assert_eq!(sanitize_filename("  notes.txt. ", false).unwrap(), "notes.txt");
+        assert_eq!(sanitize_filename("tab\there", false).unwrap(), "tab_here");
+        assert_eq!(sanitize_filename(".env", true).unwrap(), ".env");
+    }
+
+    #[test]
+    fn parent_directories_are_refused() {
+        for name in ["..", ".", "../secret", "..\\secret", "a/../../b"] {
+            assert!(sanitize_filename(name, false).is_err(), "{:?}", name);
+            assert!(sanitize_filename(name, true).is_err(), "{:?}", name);
         }
     }
 
-    pub fn verify_chunk(&self) -> bool {
-        if let Some(exp_hash) = &self.hash {
-            let mut hasher = blake3::Hasher::new();
-            hasher.update(&self.data);
-            let computed_hash = hasher.finalize().to_string();
-            computed_hash == *exp_hash
-        } else {
-            true
+    #[test]
+    fn absolute_paths_are_refused() {
+        for name in ["/etc/passwd", "\\Windows\\system.ini", "C:\\boot.ini", "C:evil"] {
+            assert!(sanitize_filename(name, true).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn separators_and_nul_are_refused() {
+        for name in ["a/b", "a\\b", "a\0b", "name\0.txt"] {
+            assert!(sanitize_filename(name, true).is_err(), "{:?}", name);
         }
     }
+
+    #[test]
+    fn reserved_names_are_refused() {
+        for name in ["CON", "con.txt", "Nul", "COM1.log", "lpt9", "aux .tar.gz"] {
+            assert!(sanitize_filename(name, true).is_err(), "{:?}", name);
+        }
+        assert!(sanitize_filename("console.txt", true).is_ok());
+    }
+
+    #[test]
+    fn empty_names_are_refused() {
+        for name in ["", " ", "...", ". .", "\u{0}"] {
+            assert!(sanitize_filename(name, true).is_err(), "{:?}", name);
+        }
+        assert!(sanitize_filename(&"a".repeat(MAX_NAME_LEN + 1), true).is_err());
+    }
+
+    #[test]
+    fn received_modes_are_masked() {
+        assert_eq!(received_mode(0o644), 0o644);
+        assert_eq!(received_mode(0o755), 0o755);
+        assert_eq!(received_mode(0o777), 0o755);
+        assert_eq!(received_mode(0o666), 0o644);
+        assert_eq!(received_mode(0o4755), 0o755);
+        assert_eq!(received_mode(0o2775), 0o755);
+        assert_eq!(received_mode(0o1777), 0o755);
+        assert_eq!(received_mode(0o7777), 0o755);
+    }
+
+    #[test]
+    fn part_files_of_transfers_differ() {
+        let path = Path::new("/downloads/report.pdf");
+        assert_eq!(part_path(path, 3), Path::new("/downloads/report.pdf.3.part"));
+        assert_ne!(part_path(path, 3), part_path(path, 4));
+    }
+
+    #[test]
+    fn hidden_names_need_permission() {
+        assert!(sanitize_filename(".ssh", false).is_err());
+        assert!(sanitize_filename(".ssh", true).is_ok());
+    }
 }
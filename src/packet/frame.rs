@@ -0,0 +1,86 @@
+/// First bytes of a chunk frame. Read as the variant tag of a bincode
+/// `Packet` it is far past the last variant, so the two never mix up.
+pub const MAGIC: [u8; 4] = *b"CPDF";
+/// Magic, flags, transfer id, chunk index, total chunks, raw length and proof length.
+pub const HEADER_LEN: usize = 4 + 1 + 8 + 4 + 4 + 4 + 1;
+const COMPRESSED: u8 = 1;
+const HASH_LEN: usize = 32;
+
+/// Fixed part of a chunk frame, the data plane of a transfer. Control
+/// packets are bincode encoded, chunks are written by hand so they go from
+/// the chunk cache into a reused buffer. They are parsed in place, the
+/// chunk is copied once out of the receive buffer, see `FilePacket::from_frame`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Transfer id from the offer.
+    pub id: u64,
+    /// 1-based.
+    pub index: u32,
+    /// 0 for a stream, whose length is only known at its end.
+    pub total: u32,
+    /// Length of the chunk before compression.
+    pub raw_len: u32,
+    pub compressed: bool,
+}
+
+/// A chunk frame borrowing from the buffer it was received into.
+pub struct Frame<'a> {
+    pub header: Header,
+    proof: &'a [u8],
+    pub data: &'a [u8],
+}
+
+impl Frame<'_> {
+    /// Hashes of the chunk's proof, see `tree.rs`.
+    pub fn proof(&self) -> impl Iterator<Item = [u8; 32]> + '_ {
+        self.proof
+            .chunks_exact(HASH_LEN)
+            .map(|hash| hash.try_into().expect("proof hashes are 32 bytes"))
+    }
+}
+
+/// Writes a chunk frame into `buf`, replacing what it held. `data` is
+/// compressed when the header says so.
+pub fn encode(buf: &mut Vec<u8>, header: &Header, proof: &[[u8; 32]], data: &[u8]) {
+    buf.clear();
+    buf.reserve(HEADER_LEN + proof.len() * HASH_LEN + data.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(if header.compressed { COMPRESSED } else { 0 });
+    buf.extend_from_slice(&header.id.to_le_bytes());
+    buf.extend_from_slice(&header.index.to_le_bytes());
+    buf.extend_from_slice(&header.total.to_le_bytes());
+    buf.extend_from_slice(&header.raw_len.to_le_bytes());
+    buf.push(proof.len() as u8);
+    for hash in proof {
+        buf.extend_from_slice(hash);
+    }
+    buf.extend_from_slice(data);
+}
+
+pub fn is_frame(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Reads a frame in place, `None` when it is cut short or not a frame.
+pub fn decode(bytes: &[u8]) -> Option<Frame<'_>> {
+    if !is_frame(bytes) || bytes.len() < HEADER_LEN {
+        return None;
+    }
+    let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+    let header = Header {
+        compressed: bytes[4] & COMPRESSED != 0,
+        id: u64::from_le_bytes(bytes[5..13].try_into().unwrap()),
+        index: u32_at(13),
+        total: u32_at(17),
+        raw_len: u32_at(21),
+    };
+    let proof_end = HEADER_LEN + bytes[25] as usize * HASH_LEN;
+    if bytes.len() < proof_end {
+        return None;
+    }
+    Some(Frame {
+        header,
+        proof: &bytes[HEADER_LEN..proof_end],
+        data: &bytes[proof_end..],
+    })
+}
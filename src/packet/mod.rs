@@ -1,15 +1,47 @@
+pub mod browse;
+pub mod cache;
 mod chat;
+pub mod compress;
+pub mod delta;
+pub mod dir;
+pub mod fec;
 pub mod file;
+pub mod frame;
+pub mod receipt;
+pub mod room;
+pub mod session;
+pub mod source;
+pub mod sparse;
+pub mod stream;
+pub mod swarm;
+pub mod sync;
+pub mod transfer;
+pub mod tree;
 
 use crate::ReceiverRes;
 
+use super::user::identity::{self, fingerprint, PublicKey};
 use super::user::User;
+use browse::{BrowsePacket, GetPacket, ListingPacket};
+use delta::{DeltaPacket, Signatures};
 use chat::ChatPacket;
+use compress::Compression;
+use fec::{GroupPacket, RepairPacket};
+use sparse::ZeroPacket;
+use stream::{StreamEnd, StreamOffer, StreamRequest};
+use sync::SyncPacket;
 use crossterm::{
     execute,
     style::{Color, Print, ResetColor, SetForegroundColor},
 };
+use dir::Manifest;
 use file::{AckPacket, FileMetadata, FilePacket, MetadataRes};
+use receipt::Receipt;
+use room::{RoomHandshake, RoomKey, RoomPacket};
+use transfer::{
+    CompletePacket, ErrorCode, ErrorPacket, RejectPacket, TransferPacket, TransferState,
+};
+use swarm::{HavePacket, WantPacket};
 use serde::{Deserialize, Serialize};
 use std::{
     io::{self, Write},
@@ -28,6 +60,24 @@ pub enum Packet {
     Discovery(bool),
     Metadata(FileMetadata),
     MdRes(MetadataRes),
+    Room(RoomPacket),
+    Transfer(TransferPacket),
+    Reject(RejectPacket),
+    Error(ErrorPacket),
+    Complete(CompletePacket),
+    Want(WantPacket),
+    Have(HavePacket),
+    Browse(BrowsePacket),
+    Listing(ListingPacket),
+    Get(GetPacket),
+    Delta(DeltaPacket),
+    Sync(SyncPacket),
+    Group(GroupPacket),
+    Repair(RepairPacket),
+    StreamOffer(StreamOffer),
+    StreamRequest(StreamRequest),
+    StreamEnd(StreamEnd),
+    Zero(ZeroPacket),
 }
 
 impl Packet {
@@ -35,38 +85,125 @@ impl Packet {
         Packet::Chat(ChatPacket::new(username, message))
     }
 
-    pub fn create_ackpacket(chunk: usize) -> Self {
-        Packet::Ack(AckPacket::new(chunk))
+    pub fn create_queued_chat(username: String, message: String, time: i64, id: u64) -> Self {
+        Packet::Chat(ChatPacket::written_at(username, message, time, id))
+    }
+
+    pub fn create_ackpacket(id: u64, chunk: usize) -> Self {
+        Packet::Ack(AckPacket::new(id, chunk))
+    }
+
+    pub fn create_transfer_packet(id: u64, state: TransferState) -> Self {
+        Packet::Transfer(TransferPacket::new(id, state))
+    }
+
+    pub fn create_reject(id: u64) -> Self {
+        Packet::Reject(RejectPacket { id })
+    }
+
+    pub fn create_error(id: u64, code: ErrorCode, reason: String) -> Self {
+        Packet::Error(ErrorPacket { id, code, reason })
+    }
+
+    pub fn create_complete(id: u64, hash: [u8; 32], receipt: Option<Receipt>) -> Self {
+        Packet::Complete(CompletePacket { id, hash, receipt })
+    }
+
+    pub fn create_want(id: u64, hash: [u8; 32], size: u64, compression: Option<Compression>) -> Self {
+        Packet::Want(WantPacket {
+            id,
+            hash,
+            size,
+            compression,
+        })
+    }
+
+    pub fn create_group(id: u64, group: usize, repair: u8) -> Self {
+        Packet::Group(GroupPacket { id, group, repair })
+    }
+
+    pub fn create_stream_offer(id: u64, sender: String) -> Self {
+        Packet::StreamOffer(StreamOffer { id, sender })
+    }
+
+    pub fn create_stream_request(id: u64, from: usize, chunks: Vec<usize>) -> Self {
+        Packet::StreamRequest(StreamRequest { id, from, chunks })
+    }
+
+    pub fn create_stream_end(id: u64, chunks: usize, size: u64, hash: [u8; 32]) -> Self {
+        Packet::StreamEnd(StreamEnd { id, chunks, size, hash })
+    }
+
+    pub fn create_zero(id: u64, offset: u64, len: u64, proof: Vec<tree::ChainingValue>) -> Self {
+        Packet::Zero(ZeroPacket {
+            id,
+            offset,
+            len,
+            proof,
+        })
+    }
+
+    pub fn create_have(id: u64, hash: [u8; 32]) -> Self {
+        Packet::Have(HavePacket { id, hash })
+    }
+
+    pub fn create_browse(id: u64, page: u32) -> Self {
+        Packet::Browse(BrowsePacket { id, page })
     }
 
-    pub fn create_file_res(file: FileMetadata) -> Self {
-        Packet::MdRes(MetadataRes::new(file))
+    pub fn create_get(id: u64, path: String, folder: Option<String>) -> Self {
+        Packet::Get(GetPacket { id, path, folder })
     }
 
-    pub fn create_filemetadata(filename: String, chunks: usize) -> Self {
-        Packet::Metadata(FileMetadata::new(filename, chunks))
+    pub fn create_file_res(file: FileMetadata, basis: Option<Signatures>) -> Self {
+        Packet::MdRes(MetadataRes::new(file, basis))
     }
 
-    pub fn create_file_packet(
+    pub fn create_delta(id: u64, hash: [u8; 32], size: u64) -> Self {
+        Packet::Delta(DeltaPacket { id, hash, size })
+    }
+
+    pub fn create_filemetadata(
+        sender: String,
         filename: String,
-        chunk_index: usize,
-        total_chunks: usize,
-        data: Vec<u8>,
+        size: u64,
+        hash: [u8; 32],
+        metadata: &std::fs::Metadata,
     ) -> Self {
-        Packet::File(FilePacket::new_chunk(
+        Packet::Metadata(FileMetadata::new(sender, filename, size, hash, metadata))
+    }
+
+    pub fn create_dirmetadata(
+        sender: String,
+        filename: String,
+        size: u64,
+        hash: [u8; 32],
+        metadata: &std::fs::Metadata,
+        manifest: &Manifest,
+        manifest_len: u64,
+    ) -> Self {
+        Packet::Metadata(FileMetadata::new_dir(
+            sender,
             filename,
-            chunk_index,
-            total_chunks,
-            data,
+            size,
+            hash,
+            metadata,
+            manifest,
+            manifest_len,
         ))
     }
 
-    pub fn create_binding_req(v: bool, name: String) -> Self {
-        Packet::Bind(BindingPacket::new(true, v, name))
+    pub fn create_binding_req(v: bool, user: &User, nonce: u64) -> Self {
+        Packet::Bind(BindingPacket::new(true, v, user, nonce, 0))
     }
 
-    pub fn create_binding_res(v: bool, name: String) -> Self {
-        Packet::Bind(BindingPacket::new(false, v, name))
+    pub fn create_binding_res(v: bool, user: &User, nonce: u64, echo: u64) -> Self {
+        Packet::Bind(BindingPacket::new(false, v, user, nonce, echo))
+    }
+
+    pub fn create_room_hello(key: &RoomKey) -> Option<(Self, RoomHandshake)> {
+        let (hello, state) = RoomPacket::hello(key)?;
+        Some((Packet::Room(hello), state))
     }
 
     pub fn _create_discover(v: bool) -> Self {
@@ -74,11 +211,32 @@ impl Packet {
     }
 
     pub fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(self).expect("failed to Serialize packet")
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    /// Chunks are written as frames, everything else with bincode.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            Packet::File(chunk) => chunk.encode(buf),
+            _ => {
+                buf.clear();
+                bincode::serialize_into(&mut *buf, self).expect("failed to Serialize packet");
+            }
+        }
     }
 
     pub fn deserialize(bytes: &[u8]) -> Option<Self> {
-        bincode::deserialize(bytes).ok()
+        if let Some(frame) = frame::decode(bytes) {
+            // Dropped when it doesn't unpack, the receiver asks for it again
+            return FilePacket::from_frame(&frame).ok().map(Packet::File);
+        }
+        match bincode::deserialize(bytes).ok()? {
+            // Chunks only travel as frames
+            Packet::File(_) => None,
+            packet => Some(packet),
+        }
     }
 
     pub async fn send_packet(
@@ -86,7 +244,11 @@ impl Packet {
         socket: &UdpSocket,
         peer: &SocketAddr,
     ) -> tokio::io::Result<()> {
-        let data = self.serialize();
+        let mut data = self.serialize();
+        // Handshakes go in the clear, they set up the session
+        if !matches!(self, Packet::Room(_)) {
+            session::seal(*peer, &mut data)?;
+        }
         socket.send_to(&data, peer).await?;
         Ok(())
     }
@@ -99,13 +261,17 @@ impl Packet {
         res_rx: ReceiverRes,
     ) {
         if let Packet::Bind(bind) = self {
+            if !bind.verify() {
+                eprintln!("Ignored connection packet from {}, its signature doesn't match its key", addr);
+                return;
+            }
             if bind.req {
                 if let Err(e) = bind.handle_binding_req(socket, addr, user_lock, res_rx).await {
                     eprintln!("Error Sending packet, {}",e);
                 }
                     
             } else {
-                if let Err(e) = bind.handle_binding_res(addr, user_lock).await {
+                if let Err(e) = bind.handle_binding_res(socket, addr, user_lock).await {
                     eprintln!("Error Sending response, {}", e);
                 }
             }
@@ -113,16 +279,54 @@ impl Packet {
     }
 }
 
+/// Binding packets are signed with the sender's identity key. A request
+/// carries a fresh nonce the answer has to sign back, and an accepting
+/// answer carries one the requester confirms with, so neither side can be
+/// impersonated by replaying old packets. Addresses and clocks aren't
+/// trusted, NATs rewrite the one and the other drifts.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BindingPacket {
     pub req: bool,
     pub accept: bool,
     pub name: String,
+    pub key: PublicKey,
+    /// Has to come back as `echo` of the answer, 0 when none is expected.
+    pub nonce: u64,
+    /// Nonce of the packet this one answers, 0 for requests.
+    pub echo: u64,
+    pub sig: Vec<u8>,
 }
 
 impl BindingPacket {
-    pub fn new(req: bool, accept: bool, name: String) -> Self {
-        BindingPacket { req, accept, name }
+    pub fn new(req: bool, accept: bool, user: &User, nonce: u64, echo: u64) -> Self {
+        let mut bind = BindingPacket {
+            req,
+            accept,
+            name: user.get_name(),
+            key: user.identity().public(),
+            nonce,
+            echo,
+            sig: Vec::new(),
+        };
+        bind.sig = user.identity().sign(&bind.message());
+        bind
+    }
+
+    fn message(&self) -> Vec<u8> {
+        let fields = (
+            "connect-p2p bind",
+            self.req,
+            self.accept,
+            &self.name,
+            self.key,
+            self.nonce,
+            self.echo,
+        );
+        bincode::serialize(&fields).expect("failed to Serialize binding")
+    }
+
+    pub fn verify(&self) -> bool {
+        identity::verify(&self.key, &self.message(), &self.sig)
     }
 
     async fn handle_binding_req(
@@ -134,7 +338,11 @@ impl BindingPacket {
     ) -> tokio::io::Result<()> {
         if self.accept {
             user_lock.lock().await.req_res();
-            print!("Connection req from {} : [y/n] -> ", self.name);
+            print!(
+                "Connection req from {} ({}) : [y/n] -> ",
+                self.name,
+                fingerprint(&self.key)
+            );
             io::stdout().flush().unwrap();
             let mut res = false;
             for _ in 0..3 {
@@ -148,12 +356,6 @@ impl BindingPacket {
                         .to_string();
                     if ans == 'y'.to_string() {
                         res = true;
-                        execute!(
-                            io::stdout(),
-                            SetForegroundColor(Color::Green),
-                            Print(format!("Peer Connected {} \n", self.name)),
-                            ResetColor
-                        )?;
                         break;
                     } else if ans == 'n'.to_string() {
                         break;
@@ -172,22 +374,28 @@ impl BindingPacket {
             }
             let mut user = user_lock.lock().await;
             user.req_resolve();
-            if res {
-                user.add_peer(addr, self.name.clone());
-            }
-            let packet = Packet::create_binding_res(res, user.get_name());
+            // Connected once the requester signs the challenge back
+            let nonce = if res { user.bind_nonce(addr, Some(self.key)) } else { 0 };
+            let packet = Packet::create_binding_res(res, &user, nonce, self.nonce);
             drop(user);
             if let Err(e) = packet.send_packet(socket, &addr).await {
                 println!("Error in sending binding response {:?}", e);
             }
         } else {
             let mut user = user_lock.lock().await;
+            // Anyone could replay an old disconnect, only the peer's own key counts
+            if user.peer_key(&addr) != Some(self.key) {
+                eprintln!("Ignored disconnect from {}, it isn't signed by the peer connected there", addr);
+                return Ok(());
+            }
             user.remove_peer(addr);
-            let packet = Packet::create_binding_res(false, user.get_name());
+            let packet = Packet::create_binding_res(false, &user, 0, self.nonce);
             drop(user);
             if let Err(e) = packet.send_packet(socket, &addr).await {
                 println!("Error in sending binding response {:?}", e);
             }
+            // The answer still goes out sealed, a room peer drops anything else
+            session::remove(addr);
             execute!(
                 io::stdout(),
                 SetForegroundColor(Color::Red),
@@ -198,22 +406,40 @@ impl BindingPacket {
         Ok(())
     }
 
+    /// Answers to a request or challenge sent from here. The peer is added
+    /// once the answer signs back our nonce, the requester then confirms
+    /// the challenge that came with it.
     async fn handle_binding_res(
         &self,
+        socket: &UdpSocket,
         addr: SocketAddr,
         user_lock: Arc<Mutex<User>>,
     ) -> tokio::io::Result<()> {
         let mut user = user_lock.lock().await;
+        if let Err(reason) = user.take_bind(&addr, self.echo, &self.key) {
+            eprintln!("Ignored connection answer from {} ({}), {}", addr, fingerprint(&self.key), reason);
+            return Ok(());
+        }
         if self.accept {
-            user.add_peer(addr, self.name.clone());
+            user.add_peer(addr, self.name.clone(), self.key);
+            let confirm = (self.nonce != 0).then(|| Packet::create_binding_res(true, &user, 0, self.nonce));
+            drop(user);
+            let text = match confirm {
+                Some(confirm) => {
+                    confirm.send_packet(socket, &addr).await?;
+                    format!("Connected to {} ({}) \n", self.name, fingerprint(&self.key))
+                }
+                None => format!("Peer Connected {} \n", self.name),
+            };
             execute!(
                 io::stdout(),
                 SetForegroundColor(Color::Green),
-                Print(format!("Connected to {} \n", self.name)),
+                Print(text),
                 ResetColor
             )?;
         } else {
             user.remove_peer(addr);
+            session::remove(addr);
             execute!(
                 io::stdout(),
                 SetForegroundColor(Color::Red),
@@ -0,0 +1,143 @@
+use chrono::{Local, TimeZone};
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::user::identity::{self, fingerprint, Identity, PublicKey};
+
+/// Proof of delivery the receiver signs with its identity key once the
+/// whole content verified, sent back with the complete packet. Anyone with
+/// the receipt can check it offline, the receiver's key is inside.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Receipt {
+    /// Transfer id both sides share.
+    pub id: u64,
+    pub name: String,
+    pub hash: [u8; 32],
+    pub size: u64,
+    /// Unix seconds when the receiver verified the content.
+    pub time: i64,
+    pub sender: PublicKey,
+    pub receiver: PublicKey,
+    pub sig: Vec<u8>,
+}
+
+impl Receipt {
+    pub fn new(identity: &Identity, id: u64, name: String, hash: [u8; 32], size: u64, sender: PublicKey) -> Self {
+        let mut receipt = Receipt {
+            id,
+            name,
+            hash,
+            size,
+            time: Local::now().timestamp(),
+            sender,
+            receiver: identity.public(),
+            sig: Vec::new(),
+        };
+        receipt.sig = identity.sign(&receipt.message());
+        receipt
+    }
+
+    fn message(&self) -> Vec<u8> {
+        let fields = (
+            "connect-p2p receipt",
+            self.id,
+            &self.name,
+            self.hash,
+            self.size,
+            self.time,
+            self.sender,
+            self.receiver,
+        );
+        bincode::serialize(&fields).expect("failed to Serialize receipt")
+    }
+
+    pub fn verify(&self) -> bool {
+        identity::verify(&self.receiver, &self.message(), &self.sig)
+    }
+
+    /// Signed by `receiver` for this content sent by `sender`.
+    pub fn confirms(&self, sender: &PublicKey, receiver: &PublicKey, hash: [u8; 32], size: u64) -> bool {
+        self.verify() && self.sender == *sender && self.receiver == *receiver && self.hash == hash && self.size == size
+    }
+
+    /// Base58 text of the receipt, how it is stored and passed around.
+    pub fn encode(&self) -> String {
+        let bytes = bincode::serialize(self).expect("failed to Serialize receipt");
+        bs58::encode(bytes).into_string()
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        let bytes = bs58::decode(text.trim()).into_vec().ok()?;
+        bincode::deserialize(&bytes).ok()
+    }
+}
+
+impl fmt::Display for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = Local
+            .timestamp_opt(self.time, 0)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let signature = if self.verify() { "valid" } else { "INVALID" };
+        write!(
+            f,
+            "  name:      {}\n  size:      {} ({} bytes)\n  blake3:    {}\n  received:  {}\n  sender:    {}\n  receiver:  {}\n  signature: {}",
+            self.name,
+            HumanBytes(self.size),
+            self.size,
+            blake3::Hash::from(self.hash).to_hex(),
+            time,
+            fingerprint(&self.sender),
+            fingerprint(&self.receiver),
+            signature,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt() -> (Receipt, Identity, Identity) {
+        let (sender, receiver) = (Identity::ephemeral(), Identity::ephemeral());
+        let receipt = Receipt::new(&receiver, 7, "report.pdf".to_string(), [3; 32], 1234, sender.public());
+        (receipt, sender, receiver)
+    }
+
+    #[test]
+    fn signed_receipts_verify() {
+        let (receipt, sender, receiver) = receipt();
+        assert!(receipt.verify());
+        assert!(receipt.confirms(&sender.public(), &receiver.public(), [3; 32], 1234));
+        let decoded = Receipt::decode(&receipt.encode()).unwrap();
+        assert_eq!(decoded, receipt);
+        assert!(decoded.verify());
+    }
+
+    #[test]
+    fn tampered_receipts_fail() {
+        let (receipt, sender, receiver) = receipt();
+        let mut tampered = receipt.clone();
+        tampered.size += 1;
+        assert!(!tampered.verify());
+        let mut tampered = receipt.clone();
+        tampered.hash[0] ^= 1;
+        assert!(!tampered.verify());
+        let mut tampered = receipt.clone();
+        tampered.name = "other.pdf".to_string();
+        assert!(!tampered.verify());
+        let mut tampered = receipt.clone();
+        tampered.sig[0] ^= 1;
+        assert!(!tampered.verify());
+        // Signed again by someone else, valid on its own but not from the receiver
+        let other = Identity::ephemeral();
+        let forged = Receipt::new(&other, 7, "report.pdf".to_string(), [3; 32], 1234, sender.public());
+        assert!(forged.verify());
+        assert!(!forged.confirms(&sender.public(), &receiver.public(), [3; 32], 1234));
+        assert!(!receipt.confirms(&receiver.public(), &receiver.public(), [3; 32], 1234));
+        assert!(!receipt.confirms(&sender.public(), &receiver.public(), [3; 32], 1235));
+        assert!(Receipt::decode("not a receipt").is_none());
+    }
+}
@@ -0,0 +1,497 @@
+use argon2::Argon2;
+use crossterm::{
+    execute,
+    style::{Color, Print, ResetColor, SetForegroundColor},
+};
+use serde::{Deserialize, Serialize};
+use snow::HandshakeState;
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::UdpSocket,
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+use crate::user::identity::{self, PublicKey};
+use crate::user::User;
+
+use super::session::{self, Session};
+use super::Packet;
+
+/// The joiner's static key and identity travel last, after the passphrase
+/// is mixed in, so nothing sent before it depends on the passphrase.
+const PARAMS: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
+const PROLOGUE: &[u8] = b"connect-p2p room v2";
+const KEY_CONTEXT: &str = "connect-p2p 2024 room passphrase";
+/// Introductions are padded to this so the length of a name doesn't leak.
+const INTRO_LEN: usize = 256;
+const MAX_MESSAGE: usize = 65535;
+/// Handshakes kept at once, a hello from another address is dropped
+/// while this many wait.
+const MAX_PENDING: usize = 64;
+/// A handshake that doesn't go on for this long is forgotten.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(30);
+/// Passphrase stretches a member runs at once for joiners, a join that
+/// comes while they are busy is dropped and the joiner tries again.
+const MAX_STRETCHES: usize = 2;
+
+pub type RoomKey = [u8; 32];
+pub type Salt = [u8; 16];
+type Psk = [u8; 32];
+
+/// Four message handshake between a peer joining a room and a member of
+/// it, a Noise XXpsk3 handshake and a welcome sealed with its keys:
+///
+/// ```text
+/// Hello    -> e
+/// Reply    <- e, ee, s, es
+/// Join     -> s, se, psk        joiner's introduction
+/// Welcome  <- sealed            member's introduction
+/// ```
+///
+/// The passphrase is stretched with the salt of the hello into the psk, so
+/// a guess is only checked against one handshake it was made for, and
+/// only by someone who answered that hello. Names and identity keys travel
+/// encrypted under keys that need the passphrase, so someone without it
+/// can neither join nor learn who is in the room. The session keys then
+/// encrypt all traffic with the peer, see `session.rs`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RoomPacket {
+    Hello { salt: Salt, noise: Vec<u8> },
+    Reply { noise: Vec<u8> },
+    Join { salt: Salt, noise: Vec<u8> },
+    Welcome { sealed: Vec<u8> },
+}
+
+/// Where a handshake with one address stands.
+#[derive(Debug)]
+pub enum RoomHandshake {
+    /// Joiner, waiting for the member's reply.
+    Hello { salt: Salt, noise: HandshakeState },
+    /// Member, waiting for the joiner's introduction.
+    Reply { noise: HandshakeState },
+    /// Joiner, the keys are known but only used once the member's welcome
+    /// shows it accepted the join.
+    Join { session: Session, hash: Vec<u8> },
+}
+
+type Pending = HashMap<SocketAddr, (Instant, RoomHandshake)>;
+
+/// Handshakes in flight by address. Shared by every copy of the user like
+/// the rate limits, a handshake state can't be cloned.
+#[derive(Clone, Debug)]
+pub struct Handshakes {
+    pending: Arc<std::sync::Mutex<Pending>>,
+    stretches: Arc<Semaphore>,
+}
+
+impl Default for Handshakes {
+    fn default() -> Self {
+        Handshakes {
+            pending: Arc::default(),
+            stretches: Arc::new(Semaphore::new(MAX_STRETCHES)),
+        }
+    }
+}
+
+impl Handshakes {
+    /// Keeps the handshake with `addr`, false when too many others wait.
+    pub fn insert(&self, addr: SocketAddr, state: RoomHandshake) -> bool {
+        let mut pending = self.lock();
+        let now = Instant::now();
+        pending.retain(|_, (started, _)| now.duration_since(*started) < PENDING_TIMEOUT);
+        if pending.len() >= MAX_PENDING && !pending.contains_key(&addr) {
+            return false;
+        }
+        pending.insert(addr, (now, state));
+        true
+    }
+
+    /// Takes the handshake with `addr`, a message that doesn't fit it ends it.
+    pub fn take(&self, addr: SocketAddr) -> Option<RoomHandshake> {
+        let (started, state) = self.lock().remove(&addr)?;
+        (started.elapsed() < PENDING_TIMEOUT).then_some(state)
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// A slot for stretching a joiner's passphrase, `None` while all are busy.
+    fn stretch(&self) -> Option<OwnedSemaphorePermit> {
+        self.stretches.clone().try_acquire_owned().ok()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Pending> {
+        self.pending.lock().expect("handshakes lock poisoned")
+    }
+}
+
+/// Name, identity key and a signature over the handshake, proving the
+/// identity took part in this very handshake.
+#[derive(Serialize, Deserialize)]
+struct Introduction {
+    name: String,
+    key: PublicKey,
+    sig: Vec<u8>,
+}
+
+/// Only hashes the passphrase, it is stretched per handshake in `psk`.
+pub fn derive_room_key(passphrase: &str) -> RoomKey {
+    blake3::derive_key(KEY_CONTEXT, passphrase.as_bytes())
+}
+
+/// Slow on purpose, every guess at the passphrase costs the same.
+fn psk(key: &RoomKey, salt: &Salt) -> Option<Psk> {
+    let mut psk = [0; 32];
+    Argon2::default()
+        .hash_password_into(key, salt, &mut psk)
+        .ok()?;
+    Some(psk)
+}
+
+/// The salt is bound through the prologue, a handshake breaks if it is changed.
+fn noise(salt: &Salt, psk: Option<&Psk>, initiator: bool) -> Option<HandshakeState> {
+    let builder = snow::Builder::new(PARAMS.parse().ok()?);
+    // The identity is proven by the introductions, the static key only adds
+    // the DH the pattern needs
+    let keypair = builder.generate_keypair().ok()?;
+    let prologue = [PROLOGUE, salt].concat();
+    let mut builder = builder
+        .local_private_key(&keypair.private)
+        .prologue(&prologue);
+    if let Some(psk) = psk {
+        builder = builder.psk(3, psk);
+    }
+    if initiator {
+        builder.build_initiator().ok()
+    } else {
+        builder.build_responder().ok()
+    }
+}
+
+fn write(noise: &mut HandshakeState, payload: &[u8]) -> Option<Vec<u8>> {
+    let mut message = vec![0; MAX_MESSAGE];
+    let len = noise.write_message(payload, &mut message).ok()?;
+    message.truncate(len);
+    Some(message)
+}
+
+fn read(noise: &mut HandshakeState, message: &[u8]) -> Option<Vec<u8>> {
+    let mut payload = vec![0; MAX_MESSAGE];
+    let len = noise.read_message(message, &mut payload).ok()?;
+    payload.truncate(len);
+    Some(payload)
+}
+
+fn signed_message(role: &str, hash: &[u8]) -> Vec<u8> {
+    [b"connect-p2p room ".as_slice(), role.as_bytes(), hash].concat()
+}
+
+fn introduce(user: &User, role: &str, hash: &[u8]) -> Vec<u8> {
+    let intro = Introduction {
+        name: user.get_name(),
+        key: user.identity().public(),
+        sig: user.identity().sign(&signed_message(role, hash)),
+    };
+    let mut bytes = bincode::serialize(&intro).expect("introductions serialize");
+    if bytes.len() < INTRO_LEN {
+        bytes.resize(INTRO_LEN, 0);
+    }
+    bytes
+}
+
+fn verify_intro(bytes: &[u8], role: &str, hash: &[u8]) -> Option<(String, PublicKey)> {
+    // Trailing padding is ignored
+    let intro: Introduction = bincode::deserialize(bytes).ok()?;
+    identity::verify(&intro.key, &signed_message(role, hash), &intro.sig)
+        .then_some((intro.name, intro.key))
+}
+
+impl RoomPacket {
+    /// Starts joining through the member at the other end, stretching the
+    /// passphrase first so it blocks.
+    pub fn hello(key: &RoomKey) -> Option<(Self, RoomHandshake)> {
+        let salt: Salt = rand::random();
+        let psk = psk(key, &salt)?;
+        let mut noise = noise(&salt, Some(&psk), true)?;
+        let packet = RoomPacket::Hello {
+            salt,
+            noise: write(&mut noise, &[])?,
+        };
+        Some((packet, RoomHandshake::Hello { salt, noise }))
+    }
+
+    /// Member side, answers a hello without knowing yet if the joiner has
+    /// the passphrase. The answer says nothing about the member.
+    fn reply(salt: &Salt, message: &[u8]) -> Option<(Self, RoomHandshake)> {
+        let mut noise = noise(salt, None, false)?;
+        read(&mut noise, message)?;
+        let packet = RoomPacket::Reply {
+            noise: write(&mut noise, &[])?,
+        };
+        Some((packet, RoomHandshake::Reply { noise }))
+    }
+
+    /// Joiner side, mixes in the passphrase and introduces the joiner.
+    fn join(
+        salt: Salt,
+        mut noise: HandshakeState,
+        message: &[u8],
+        user: &User,
+    ) -> Option<(Self, RoomHandshake)> {
+        read(&mut noise, message)?;
+        let intro = introduce(user, "joiner", noise.get_handshake_hash());
+        let packet = RoomPacket::Join {
+            salt,
+            noise: write(&mut noise, &intro)?,
+        };
+        let hash = noise.get_handshake_hash().to_vec();
+        let session = Session::new(noise.into_stateless_transport_mode().ok()?);
+        Some((packet, RoomHandshake::Join { session, hash }))
+    }
+
+    /// Member side, checks the passphrase and the joiner's introduction and
+    /// answers with its own, sealed with the new session.
+    fn welcome(
+        psk: &Psk,
+        mut noise: HandshakeState,
+        message: &[u8],
+        user: &User,
+    ) -> Option<(Self, Session, String, PublicKey)> {
+        noise.set_psk(3, psk).ok()?;
+        let signed = noise.get_handshake_hash().to_vec();
+        let intro = read(&mut noise, message)?;
+        let (name, key) = verify_intro(&intro, "joiner", &signed)?;
+        let hash = noise.get_handshake_hash().to_vec();
+        let session = Session::new(noise.into_stateless_transport_mode().ok()?);
+        let sealed = session.seal(&introduce(user, "member", &hash)).ok()?;
+        Some((RoomPacket::Welcome { sealed }, session, name, key))
+    }
+
+    /// Joiner side, the member's introduction completes the join.
+    fn finish(session: &Session, hash: &[u8], sealed: &[u8]) -> Option<(String, PublicKey)> {
+        verify_intro(&session.open(sealed)?, "member", hash)
+    }
+
+    pub async fn handle(
+        &self,
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        user_lock: Arc<Mutex<User>>,
+    ) -> tokio::io::Result<()> {
+        // Anything that does not verify is dropped silently, outsiders get no
+        // answer that tells them anything
+        let Some(key) = user_lock.lock().await.room_key() else {
+            return Ok(());
+        };
+
+        match self {
+            RoomPacket::Hello { salt, noise } => {
+                let Some((packet, state)) = RoomPacket::reply(salt, noise) else {
+                    return Ok(());
+                };
+                if !user_lock.lock().await.room_pending.insert(addr, state) {
+                    return Ok(());
+                }
+                Packet::Room(packet).send_packet(socket, &addr).await?;
+            }
+            RoomPacket::Reply { noise } => {
+                let user = user_lock.lock().await;
+                let Some(RoomHandshake::Hello { salt, noise: state }) =
+                    user.room_pending.take(addr)
+                else {
+                    return Ok(());
+                };
+                let Some((packet, state)) = RoomPacket::join(salt, state, noise, &user) else {
+                    return Ok(());
+                };
+                if !user.room_pending.insert(addr, state) {
+                    return Ok(());
+                }
+                drop(user);
+                Packet::Room(packet).send_packet(socket, &addr).await?;
+            }
+            RoomPacket::Join { salt, noise } => {
+                // Only a join that follows a reply to the same address is
+                // stretched, and only a few at once, so forged joins can't
+                // keep the member busy
+                let (state, permit) = {
+                    let user = user_lock.lock().await;
+                    let Some(RoomHandshake::Reply { noise: state }) = user.room_pending.take(addr)
+                    else {
+                        return Ok(());
+                    };
+                    let Some(permit) = user.room_pending.stretch() else {
+                        return Ok(());
+                    };
+                    (state, permit)
+                };
+                let salt = *salt;
+                let psk = tokio::task::spawn_blocking(move || psk(&key, &salt))
+                    .await
+                    .map_err(io::Error::other)?;
+                drop(permit);
+                let Some(psk) = psk else {
+                    return Ok(());
+                };
+                let mut user = user_lock.lock().await;
+                let Some((packet, session, name, key)) =
+                    RoomPacket::welcome(&psk, state, noise, &user)
+                else {
+                    return Ok(());
+                };
+                session::install(addr, session);
+                user.add_peer(addr, name.clone(), key);
+                drop(user);
+                Packet::Room(packet).send_packet(socket, &addr).await?;
+                execute!(
+                    io::stdout(),
+                    SetForegroundColor(Color::Green),
+                    Print(format!("{} joined the room \n", name)),
+                    ResetColor
+                )?;
+            }
+            RoomPacket::Welcome { sealed } => {
+                let mut user = user_lock.lock().await;
+                let Some(RoomHandshake::Join { session, hash }) = user.room_pending.take(addr)
+                else {
+                    return Ok(());
+                };
+                let Some((name, key)) = RoomPacket::finish(&session, &hash, sealed) else {
+                    return Ok(());
+                };
+                session::install(addr, session);
+                user.add_peer(addr, name.clone(), key);
+                drop(user);
+                execute!(
+                    io::stdout(),
+                    SetForegroundColor(Color::Green),
+                    Print(format!("Joined room with {} \n", name)),
+                    ResetColor
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::user::{history::History, identity::Identity, outbox::Outbox, sync::SyncDb};
+
+    fn user(name: &str) -> User {
+        User::new(
+            name.to_string(),
+            Config::default(),
+            Identity::ephemeral(),
+            SyncDb::default(),
+            Outbox::default(),
+            History::default(),
+        )
+    }
+
+    /// Runs a handshake between a joiner and a member with their own
+    /// passphrases, returning both sessions if it completes.
+    fn handshake(
+        joiner: &User,
+        joining: &str,
+        member: &User,
+        room: &str,
+    ) -> Option<(Session, Session)> {
+        let (hello, state) = RoomPacket::hello(&derive_room_key(joining)).unwrap();
+        let RoomPacket::Hello { salt, noise } = hello else {
+            unreachable!()
+        };
+        let (reply, member_state) = RoomPacket::reply(&salt, &noise).unwrap();
+        let (RoomHandshake::Hello { salt, noise: state }, RoomPacket::Reply { noise }) =
+            (state, reply)
+        else {
+            unreachable!()
+        };
+        let (join, state) = RoomPacket::join(salt, state, &noise, joiner).unwrap();
+        let (
+            RoomHandshake::Reply {
+                noise: member_state,
+            },
+            RoomPacket::Join { salt, noise },
+        ) = (member_state, join)
+        else {
+            unreachable!()
+        };
+        let psk = psk(&derive_room_key(room), &salt).unwrap();
+        let (welcome, member_session, name, key) =
+            RoomPacket::welcome(&psk, member_state, &noise, member)?;
+        assert_eq!((name, key), (joiner.get_name(), joiner.identity().public()));
+        let (RoomHandshake::Join { session, hash }, RoomPacket::Welcome { sealed }) =
+            (state, welcome)
+        else {
+            unreachable!()
+        };
+        let (name, key) = RoomPacket::finish(&session, &hash, &sealed).unwrap();
+        assert_eq!((name, key), (member.get_name(), member.identity().public()));
+        Some((session, member_session))
+    }
+
+    #[test]
+    fn room_handshake_yields_shared_session_keys() {
+        let (joiner, member) = (user("joiner"), user("member"));
+        let (joined, welcomed) = handshake(&joiner, "passphrase", &member, "passphrase").unwrap();
+        assert_eq!(
+            welcomed.open(&joined.seal(b"to member").unwrap()).unwrap(),
+            b"to member"
+        );
+        assert_eq!(
+            joined.open(&welcomed.seal(b"to joiner").unwrap()).unwrap(),
+            b"to joiner"
+        );
+    }
+
+    #[test]
+    fn wrong_passphrase_learns_nothing() {
+        let (joiner, member) = (user("joiner"), user("member"));
+        assert!(handshake(&joiner, "guess", &member, "passphrase").is_none());
+    }
+
+    #[test]
+    fn a_changed_salt_breaks_the_handshake() {
+        let (hello, _) = RoomPacket::hello(&derive_room_key("passphrase")).unwrap();
+        let RoomPacket::Hello { mut salt, noise } = hello else {
+            unreachable!()
+        };
+        assert!(RoomPacket::reply(&salt, &noise).is_some());
+        salt[0] ^= 1;
+        assert!(RoomPacket::reply(&salt, &noise).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pending_handshakes_are_capped_and_expire() {
+        let handshakes = Handshakes::default();
+        let state = || RoomHandshake::Reply {
+            noise: noise(&[0; 16], None, false).unwrap(),
+        };
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        for port in 0..MAX_PENDING as u16 {
+            assert!(handshakes.insert(addr(port), state()));
+        }
+        assert!(!handshakes.insert(addr(9999), state()));
+        // A new hello from a waiting address replaces its handshake
+        assert!(handshakes.insert(addr(0), state()));
+        tokio::time::advance(PENDING_TIMEOUT).await;
+        assert!(handshakes.take(addr(1)).is_none());
+        assert!(handshakes.insert(addr(9999), state()));
+    }
+
+    #[test]
+    fn joins_wait_for_a_free_stretch() {
+        let handshakes = Handshakes::default();
+        let permits: Vec<_> = (0..MAX_STRETCHES)
+            .map(|_| handshakes.stretch().unwrap())
+            .collect();
+        assert!(handshakes.stretch().is_none());
+        drop(permits);
+        assert!(handshakes.stretch().is_some());
+    }
+}
@@ -0,0 +1,248 @@
+use snow::StatelessTransportState;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+};
+
+use super::Packet;
+
+/// First bytes of a sealed datagram, distinct from a chunk frame's and
+/// far past the last variant tag of a bincode `Packet`.
+pub const MAGIC: [u8; 4] = *b"CPSE";
+/// Magic and the nonce the datagram was sealed with.
+const HEADER_LEN: usize = 4 + 8;
+const TAG_LEN: usize = 16;
+/// Nonces this far behind the newest one are refused, later ones are
+/// accepted once each so reordered datagrams still get through.
+const WINDOW: u64 = 128;
+
+/// Sessions keyed by the peer they encrypt the traffic with. Kept outside
+/// `User` because every send path has to reach them, most without the user.
+static SESSIONS: LazyLock<Mutex<HashMap<SocketAddr, Arc<Session>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Keys from a room handshake, used for everything sent to and received
+/// from the peer after it.
+#[derive(Debug)]
+pub struct Session {
+    transport: StatelessTransportState,
+    next: AtomicU64,
+    window: Mutex<Window>,
+}
+
+/// Nonces already received, bit `i` of `seen` is `highest - i`.
+#[derive(Default, Debug)]
+struct Window {
+    highest: u64,
+    seen: u128,
+}
+
+impl Window {
+    fn accept(&mut self, nonce: u64) -> bool {
+        if nonce > self.highest || self.seen == 0 {
+            let shift = nonce.saturating_sub(self.highest);
+            self.seen = if shift >= WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = nonce.max(self.highest);
+            return true;
+        }
+        let back = self.highest - nonce;
+        if back >= WINDOW || self.seen & (1 << back) != 0 {
+            return false;
+        }
+        self.seen |= 1 << back;
+        true
+    }
+}
+
+impl Session {
+    pub fn new(transport: StatelessTransportState) -> Self {
+        Session {
+            transport,
+            next: AtomicU64::new(0),
+            window: Mutex::new(Window::default()),
+        }
+    }
+
+    /// Encrypts `plain` under the next nonce into a sealed datagram.
+    pub fn seal(&self, plain: &[u8]) -> io::Result<Vec<u8>> {
+        let mut sealed = Vec::new();
+        self.seal_into(plain, &mut sealed)?;
+        Ok(sealed)
+    }
+
+    /// Like `seal`, into a buffer that is reused.
+    fn seal_into(&self, plain: &[u8], sealed: &mut Vec<u8>) -> io::Result<()> {
+        let nonce = self.next.fetch_add(1, Ordering::Relaxed);
+        sealed.clear();
+        sealed.resize(HEADER_LEN + plain.len() + TAG_LEN, 0);
+        sealed[..4].copy_from_slice(&MAGIC);
+        sealed[4..HEADER_LEN].copy_from_slice(&nonce.to_le_bytes());
+        self.transport
+            .write_message(nonce, plain, &mut sealed[HEADER_LEN..])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(())
+    }
+
+    /// Decrypts a sealed datagram, `None` if it was not sealed by the peer
+    /// or was already received.
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < HEADER_LEN + TAG_LEN || sealed[..4] != MAGIC {
+            return None;
+        }
+        let nonce = u64::from_le_bytes(sealed[4..HEADER_LEN].try_into().ok()?);
+        let mut plain = vec![0; sealed.len() - HEADER_LEN - TAG_LEN];
+        self.transport
+            .read_message(nonce, &sealed[HEADER_LEN..], &mut plain)
+            .ok()?;
+        // Only after it authenticated, forged nonces don't move the window
+        let fresh = self
+            .window
+            .lock()
+            .expect("window lock poisoned")
+            .accept(nonce);
+        fresh.then_some(plain)
+    }
+}
+
+fn sessions() -> std::sync::MutexGuard<'static, HashMap<SocketAddr, Arc<Session>>> {
+    SESSIONS.lock().expect("sessions lock poisoned")
+}
+
+/// Encrypts all further traffic with `addr` under `session`.
+pub fn install(addr: SocketAddr, session: Session) {
+    sessions().insert(addr, Arc::new(session));
+}
+
+pub fn remove(addr: SocketAddr) {
+    sessions().remove(&addr);
+}
+
+fn get(addr: SocketAddr) -> Option<Arc<Session>> {
+    sessions().get(&addr).cloned()
+}
+
+/// Seals a datagram in place if `addr` joined through a room.
+pub fn seal(addr: SocketAddr, data: &mut Vec<u8>) -> io::Result<()> {
+    if let Some(session) = get(addr) {
+        *data = session.seal(data)?;
+    }
+    Ok(())
+}
+
+/// Seals every datagram of a batch if `addr` joined through a room. Each
+/// is sealed into `scratch` and swapped with it, so the buffers go round
+/// without allocating once they have grown.
+pub fn seal_all(addr: SocketAddr, bufs: &mut [Vec<u8>], scratch: &mut Vec<u8>) -> io::Result<()> {
+    let Some(session) = get(addr) else {
+        return Ok(());
+    };
+    for buf in bufs {
+        session.seal_into(buf, scratch)?;
+        std::mem::swap(buf, scratch);
+    }
+    Ok(())
+}
+
+/// The datagram to handle, `None` when it is dropped. A peer with a
+/// session sends nothing in the clear except a new room handshake, and
+/// sealed datagrams from anyone else are not ours to open.
+pub fn open(addr: SocketAddr, bytes: &[u8]) -> Option<Cow<'_, [u8]>> {
+    let session = get(addr);
+    if bytes.starts_with(&MAGIC) {
+        return session?.open(bytes).map(Cow::Owned);
+    }
+    if session.is_some() && !matches!(Packet::deserialize(bytes), Some(Packet::Room(_))) {
+        return None;
+    }
+    Some(Cow::Borrowed(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+
+    fn pair() -> (Session, Session) {
+        let psk = [7; 32];
+        let builder = || snow::Builder::new(PARAMS.parse().unwrap()).psk(0, &psk);
+        let mut initiator = builder().build_initiator().unwrap();
+        let mut responder = builder().build_responder().unwrap();
+        let (mut msg, mut payload) = (vec![0; 256], vec![0; 256]);
+        let len = initiator.write_message(&[], &mut msg).unwrap();
+        responder.read_message(&msg[..len], &mut payload).unwrap();
+        let len = responder.write_message(&[], &mut msg).unwrap();
+        initiator.read_message(&msg[..len], &mut payload).unwrap();
+        (
+            Session::new(initiator.into_stateless_transport_mode().unwrap()),
+            Session::new(responder.into_stateless_transport_mode().unwrap()),
+        )
+    }
+
+    #[test]
+    fn sealed_datagrams_open_once() {
+        let (a, b) = pair();
+        let first = a.seal(b"first").unwrap();
+        let second = a.seal(b"second").unwrap();
+        assert!(first.starts_with(&MAGIC));
+        assert_eq!(b.open(&second).unwrap(), b"second");
+        // Reordered still opens, a replay does not
+        assert_eq!(b.open(&first).unwrap(), b"first");
+        assert!(b.open(&first).is_none());
+        // Nor does it open for the side that sealed it
+        assert!(a.open(&a.seal(b"own").unwrap()).is_none());
+    }
+
+    #[test]
+    fn tampered_datagrams_are_dropped() {
+        let (a, b) = pair();
+        let mut sealed = a.seal(b"payload").unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(b.open(&sealed).is_none());
+        // Moving it to another nonce breaks it too
+        let mut moved = a.seal(b"payload").unwrap();
+        moved[4] ^= 1;
+        assert!(b.open(&moved).is_none());
+    }
+
+    #[test]
+    fn batches_are_sealed_for_room_peers_only() {
+        let (a, b) = pair();
+        let (peer, other) = (
+            SocketAddr::from(([127, 0, 0, 1], 9)),
+            SocketAddr::from(([127, 0, 0, 1], 10)),
+        );
+        install(peer, a);
+        let mut scratch = Vec::new();
+        let mut bufs = vec![b"one".to_vec(), b"two".to_vec()];
+        seal_all(other, &mut bufs, &mut scratch).unwrap();
+        assert_eq!(bufs, [b"one".to_vec(), b"two".to_vec()]);
+        seal_all(peer, &mut bufs, &mut scratch).unwrap();
+        let opened: Vec<Vec<u8>> = bufs.iter().map(|buf| b.open(buf).unwrap()).collect();
+        assert_eq!(opened, [b"one".to_vec(), b"two".to_vec()]);
+        remove(peer);
+    }
+
+    #[test]
+    fn window_refuses_old_nonces() {
+        let mut window = Window::default();
+        assert!(window.accept(0));
+        assert!(!window.accept(0));
+        assert!(window.accept(WINDOW + 5));
+        assert!(!window.accept(4));
+        assert!(window.accept(6));
+        assert!(!window.accept(6));
+    }
+}
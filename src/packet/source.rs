@@ -0,0 +1,162 @@
+use std::{
+    collections::VecDeque,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+/// Content of a file offer. A directory is streamed as its serialized
+/// manifest followed by every regular file in manifest order.
+#[derive(Debug, Clone)]
+pub enum Source {
+    File(PathBuf),
+    Dir {
+        manifest: Arc<Vec<u8>>,
+        files: Arc<Vec<(PathBuf, u64)>>,
+    },
+    /// Generated content, the delta of an updated file.
+    Memory(Arc<Vec<u8>>),
+}
+
+impl Source {
+    pub async fn open(&self) -> io::Result<ContentReader> {
+        let reader = match self {
+            Source::File(path) => ContentReader {
+                prefix: Arc::new(Vec::new()),
+                pos: 0,
+                files: VecDeque::new(),
+                current: Some(File::open(path).await?),
+            },
+            Source::Dir { manifest, files } => ContentReader {
+                prefix: manifest.clone(),
+                pos: 0,
+                files: files.iter().map(|(path, _)| path.clone()).collect(),
+                current: None,
+            },
+            Source::Memory(data) => ContentReader {
+                prefix: data.clone(),
+                pos: 0,
+                files: VecDeque::new(),
+                current: None,
+            },
+        };
+        Ok(reader)
+    }
+
+    /// Fills `buf` from `offset` of the content, used to answer chunk
+    /// requests in any order. Returns less than `buf.len()` only at the end.
+    pub async fn read_at(&self, mut offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::File(path) => read_file_at(path, offset, buf).await,
+            Source::Memory(data) => {
+                let start = (offset as usize).min(data.len());
+                let n = buf.len().min(data.len() - start);
+                buf[..n].copy_from_slice(&data[start..start + n]);
+                Ok(n)
+            }
+            Source::Dir { manifest, files } => {
+                let mut filled = 0;
+                let prefix = manifest.len() as u64;
+                if offset < prefix {
+                    let n = buf.len().min((prefix - offset) as usize);
+                    let start = offset as usize;
+                    buf[..n].copy_from_slice(&manifest[start..start + n]);
+                    filled = n;
+                    offset = 0;
+                } else {
+                    offset -= prefix;
+                }
+                for (path, size) in files.iter() {
+                    if filled == buf.len() {
+                        break;
+                    }
+                    if offset >= *size {
+                        offset -= size;
+                        continue;
+                    }
+                    let want = (buf.len() - filled).min((size - offset) as usize);
+                    let n = read_file_at(path, offset, &mut buf[filled..filled + want]).await?;
+                    if n < want {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("{} changed while sharing", path.display()),
+                        ));
+                    }
+                    filled += n;
+                    offset = 0;
+                }
+                Ok(filled)
+            }
+        }
+    }
+}
+
+async fn read_file_at(path: &Path, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Sequential reader over the bytes of a `Source`.
+pub struct ContentReader {
+    prefix: Arc<Vec<u8>>,
+    pos: usize,
+    files: VecDeque<PathBuf>,
+    current: Option<File>,
+}
+
+impl ContentReader {
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.prefix.len() {
+            let n = buf.len().min(self.prefix.len() - self.pos);
+            buf[..n].copy_from_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        loop {
+            if let Some(file) = self.current.as_mut() {
+                let n = file.read(buf).await?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+            }
+            match self.files.pop_front() {
+                Some(path) => self.current = Some(File::open(path).await?),
+                None => return Ok(0),
+            }
+        }
+    }
+
+    /// Moves `n` bytes ahead in the file being read without reading them.
+    pub async fn skip(&mut self, n: u64) -> io::Result<()> {
+        match self.current.as_mut() {
+            Some(file) => file.seek(SeekFrom::Current(n as i64)).await.map(|_| ()),
+            None => Err(io::Error::new(io::ErrorKind::Unsupported, "no file to skip in")),
+        }
+    }
+
+    /// Reads until `buf` is full or the content ends.
+    pub async fn read_full(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = self.read(&mut buf[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        Ok(filled)
+    }
+}
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+use std::{io, ops::RangeInclusive, path::Path};
+use tokio::fs::File;
+
+use super::file::CHUNK_SIZE;
+use super::tree::{self, ChainingValue};
+
+/// Stands in for the chunks of a subtree holding only zeros, so holes and
+/// zeroed regions are not sent. `offset` and `len` are in bytes, `proof`
+/// verifies the zeros against the root hash like a chunk's proof.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ZeroPacket {
+    pub id: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub proof: Vec<ChainingValue>,
+}
+
+impl ZeroPacket {
+    pub fn verify(&self, root: &[u8; 32], size: u64) -> bool {
+        self.len > 0
+            && self.offset.is_multiple_of(CHUNK_SIZE as u64)
+            && tree::verify_zeros(root, size, self.offset, self.len, &self.proof)
+    }
+
+    /// 1-based chunk indices it covers.
+    pub fn chunks(&self) -> RangeInclusive<usize> {
+        let first = (self.offset / CHUNK_SIZE as u64) as usize + 1;
+        first..=first + (self.len.div_ceil(CHUNK_SIZE as u64) as usize) - 1
+    }
+}
+
+pub fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+/// Byte ranges of the holes in a sparse file, found with `SEEK_HOLE` and
+/// `SEEK_DATA`. Empty when the filesystem doesn't report holes.
+#[cfg(target_os = "linux")]
+pub async fn holes(path: &Path) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::fd::AsRawFd;
+
+    let file = File::open(path).await?;
+    let size = file.metadata().await?.len();
+    let fd = file.as_raw_fd();
+    let mut holes = Vec::new();
+    let mut pos = 0;
+    while pos < size {
+        let hole = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Ok(Vec::new());
+        }
+        let hole = hole as u64;
+        if hole >= size {
+            break;
+        }
+        let data = unsafe { libc::lseek(fd, hole as libc::off_t, libc::SEEK_DATA) };
+        let end = if data >= 0 {
+            data as u64
+        } else if io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+            // No data after the hole
+            size
+        } else {
+            return Ok(Vec::new());
+        };
+        holes.push((hole, end));
+        pos = end;
+    }
+    Ok(holes)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn holes(_path: &Path) -> io::Result<Vec<(u64, u64)>> {
+    Ok(Vec::new())
+}
+
+/// Frees the blocks of a zero range in a file being received, so it ends up
+/// as a hole like on the sender. The file size stays the same.
+#[cfg(target_os = "linux")]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    let res = unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Without hole punching the range is left as it is, a new file reads as
+/// zeros wherever nothing was written.
+#[cfg(not(target_os = "linux"))]
+pub fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
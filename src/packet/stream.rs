@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+/// Offer of a stream read from the sender's stdin. Unlike a file offer its
+/// size and hash are only known once the input ends, see `StreamEnd`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StreamOffer {
+    pub id: u64,
+    pub sender: String,
+}
+
+/// Asks for `chunks` of a stream, 1-based. Everything before `from` is
+/// written on the receiving side, the sender may forget it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StreamRequest {
+    pub id: u64,
+    pub from: usize,
+    pub chunks: Vec<usize>,
+}
+
+/// Answer to a request past the last chunk, the input ended after `chunks`
+/// chunks holding `size` bytes with BLAKE3 `hash`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StreamEnd {
+    pub id: u64,
+    pub chunks: usize,
+    pub size: u64,
+    pub hash: [u8; 32],
+}
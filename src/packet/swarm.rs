@@ -0,0 +1,567 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use super::compress::Compression;
+use super::fec;
+
+/// How long a chunk request may stay unanswered before it goes to another peer.
+pub const CHUNK_TIMEOUT: Duration = Duration::from_secs(3);
+/// Chunks a receiver asks for ahead of the next one it can write.
+const WINDOW: usize = 256;
+/// Timeouts in a row after which a peer is no longer asked.
+const MAX_STALLS: u32 = 3;
+/// Requests a peer has outstanding before its rate is measured, and at least.
+const MIN_INFLIGHT: usize = 2;
+/// Most requests outstanding with one peer.
+const MAX_INFLIGHT: usize = 64;
+
+/// Asks connected peers whether they have the content with root `hash`.
+/// `id` is the id of the offer being received.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WantPacket {
+    pub id: u64,
+    pub hash: [u8; 32],
+    pub size: u64,
+    /// Compression the receiver takes, helpers use it when the content compresses.
+    pub compression: Option<Compression>,
+}
+
+/// Answer to a `WantPacket` from a peer that will serve chunk requests for `id`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HavePacket {
+    pub id: u64,
+    pub hash: [u8; 32],
+}
+
+/// What a peer was asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Chunk(usize),
+    /// Every chunk of a group and its repair symbols, see `fec.rs`.
+    Group(usize),
+}
+
+/// A received chunk waiting to be written.
+pub enum Piece {
+    Data(Vec<u8>),
+    /// Part of a verified zero range, see `sparse.rs`.
+    Zeros,
+}
+
+struct Inflight {
+    request: Request,
+    sent: Instant,
+    /// Something arrived for it, a group that got partly through.
+    heard: bool,
+}
+
+struct Peer {
+    addr: SocketAddr,
+    inflight: Vec<Inflight>,
+    stalls: u32,
+    /// Shortest round trip seen, the latency of the path without queueing.
+    min_rtt: Option<Duration>,
+    /// Smoothed time between two answers, how fast the peer gets them to us.
+    gap: Option<Duration>,
+    last_answer: Option<Instant>,
+}
+
+impl Peer {
+    fn new(addr: SocketAddr) -> Self {
+        Peer {
+            addr,
+            inflight: Vec::new(),
+            stalls: 0,
+            min_rtt: None,
+            gap: None,
+            last_answer: None,
+        }
+    }
+
+    /// Requests kept outstanding, twice the answers that fit in a round
+    /// trip at the measured rate. The path stays full, and the window keeps
+    /// growing while the rate does.
+    fn window(&self) -> usize {
+        let (Some(rtt), Some(gap)) = (self.min_rtt, self.gap) else {
+            return MIN_INFLIGHT;
+        };
+        if gap.is_zero() {
+            return MAX_INFLIGHT;
+        }
+        let answers = 2.0 * rtt.as_secs_f64() / gap.as_secs_f64();
+        (answers.ceil() as usize).clamp(MIN_INFLIGHT, MAX_INFLIGHT)
+    }
+
+    fn answered(&mut self, pos: usize, now: Instant) {
+        let rtt = now - self.inflight.remove(pos).sent;
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        if let Some(last) = self.last_answer {
+            let sample = now - last;
+            self.gap = Some(self.gap.map_or(sample, |gap| gap * 7 / 8 + sample / 8));
+        }
+        self.last_answer = Some(now);
+        self.stalls = 0;
+    }
+
+    /// Something was lost, the rate is taken to be half of what it was.
+    fn slow_down(&mut self) {
+        if let Some(gap) = self.gap.as_mut() {
+            *gap *= 2;
+        }
+    }
+
+    fn take(&mut self, request: Request) -> bool {
+        match self.inflight.iter().position(|entry| entry.request == request) {
+            Some(pos) => {
+                self.inflight.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Receiver side scheduler spreading chunk requests over every peer that
+/// has the content. Each peer has as many requests outstanding as its
+/// measured rate and round trip call for. Chunks may arrive in any order,
+/// they are handed out for writing in order, or as they come to writers
+/// that place them at their offset.
+pub struct Swarm {
+    total: usize,
+    /// Requests whole FEC groups instead of single chunks.
+    groups: bool,
+    next_write: usize,
+    next_request: usize,
+    /// Chunks to ask for again, with the peer that failed to send them.
+    retry: BTreeMap<usize, Option<SocketAddr>>,
+    ready: BTreeMap<usize, Piece>,
+    /// Chunks past `next_write` already written at their offset.
+    written: BTreeSet<usize>,
+    peers: Vec<Peer>,
+}
+
+impl Swarm {
+    pub fn new(total_chunks: usize, first: SocketAddr, groups: bool) -> Self {
+        let mut swarm = Swarm {
+            total: total_chunks,
+            groups,
+            next_write: 1,
+            next_request: 1,
+            retry: BTreeMap::new(),
+            ready: BTreeMap::new(),
+            written: BTreeSet::new(),
+            peers: Vec::new(),
+        };
+        swarm.add_peer(first);
+        swarm
+    }
+
+    pub fn add_peer(&mut self, addr: SocketAddr) -> bool {
+        if self.has_peer(addr) {
+            return false;
+        }
+        self.peers.push(Peer::new(addr));
+        true
+    }
+
+    pub fn remove_peer(&mut self, addr: SocketAddr) {
+        if let Some(pos) = self.peers.iter().position(|p| p.addr == addr) {
+            for entry in self.peers.remove(pos).inflight {
+                self.requeue(entry.request, None);
+            }
+        }
+    }
+
+    /// Chunks of a request that are neither written nor waiting to be.
+    fn missing(&self, request: Request) -> Vec<usize> {
+        let range = match request {
+            Request::Chunk(index) => index..=index,
+            Request::Group(group) => fec::group_chunks(group, self.total),
+        };
+        range
+            .filter(|i| *i >= self.next_write && !self.ready.contains_key(i) && !self.written.contains(i))
+            .collect()
+    }
+
+    /// Asks for the missing chunks of a request again, from another peer
+    /// than `failed` if there is one.
+    fn requeue(&mut self, request: Request, failed: Option<SocketAddr>) {
+        for index in self.missing(request) {
+            self.retry.insert(index, failed);
+        }
+    }
+
+    pub fn has_peer(&self, addr: SocketAddr) -> bool {
+        self.peers.iter().any(|p| p.addr == addr)
+    }
+
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.peers.iter().map(|p| p.addr).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn done(&self) -> bool {
+        self.next_write > self.total
+    }
+
+    /// Chunk index the writer waits for.
+    pub fn next_write(&self) -> usize {
+        self.next_write
+    }
+
+    /// The next request for `addr`. Chunks that went missing come first and
+    /// are always asked for alone.
+    fn next_request(&mut self, addr: SocketAddr) -> Option<Request> {
+        let alone = self.peers.len() == 1;
+        let retry = self
+            .retry
+            .iter()
+            .find(|(_, failed)| alone || **failed != Some(addr))
+            .map(|(index, _)| *index);
+        if let Some(index) = retry {
+            self.retry.remove(&index);
+            return Some(Request::Chunk(index));
+        }
+        // Zero ranges may have covered chunks ahead
+        while self.ready.contains_key(&self.next_request) || self.written.contains(&self.next_request) {
+            self.next_request += 1;
+        }
+        if self.next_request > self.total || self.next_request >= self.next_write + WINDOW {
+            return None;
+        }
+        // Groups start aligned, a lone chunk first fills up to the boundary
+        if self.groups && (self.next_request - 1).is_multiple_of(fec::GROUP) {
+            let group = (self.next_request - 1) / fec::GROUP;
+            self.next_request = *fec::group_chunks(group, self.total).end() + 1;
+            Some(Request::Group(group))
+        } else {
+            self.next_request += 1;
+            Some(Request::Chunk(self.next_request - 1))
+        }
+    }
+
+    /// Fills the window of every peer, one request each in turn so the
+    /// chunks spread over the peers. Returns the requests to send.
+    pub fn assign(&mut self) -> Vec<(SocketAddr, Request)> {
+        let mut requests = Vec::new();
+        let now = Instant::now();
+        loop {
+            let mut assigned = false;
+            for i in 0..self.peers.len() {
+                if self.peers[i].inflight.len() >= self.peers[i].window() {
+                    continue;
+                }
+                let addr = self.peers[i].addr;
+                let Some(request) = self.next_request(addr) else {
+                    continue;
+                };
+                self.peers[i].inflight.push(Inflight {
+                    request,
+                    sent: now,
+                    heard: false,
+                });
+                requests.push((addr, request));
+                assigned = true;
+            }
+            if !assigned {
+                return requests;
+            }
+        }
+    }
+
+    /// Takes a verified chunk, false when it was not needed any more.
+    pub fn received(&mut self, addr: SocketAddr, index: usize, piece: Piece) -> bool {
+        // Indices are 1-based, a peer may still send 0
+        if index == 0 {
+            return false;
+        }
+        let needed = index >= self.next_write
+            && index <= self.total
+            && !self.ready.contains_key(&index)
+            && !self.written.contains(&index);
+        if needed {
+            self.retry.remove(&index);
+            self.ready.insert(index, piece);
+        }
+        let Some(pos) = self.peers.iter().position(|p| p.addr == addr) else {
+            return needed;
+        };
+        let group = Request::Group((index - 1) / fec::GROUP);
+        let group_done = self.missing(group).is_empty();
+        let peer = &mut self.peers[pos];
+        let mut answered = None;
+        for (i, entry) in peer.inflight.iter_mut().enumerate() {
+            if entry.request == Request::Chunk(index) || (entry.request == group && group_done) {
+                answered = Some(i);
+                break;
+            }
+            entry.heard |= entry.request == group;
+        }
+        if let Some(i) = answered {
+            peer.answered(i, Instant::now());
+        }
+        needed
+    }
+
+    /// The peer sent everything for its group and some chunks are still
+    /// missing, they are requested one by one right away.
+    pub fn settle(&mut self, addr: SocketAddr, group: usize) {
+        let request = Request::Group(group);
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.addr == addr) {
+            if let Some(pos) = peer.inflight.iter().position(|entry| entry.request == request) {
+                peer.answered(pos, Instant::now());
+                self.requeue(request, None);
+            }
+        }
+    }
+
+    /// A peer sent a chunk that failed verification.
+    pub fn rejected(&mut self, addr: SocketAddr, index: usize) {
+        if index == 0 {
+            return;
+        }
+        let group = (index - 1) / fec::GROUP;
+        let mut failed = Vec::new();
+        if let Some(peer) = self.peers.iter_mut().find(|p| p.addr == addr) {
+            for request in [Request::Chunk(index), Request::Group(group)] {
+                if peer.take(request) {
+                    failed.push(request);
+                }
+            }
+            if !failed.is_empty() {
+                peer.stalls += 1;
+            }
+        }
+        for request in failed {
+            self.requeue(request, Some(addr));
+        }
+        self.drop_stalled();
+    }
+
+    /// Next chunk in write order, if it has arrived.
+    pub fn take_ready(&mut self) -> Option<Piece> {
+        let piece = self.ready.remove(&self.next_write)?;
+        self.next_write += 1;
+        Some(piece)
+    }
+
+    /// Any chunk that has arrived with its index, in whatever order.
+    pub fn take_any(&mut self) -> Option<(usize, Piece)> {
+        let (index, piece) = self.ready.pop_first()?;
+        self.written.insert(index);
+        while self.written.remove(&self.next_write) {
+            self.next_write += 1;
+        }
+        Some((index, piece))
+    }
+
+    /// When the oldest outstanding request times out.
+    pub fn deadline(&self) -> Instant {
+        self.peers
+            .iter()
+            .flat_map(|p| &p.inflight)
+            .map(|entry| entry.sent + CHUNK_TIMEOUT)
+            .min()
+            .unwrap_or_else(|| Instant::now() + CHUNK_TIMEOUT)
+    }
+
+    /// Sends timed out requests to other peers and stops asking peers that
+    /// keep stalling. Timeouts from one loss count as one stall, and a group
+    /// that got partly through doesn't count. Returns the groups that timed out.
+    pub fn expire(&mut self) -> Vec<usize> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut failed = Vec::new();
+        for peer in &mut self.peers {
+            let (late, waiting): (Vec<Inflight>, Vec<Inflight>) = peer
+                .inflight
+                .drain(..)
+                .partition(|entry| entry.sent + CHUNK_TIMEOUT <= now);
+            peer.inflight = waiting;
+            if late.is_empty() {
+                continue;
+            }
+            if late.iter().any(|entry| !entry.heard) {
+                peer.stalls += 1;
+            }
+            peer.slow_down();
+            for entry in late {
+                if let Request::Group(group) = entry.request {
+                    expired.push(group);
+                }
+                failed.push((entry.request, peer.addr));
+            }
+        }
+        for (request, addr) in failed {
+            self.requeue(request, Some(addr));
+        }
+        self.drop_stalled();
+        expired
+    }
+
+    /// Forgets outstanding requests, after a pause every peer is asked again.
+    pub fn reset(&mut self) {
+        let mut requests = Vec::new();
+        for peer in &mut self.peers {
+            requests.extend(peer.inflight.drain(..).map(|entry| entry.request));
+            peer.stalls = 0;
+        }
+        for request in requests {
+            self.requeue(request, None);
+        }
+    }
+
+    fn drop_stalled(&mut self) {
+        let stalled: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|p| p.stalls >= MAX_STALLS)
+            .map(|p| p.addr)
+            .collect();
+        for addr in stalled {
+            self.remove_peer(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:4000".parse().unwrap()
+    }
+
+    fn other() -> SocketAddr {
+        "127.0.0.1:4001".parse().unwrap()
+    }
+
+    fn chunks_of(requests: &[(SocketAddr, Request)], peer: SocketAddr) -> Vec<usize> {
+        requests
+            .iter()
+            .filter(|(to, _)| *to == peer)
+            .map(|(_, request)| match request {
+                Request::Chunk(index) => *index,
+                Request::Group(group) => panic!("group {} asked for", group),
+            })
+            .collect()
+    }
+
+    /// Answers every request of `requests` after `rtt`, `gap` apart.
+    async fn answer(swarm: &mut Swarm, requests: &[(SocketAddr, Request)], rtt: Duration, gap: Duration) {
+        tokio::time::advance(rtt).await;
+        for (from, request) in requests {
+            if let Request::Chunk(index) = request {
+                swarm.received(*from, *index, Piece::Zeros);
+            }
+            tokio::time::advance(gap).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_spread_over_every_peer() {
+        let mut swarm = Swarm::new(100, addr(), false);
+        swarm.add_peer(other());
+        let requests = swarm.assign();
+        let (first, second) = (chunks_of(&requests, addr()), chunks_of(&requests, other()));
+        assert_eq!(first, vec![1, 3]);
+        assert_eq!(second, vec![2, 4]);
+        // Every window is full until something is answered
+        assert!(swarm.assign().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_window_grows_with_the_measured_rate() {
+        let mut swarm = Swarm::new(1000, addr(), false);
+        let rtt = Duration::from_millis(50);
+        let mut requests = swarm.assign();
+        assert_eq!(requests.len(), MIN_INFLIGHT);
+        for _ in 0..4 {
+            answer(&mut swarm, &requests, rtt, Duration::from_millis(1)).await;
+            requests = swarm.assign();
+        }
+        // 50 ms round trips with answers 1 ms apart fill a window of dozens
+        assert!(requests.len() > MAX_INFLIGHT / 2, "window {}", requests.len());
+        assert_eq!(swarm.peers[0].inflight.len(), requests.len());
+
+        // A peer that answers slowly gets fewer requests at once
+        let mut slow = Swarm::new(1000, addr(), false);
+        let mut requests = slow.assign();
+        for _ in 0..4 {
+            answer(&mut slow, &requests, rtt, Duration::from_millis(20)).await;
+            requests = slow.assign();
+        }
+        assert!(slow.peers[0].window() <= 6, "window {}", slow.peers[0].window());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_chunks_go_to_another_peer() {
+        let mut swarm = Swarm::new(100, addr(), false);
+        swarm.add_peer(other());
+        let requests = swarm.assign();
+        // Only the second peer answers
+        tokio::time::advance(Duration::from_millis(10)).await;
+        for index in chunks_of(&requests, other()) {
+            swarm.received(other(), index, Piece::Zeros);
+        }
+        tokio::time::advance(CHUNK_TIMEOUT).await;
+        assert!(swarm.expire().is_empty());
+        assert_eq!(swarm.peers[0].stalls, 1);
+        let requests = swarm.assign();
+        let retried: Vec<usize> = chunks_of(&requests, other()).into_iter().filter(|i| *i <= 4).collect();
+        assert_eq!(retried, vec![1, 3]);
+        assert!(chunks_of(&requests, addr()).iter().all(|i| *i > 4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_removed_peers_chunks_are_asked_again() {
+        let mut swarm = Swarm::new(100, addr(), false);
+        swarm.add_peer(other());
+        swarm.assign();
+        swarm.remove_peer(addr());
+        tokio::time::advance(Duration::from_millis(10)).await;
+        for index in [2, 4] {
+            swarm.received(other(), index, Piece::Zeros);
+        }
+        let requests = swarm.assign();
+        assert_eq!(chunks_of(&requests, other())[..2], [1, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_peer_that_keeps_stalling_is_dropped() {
+        let mut swarm = Swarm::new(100, addr(), false);
+        swarm.add_peer(other());
+        for _ in 0..MAX_STALLS {
+            let requests = swarm.assign();
+            tokio::time::advance(Duration::from_millis(10)).await;
+            for index in chunks_of(&requests, other()) {
+                swarm.received(other(), index, Piece::Zeros);
+            }
+            tokio::time::advance(CHUNK_TIMEOUT).await;
+            swarm.expire();
+        }
+        assert_eq!(swarm.peers(), vec![other()]);
+        // Alone, it gets the retries even of chunks it failed itself
+        swarm.rejected(other(), 5);
+        assert!(swarm.assign().iter().all(|(to, _)| *to == other()));
+    }
+
+    #[test]
+    fn chunk_zero_is_ignored() {
+        for groups in [false, true] {
+            let mut swarm = Swarm::new(40, addr(), groups);
+            swarm.assign();
+            assert!(!swarm.received(addr(), 0, Piece::Zeros));
+            swarm.rejected(addr(), 0);
+            assert_eq!(swarm.next_write(), 1);
+        }
+    }
+}
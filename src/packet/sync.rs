@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// Entries per manifest packet, keeps one page well inside a datagram.
+pub const MANIFEST_PAGE: usize = 50;
+
+/// How the edits behind two versions of a file relate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// Every edit the other side saw is included, and more.
+    Newer,
+    Older,
+    /// Both sides changed the file without seeing the other's edit.
+    Concurrent,
+}
+
+/// Edit counter per identity fingerprint.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    /// Counters jump to the current time so a peer that lost its sync state
+    /// never reuses a counter it already sent.
+    pub fn bump(&mut self, who: &str) {
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        let counter = self.0.entry(who.to_string()).or_default();
+        *counter = (*counter + 1).max(now);
+    }
+
+    pub fn merge(&self, other: &VersionVector) -> VersionVector {
+        let mut merged = self.clone();
+        for (who, n) in other.0.iter() {
+            let entry = merged.0.entry(who.clone()).or_default();
+            *entry = (*entry).max(*n);
+        }
+        merged
+    }
+
+    pub fn compare(&self, other: &VersionVector) -> Causality {
+        let mut result = Ordering::Equal;
+        for who in self.0.keys().chain(other.0.keys()) {
+            let mine = self.0.get(who).copied().unwrap_or(0);
+            let theirs = other.0.get(who).copied().unwrap_or(0);
+            match (result, mine.cmp(&theirs)) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, o) => result = o,
+                (r, o) if r != o => return Causality::Concurrent,
+                _ => {}
+            }
+        }
+        match result {
+            Ordering::Equal => Causality::Equal,
+            Ordering::Greater => Causality::Newer,
+            Ordering::Less => Causality::Older,
+        }
+    }
+}
+
+/// State of one file of a synced folder. Deleted files stay as tombstones
+/// so the deletion reaches every peer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncEntry {
+    /// Relative to the folder root, `/` separated.
+    pub path: String,
+    pub hash: [u8; 32],
+    pub size: u64,
+    pub modified: Option<i64>,
+    pub deleted: bool,
+    pub version: VersionVector,
+    /// Fingerprint of whoever made the last edit.
+    pub by: String,
+}
+
+impl SyncEntry {
+    /// Same content, or both deleted.
+    pub fn same_content(&self, other: &SyncEntry) -> bool {
+        if self.deleted || other.deleted {
+            return self.deleted && other.deleted;
+        }
+        self.hash == other.hash
+    }
+
+    /// Picks the same winner on every peer when two edits conflict.
+    pub fn wins_over(&self, other: &SyncEntry) -> bool {
+        (self.modified, &self.by, self.hash) > (other.modified, &other.by, other.hash)
+    }
+}
+
+/// One page of a peer's view of the folder named `folder`. Pages are
+/// independent, each entry is applied on its own.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncPacket {
+    pub folder: String,
+    pub entries: Vec<SyncEntry>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vv(counters: &[(&str, u64)]) -> VersionVector {
+        VersionVector(counters.iter().map(|(who, n)| (who.to_string(), *n)).collect())
+    }
+
+    fn entry(by: &str, modified: i64, hash: u8) -> SyncEntry {
+        SyncEntry {
+            path: "a.txt".to_string(),
+            hash: [hash; 32],
+            size: 1,
+            modified: Some(modified),
+            deleted: false,
+            version: VersionVector::default(),
+            by: by.to_string(),
+        }
+    }
+
+    #[test]
+    fn versions_compare_by_every_counter() {
+        let cases = [
+            (vv(&[]), vv(&[]), Causality::Equal),
+            (vv(&[("a", 1)]), vv(&[("a", 1)]), Causality::Equal),
+            (vv(&[("a", 2)]), vv(&[("a", 1)]), Causality::Newer),
+            (vv(&[("a", 1), ("b", 1)]), vv(&[("a", 1)]), Causality::Newer),
+            (vv(&[("a", 1)]), vv(&[("a", 2)]), Causality::Older),
+            (vv(&[]), vv(&[("b", 1)]), Causality::Older),
+            (vv(&[("a", 2), ("b", 1)]), vv(&[("a", 1), ("b", 2)]), Causality::Concurrent),
+            (vv(&[("a", 1)]), vv(&[("b", 1)]), Causality::Concurrent),
+        ];
+        for (mine, theirs, expected) in cases {
+            assert_eq!(mine.compare(&theirs), expected, "{:?} vs {:?}", mine, theirs);
+            let mirrored = match expected {
+                Causality::Newer => Causality::Older,
+                Causality::Older => Causality::Newer,
+                other => other,
+            };
+            assert_eq!(theirs.compare(&mine), mirrored, "{:?} vs {:?}", theirs, mine);
+        }
+    }
+
+    #[test]
+    fn merged_versions_dominate_both() {
+        let (a, b) = (vv(&[("a", 2), ("b", 1)]), vv(&[("a", 1), ("b", 3)]));
+        let merged = a.merge(&b);
+        assert_eq!(merged, vv(&[("a", 2), ("b", 3)]));
+        assert_eq!(merged.compare(&a), Causality::Newer);
+        assert_eq!(merged.compare(&b), Causality::Newer);
+        let mut bumped = merged.clone();
+        bumped.bump("a");
+        assert_eq!(bumped.compare(&merged), Causality::Newer);
+    }
+
+    #[test]
+    fn conflicts_pick_one_winner() {
+        let cases = [
+            // Later edit, then fingerprint, then content break the tie
+            (entry("a", 2, 1), entry("b", 1, 1)),
+            (entry("b", 1, 1), entry("a", 1, 1)),
+            (entry("a", 1, 2), entry("a", 1, 1)),
+        ];
+        for (winner, loser) in cases {
+            assert!(winner.wins_over(&loser));
+            assert!(!loser.wins_over(&winner));
+        }
+        let same = entry("a", 1, 1);
+        assert!(!same.wins_over(&same.clone()));
+    }
+}
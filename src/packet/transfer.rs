@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, io};
+
+use super::receipt::Receipt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    Queued,
+    Running,
+    Paused,
+    Cancelled,
+}
+
+impl fmt::Display for TransferState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            TransferState::Queued => "queued",
+            TransferState::Running => "running",
+            TransferState::Paused => "paused",
+            TransferState::Cancelled => "cancelled",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+/// Tells the other side of a transfer that it was queued, paused, resumed
+/// or cancelled. `id` is the one carried by the file offer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferPacket {
+    pub id: u64,
+    pub state: TransferState,
+}
+
+impl TransferPacket {
+    pub fn new(id: u64, state: TransferState) -> Self {
+        TransferPacket { id, state }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The offered name can't be used on the receiving side.
+    InvalidName,
+    /// Reading or writing the content failed.
+    Io,
+    /// The content did not match the offered hash.
+    Verify,
+    /// The other side stopped answering.
+    Timeout,
+    /// The requested path is not shared with the asking peer.
+    Denied,
+    /// The receiver has no room for the content.
+    NoSpace,
+}
+
+impl ErrorCode {
+    /// `NoSpace` for a full disk or quota, `Io` for any other failure.
+    pub fn from_io(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => ErrorCode::NoSpace,
+            _ => ErrorCode::Io,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self {
+            ErrorCode::InvalidName => "invalid name",
+            ErrorCode::Io => "io error",
+            ErrorCode::Verify => "verification failed",
+            ErrorCode::Timeout => "timed out",
+            ErrorCode::Denied => "access denied",
+            ErrorCode::NoSpace => "disk full",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+/// The receiver declined the offer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RejectPacket {
+    pub id: u64,
+}
+
+/// Either side gave up on the transfer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorPacket {
+    pub id: u64,
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+/// The receiver verified the whole content and saved it, `hash` is what it computed.
+/// The original sender of a file also gets a signed receipt.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompletePacket {
+    pub id: u64,
+    pub hash: [u8; 32],
+    pub receipt: Option<Receipt>,
+}
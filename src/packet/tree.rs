@@ -0,0 +1,379 @@
+use blake3::hazmat::{
+    left_subtree_len, merge_subtrees_non_root, merge_subtrees_root, HasherExt, Mode,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
+
+use super::file::CHUNK_SIZE;
+use super::source::Source;
+use super::sparse;
+
+pub use blake3::hazmat::ChainingValue;
+
+/// BLAKE3 tree of a file with one leaf per transfer chunk. The root is the
+/// plain BLAKE3 hash of the contents (what `b3sum` prints), and every chunk
+/// can be checked against it with the sibling chaining values on its path,
+/// the same idea as a Bao outboard tree at chunk granularity. Subtrees
+/// holding only zeros are remembered, they are sent as zero ranges.
+#[derive(Debug)]
+pub struct HashTree {
+    size: u64,
+    root: [u8; 32],
+    nodes: HashMap<(u64, u64), ChainingValue>,
+    zeros: HashSet<(u64, u64)>,
+}
+
+impl HashTree {
+    pub async fn from_source(source: &Source) -> io::Result<Self> {
+        let mut reader = source.open().await?;
+        // Chunks inside a hole are not read, they are zeros
+        let holes = match source {
+            Source::File(path) => sparse::holes(path).await?,
+            _ => Vec::new(),
+        };
+        let mut holes = holes.into_iter().peekable();
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut first = Vec::new();
+        let mut leaves = Vec::new();
+        let mut size = 0;
+        loop {
+            while holes.next_if(|(_, end)| *end < size + CHUNK_SIZE as u64).is_some() {}
+            let n = match holes.peek() {
+                Some((start, _)) if *start <= size => {
+                    buf.fill(0);
+                    reader.skip(CHUNK_SIZE as u64).await?;
+                    CHUNK_SIZE
+                }
+                _ => reader.read_full(&mut buf).await?,
+            };
+            if n == 0 {
+                break;
+            }
+            if size == 0 {
+                first = buf[..n].to_vec();
+            }
+            let mut hasher = blake3::Hasher::new();
+            hasher.set_input_offset(size);
+            hasher.update(&buf[..n]);
+            leaves.push((hasher.finalize_non_root(), sparse::is_zero(&buf[..n])));
+            size += n as u64;
+        }
+
+        // A single chunk is its own root and has no chaining value above it
+        let mut tree = HashTree {
+            size,
+            root: *blake3::hash(&first).as_bytes(),
+            nodes: HashMap::new(),
+            zeros: HashSet::new(),
+        };
+        if size > CHUNK_SIZE as u64 {
+            let left = left_subtree_len(size);
+            let (l, l_zero) = tree.build(&leaves, 0, left);
+            let (r, r_zero) = tree.build(&leaves, left, size - left);
+            tree.root = *merge_subtrees_root(&l, &r, Mode::Hash).as_bytes();
+            if l_zero && r_zero {
+                tree.zeros.insert((0, size));
+            }
+        } else if leaves.first().is_some_and(|(_, zero)| *zero) {
+            tree.zeros.insert((0, size));
+        }
+        Ok(tree)
+    }
+
+    fn build(&mut self, leaves: &[(ChainingValue, bool)], offset: u64, len: u64) -> (ChainingValue, bool) {
+        let (cv, zero) = if len <= CHUNK_SIZE as u64 {
+            leaves[(offset / CHUNK_SIZE as u64) as usize]
+        } else {
+            let left = left_subtree_len(len);
+            let (l, l_zero) = self.build(leaves, offset, left);
+            let (r, r_zero) = self.build(leaves, offset + left, len - left);
+            (merge_subtrees_non_root(&l, &r, Mode::Hash), l_zero && r_zero)
+        };
+        self.nodes.insert((offset, len), cv);
+        if zero {
+            self.zeros.insert((offset, len));
+        }
+        (cv, zero)
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.root
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn total_chunks(&self) -> usize {
+        self.size.div_ceil(CHUNK_SIZE as u64) as usize
+    }
+
+    /// Sibling chaining values from the root down to the chunk at `index`.
+    pub fn proof(&self, index: usize) -> Vec<ChainingValue> {
+        let target = index as u64 * CHUNK_SIZE as u64;
+        self.subtree_proof(target, (self.size - target).min(CHUNK_SIZE as u64))
+    }
+
+    /// The largest subtree of only zeros holding the chunk at `index`, as
+    /// offset and length. `None` when the chunk has data.
+    pub fn zero_range(&self, index: usize) -> Option<(u64, u64)> {
+        let target = index as u64 * CHUNK_SIZE as u64;
+        let (mut o, mut l) = (0, self.size);
+        loop {
+            if self.zeros.contains(&(o, l)) {
+                return Some((o, l));
+            }
+            if l <= CHUNK_SIZE as u64 {
+                return None;
+            }
+            let left = left_subtree_len(l);
+            if target < o + left {
+                l = left;
+            } else {
+                o += left;
+                l -= left;
+            }
+        }
+    }
+
+    /// Sibling chaining values from the root down to the subtree covering
+    /// `len` bytes at `offset`, see `verify_range`.
+    pub fn subtree_proof(&self, offset: u64, len: u64) -> Vec<ChainingValue> {
+        let mut proof = Vec::new();
+        let (mut o, mut l) = (0, self.size);
+        while (o, l) != (offset, len) && l > CHUNK_SIZE as u64 {
+            let left = left_subtree_len(l);
+            if offset < o + left {
+                proof.push(self.nodes[&(o + left, l - left)]);
+                l = left;
+            } else {
+                proof.push(self.nodes[&(o, left)]);
+                o += left;
+                l -= left;
+            }
+        }
+        proof
+    }
+}
+
+/// Checks one chunk of a `size` byte file against the root hash.
+pub fn verify_chunk(
+    root: &[u8; 32],
+    size: u64,
+    index: usize,
+    data: &[u8],
+    proof: &[ChainingValue],
+) -> bool {
+    let target = index as u64 * CHUNK_SIZE as u64;
+    if target >= size.max(1) || data.len() as u64 != (size - target).min(CHUNK_SIZE as u64) {
+        return false;
+    }
+    verify_range(root, size, target, data, proof)
+}
+
+/// Checks `data` at `offset` against the root hash. The range must be a
+/// subtree of the file, a chunk or an aligned run of chunks.
+pub fn verify_range(
+    root: &[u8; 32],
+    size: u64,
+    offset: u64,
+    data: &[u8],
+    proof: &[ChainingValue],
+) -> bool {
+    verify_subtree(root, size, offset, data.len() as u64, proof, |hasher| {
+        hasher.update(data);
+    })
+}
+
+/// Checks that the `len` bytes at `offset` are zeros, like `verify_range`
+/// without having them. The zeros are hashed a chunk at a time.
+pub fn verify_zeros(
+    root: &[u8; 32],
+    size: u64,
+    offset: u64,
+    len: u64,
+    proof: &[ChainingValue],
+) -> bool {
+    let zeros = vec![0; CHUNK_SIZE];
+    verify_subtree(root, size, offset, len, proof, |hasher| {
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(CHUNK_SIZE as u64);
+            hasher.update(&zeros[..n as usize]);
+            left -= n;
+        }
+    })
+}
+
+fn verify_subtree(
+    root: &[u8; 32],
+    size: u64,
+    offset: u64,
+    len: u64,
+    proof: &[ChainingValue],
+    feed: impl FnOnce(&mut blake3::Hasher),
+) -> bool {
+    let mut hasher = blake3::Hasher::new();
+    if offset == 0 && len == size {
+        feed(&mut hasher);
+        return proof.is_empty() && hasher.finalize() == blake3::Hash::from(*root);
+    }
+
+    let mut sides = Vec::with_capacity(proof.len());
+    let (mut o, mut l) = (0, size);
+    while (o, l) != (offset, len) {
+        if l <= CHUNK_SIZE as u64 {
+            return false;
+        }
+        let left = left_subtree_len(l);
+        let is_left = offset < o + left;
+        if is_left {
+            l = left;
+        } else {
+            o += left;
+            l -= left;
+        }
+        sides.push(is_left);
+    }
+    if sides.len() != proof.len() {
+        return false;
+    }
+
+    hasher.set_input_offset(offset);
+    feed(&mut hasher);
+    let mut cv = hasher.finalize_non_root();
+    for (depth, (is_left, sibling)) in sides.iter().zip(proof).enumerate().rev() {
+        let (l, r) = if *is_left { (&cv, sibling) } else { (sibling, &cv) };
+        if depth == 0 {
+            return merge_subtrees_root(l, r, Mode::Hash) == blake3::Hash::from(*root);
+        }
+        cv = merge_subtrees_non_root(l, r, Mode::Hash);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const CHUNK: u64 = CHUNK_SIZE as u64;
+
+    async fn tree_of(data: &[u8]) -> HashTree {
+        let source = Source::Memory(Arc::new(data.to_vec()));
+        HashTree::from_source(&source).await.unwrap()
+    }
+
+    /// Five and a half chunks of bytes that differ from chunk to chunk.
+    fn content() -> Vec<u8> {
+        (0..CHUNK_SIZE * 11 / 2).map(|i| (i * 7 + i / CHUNK_SIZE) as u8).collect()
+    }
+
+    fn chunk(data: &[u8], index: usize) -> &[u8] {
+        &data[index * CHUNK_SIZE..((index + 1) * CHUNK_SIZE).min(data.len())]
+    }
+
+    #[tokio::test]
+    async fn root_is_the_plain_hash() {
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, CHUNK_SIZE * 11 / 2] {
+            let data = &content()[..len];
+            assert_eq!(tree_of(data).await.root(), *blake3::hash(data).as_bytes(), "{} bytes", len);
+        }
+    }
+
+    #[tokio::test]
+    async fn every_chunk_verifies_with_its_proof() {
+        let data = content();
+        let tree = tree_of(&data).await;
+        assert_eq!(tree.total_chunks(), 6);
+        for index in 0..tree.total_chunks() {
+            let proof = tree.proof(index);
+            assert!(verify_chunk(&tree.root(), tree.size(), index, chunk(&data, index), &proof), "chunk {}", index);
+        }
+    }
+
+    #[tokio::test]
+    async fn single_chunk_needs_no_proof() {
+        let data = &content()[..100];
+        let tree = tree_of(data).await;
+        assert!(tree.proof(0).is_empty());
+        assert!(verify_chunk(&tree.root(), tree.size(), 0, data, &[]));
+    }
+
+    #[tokio::test]
+    async fn tampered_chunk_fails() {
+        let data = content();
+        let tree = tree_of(&data).await;
+        let proof = tree.proof(2);
+        let mut bad = chunk(&data, 2).to_vec();
+        bad[100] ^= 1;
+        assert!(!verify_chunk(&tree.root(), tree.size(), 2, &bad, &proof));
+        // A good chunk under the wrong index or with another chunk's proof
+        assert!(!verify_chunk(&tree.root(), tree.size(), 3, chunk(&data, 2), &tree.proof(3)));
+        assert!(!verify_chunk(&tree.root(), tree.size(), 2, chunk(&data, 2), &tree.proof(1)));
+        let mut bad_proof = proof.clone();
+        bad_proof[0][0] ^= 1;
+        assert!(!verify_chunk(&tree.root(), tree.size(), 2, chunk(&data, 2), &bad_proof));
+        assert!(!verify_chunk(&tree.root(), tree.size(), 2, chunk(&data, 2), &proof[1..]));
+    }
+
+    #[tokio::test]
+    async fn last_partial_chunk() {
+        let data = content();
+        let tree = tree_of(&data).await;
+        let last = tree.total_chunks() - 1;
+        let tail = chunk(&data, last);
+        assert_eq!(tail.len(), CHUNK_SIZE / 2);
+        assert!(verify_chunk(&tree.root(), tree.size(), last, tail, &tree.proof(last)));
+        // Padded or cut short it no longer matches
+        let mut padded = tail.to_vec();
+        padded.resize(CHUNK_SIZE, 0);
+        assert!(!verify_chunk(&tree.root(), tree.size(), last, &padded, &tree.proof(last)));
+        assert!(!verify_chunk(&tree.root(), tree.size(), last, &tail[1..], &tree.proof(last)));
+    }
+
+    #[tokio::test]
+    async fn out_of_range_index_fails() {
+        let data = content();
+        let tree = tree_of(&data).await;
+        let total = tree.total_chunks();
+        let proof = tree.proof(total - 1);
+        for index in [total, total + 1, usize::MAX / CHUNK_SIZE] {
+            assert!(!verify_chunk(&tree.root(), tree.size(), index, chunk(&data, total - 1), &proof));
+        }
+        assert!(!verify_chunk(&tree.root(), 0, 0, b"x", &[]));
+    }
+
+    #[tokio::test]
+    async fn aligned_runs_verify_as_subtrees() {
+        let data = content();
+        let tree = tree_of(&data).await;
+        let left = left_subtree_len(tree.size());
+        assert_eq!(left, 4 * CHUNK);
+        let proof = tree.subtree_proof(0, left);
+        assert!(verify_range(&tree.root(), tree.size(), 0, &data[..left as usize], &proof));
+        assert!(!verify_range(&tree.root(), tree.size(), 0, &data[..left as usize - 1], &proof));
+        // Two chunks that aren't one subtree can't be checked together
+        let proof = tree.subtree_proof(CHUNK, 2 * CHUNK);
+        assert!(!verify_range(&tree.root(), tree.size(), CHUNK, &data[CHUNK_SIZE..3 * CHUNK_SIZE], &proof));
+    }
+
+    #[tokio::test]
+    async fn zero_ranges() {
+        let mut data = content();
+        data[..4 * CHUNK_SIZE].fill(0);
+        let tree = tree_of(&data).await;
+        assert_eq!(tree.zero_range(1), Some((0, 4 * CHUNK)));
+        assert_eq!(tree.zero_range(4), None);
+        let proof = tree.subtree_proof(0, 4 * CHUNK);
+        assert!(verify_zeros(&tree.root(), tree.size(), 0, 4 * CHUNK, &proof));
+        // The same claim against content that isn't zeros
+        let other = tree_of(&content()).await;
+        let proof = other.subtree_proof(0, 4 * CHUNK);
+        assert!(!verify_zeros(&other.root(), other.size(), 0, 4 * CHUNK, &proof));
+        // Nor for a length that isn't the subtree
+        assert!(!verify_zeros(&tree.root(), tree.size(), 0, 3 * CHUNK, &tree.subtree_proof(0, 3 * CHUNK)));
+    }
+}
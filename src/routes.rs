@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::mpsc;
+
+use crate::packet::Packet;
+
+/// Packets waiting for a task that falls behind. More are dropped like a
+/// full socket buffer would, and asked for again like any lost datagram.
+const ROUTE_CAPACITY: usize = 1024;
+
+pub type Routed = (Packet, SocketAddr);
+
+/// Packets of one transfer from one peer, or from any peer.
+type Key = (u64, Option<SocketAddr>);
+/// Every open route of a key with the serial it closes by.
+type Senders = Vec<(u64, mpsc::Sender<Routed>)>;
+
+/// Hands the packets of a transfer to the tasks that opened a route for
+/// its id, so each task sees only its own packets.
+#[derive(Clone, Default)]
+pub struct Routes {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next: AtomicU64,
+    routes: Mutex<HashMap<Key, Senders>>,
+}
+
+impl Routes {
+    /// Starts taking packets with `id` from `from`, or from every peer.
+    /// Opened before the packet that asks for them goes out, so none is
+    /// missed.
+    pub fn open(&self, id: u64, from: Option<SocketAddr>) -> Route {
+        let (tx, rx) = mpsc::channel(ROUTE_CAPACITY);
+        let serial = self.inner.next.fetch_add(1, Ordering::Relaxed);
+        let key = (id, from);
+        let mut routes = self.inner.routes.lock().expect("routes lock poisoned");
+        routes.entry(key).or_default().push((serial, tx));
+        Route {
+            key,
+            serial,
+            rx,
+            routes: self.clone(),
+        }
+    }
+
+    /// Passes a packet to every route open for its id and sender, false
+    /// when none is.
+    pub fn deliver(&self, packet: Packet, addr: SocketAddr) -> bool {
+        let Some(id) = route_id(&packet) else {
+            return false;
+        };
+        let routes = self.inner.routes.lock().expect("routes lock poisoned");
+        let senders: Vec<&mpsc::Sender<Routed>> = [(id, Some(addr)), (id, None)]
+            .iter()
+            .filter_map(|key| routes.get(key))
+            .flatten()
+            .map(|(_, tx)| tx)
+            .collect();
+        let Some((last, rest)) = senders.split_last() else {
+            return false;
+        };
+        for tx in rest {
+            forward(tx, (packet.clone(), addr));
+        }
+        forward(last, (packet, addr));
+        true
+    }
+
+    fn close(&self, key: Key, serial: u64) {
+        let mut routes = self.inner.routes.lock().expect("routes lock poisoned");
+        if let Some(senders) = routes.get_mut(&key) {
+            senders.retain(|(s, _)| *s != serial);
+            if senders.is_empty() {
+                routes.remove(&key);
+            }
+        }
+    }
+}
+
+fn forward(tx: &mpsc::Sender<Routed>, routed: Routed) {
+    // Full or closing, either way the packet is dropped like a lost datagram
+    let _ = tx.try_send(routed);
+}
+
+/// Id of the transfer, listing or request a packet belongs to.
+fn route_id(packet: &Packet) -> Option<u64> {
+    let id = match packet {
+        Packet::File(p) => p.id,
+        Packet::Metadata(p) => p.id,
+        Packet::MdRes(p) => p.id,
+        Packet::Ack(p) => p.id,
+        Packet::Listing(p) => p.id,
+        Packet::Group(p) => p.id,
+        Packet::Repair(p) => p.id,
+        Packet::Zero(p) => p.id,
+        Packet::Delta(p) => p.id,
+        Packet::Reject(p) => p.id,
+        Packet::Error(p) => p.id,
+        Packet::Complete(p) => p.id,
+        Packet::Have(p) => p.id,
+        _ => return None,
+    };
+    Some(id)
+}
+
+/// Receiving end of `Routes::open`, closed when dropped.
+pub struct Route {
+    key: Key,
+    serial: u64,
+    rx: mpsc::Receiver<Routed>,
+    routes: Routes,
+}
+
+impl Route {
+    /// Next packet of the route. The route stays registered while it is
+    /// held, so this only ends with `None` if every sender is gone.
+    pub async fn recv(&mut self) -> Option<Routed> {
+        self.rx.recv().await
+    }
+
+    /// A packet that is already waiting, `None` if there is none.
+    pub fn try_recv(&mut self) -> Option<Routed> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        self.routes.close(self.key, self.serial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Packet;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn packets_reach_only_their_transfer() {
+        let routes = Routes::default();
+        let mut one = routes.open(1, Some(addr(1)));
+        let mut two = routes.open(2, None);
+        assert!(routes.deliver(Packet::create_reject(1), addr(1)));
+        assert!(routes.deliver(Packet::create_reject(2), addr(3)));
+        // Another peer's packet for transfer 1, nobody waits for it
+        assert!(!routes.deliver(Packet::create_reject(1), addr(2)));
+        assert!(!routes.deliver(Packet::create_reject(3), addr(1)));
+
+        let (packet, from) = one.recv().await.unwrap();
+        assert!(matches!(packet, Packet::Reject(r) if r.id == 1) && from == addr(1));
+        let (packet, from) = two.recv().await.unwrap();
+        assert!(matches!(packet, Packet::Reject(r) if r.id == 2) && from == addr(3));
+        assert!(one.rx.try_recv().is_err() && two.rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_routes_are_forgotten() {
+        let routes = Routes::default();
+        let first = routes.open(1, None);
+        let mut second = routes.open(1, None);
+        drop(first);
+        assert!(routes.deliver(Packet::create_reject(1), addr(1)));
+        assert!(second.recv().await.is_some());
+        drop(second);
+        assert!(!routes.deliver(Packet::create_reject(1), addr(1)));
+        assert!(routes.inner.routes.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_full_route_drops_instead_of_blocking() {
+        let routes = Routes::default();
+        let mut route = routes.open(1, None);
+        for _ in 0..ROUTE_CAPACITY + 10 {
+            routes.deliver(Packet::create_reject(1), addr(1));
+        }
+        let mut count = 0;
+        while route.rx.try_recv().is_ok() {
+            count += 1;
+        }
+        assert_eq!(count, ROUTE_CAPACITY);
+    }
+}
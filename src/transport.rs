@@ -0,0 +1,369 @@
+use indicatif::HumanBytes;
+use std::{io, net::SocketAddr, time::Duration};
+use tokio::{
+    net::UdpSocket,
+    sync::watch,
+    time::{timeout, Instant},
+};
+
+use crate::packet::{
+    file::{CHUNK_SIZE, PACKET_SIZE},
+    frame::HEADER_LEN,
+    session,
+};
+
+/// Most datagrams moved by one system call.
+pub const BATCH: usize = 32;
+/// Socket buffer asked for, enough for a whole batch of chunk frames.
+/// The system may cap it, on Linux at `net.core.rmem_max`.
+const SOCKET_BUFFER: usize = 4 * 1024 * 1024;
+/// Datagrams sent per run of `connect-p2p bench`.
+const BENCH_COUNT: usize = 50_000;
+/// A control packet and a full chunk frame with the proof of a chunk in a 1 GiB file.
+const BENCH_SIZES: [usize; 2] = [256, HEADER_LEN + 15 * 32 + CHUNK_SIZE];
+/// Bytes sent ahead of the benchmark receiver, below the default receive buffer.
+const BENCH_INFLIGHT: usize = 160 * 1024;
+/// Quiet time after which the benchmark receiver stops counting.
+const BENCH_IDLE: Duration = Duration::from_millis(300);
+
+// Batched datagram I/O. On Linux a batch goes through one sendmmsg or
+// recvmmsg call, elsewhere it falls back to one send_to or recv_from per
+// datagram. Segmentation offload (UDP_SEGMENT/UDP_GRO) isn't used: a chunk
+// frame is already larger than the MTU and IP-fragmented, bigger than any
+// segment the kernel would split off or coalesce.
+
+/// Enlarges the socket's buffers. A batch reaches the other side at once,
+/// with the default receive buffer of a few hundred KiB the end of it is dropped.
+pub fn set_buffers(socket: &UdpSocket) {
+    let sock = socket2::SockRef::from(socket);
+    let res = sock
+        .set_recv_buffer_size(SOCKET_BUFFER)
+        .and_then(|()| sock.set_send_buffer_size(SOCKET_BUFFER));
+    if let Err(e) = res {
+        eprintln!("Could not enlarge the socket buffers, {}", e);
+    }
+}
+
+/// Datagrams received by one call, each in its own reused buffer.
+pub struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    pub fn new() -> Self {
+        RecvBatch {
+            bufs: vec![vec![0; PACKET_SIZE]; BATCH],
+            received: Vec::with_capacity(BATCH),
+        }
+    }
+
+    /// Waits for at least one datagram and takes what else is queued, up to `BATCH`.
+    pub async fn recv(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        self.received.clear();
+        #[cfg(target_os = "linux")]
+        loop {
+            socket.readable().await?;
+            let res = socket.try_io(tokio::io::Interest::READABLE, || {
+                mmsg::recv(socket, &mut self.bufs, &mut self.received)
+            });
+            match res {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let received = socket.recv_from(&mut self.bufs[0]).await?;
+            self.received.push(received);
+            Ok(())
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received.iter().zip(&self.bufs).map(|((len, addr), buf)| (&buf[..*len], *addr))
+    }
+}
+
+/// Datagrams queued for one address, encoded into buffers kept across batches.
+pub struct SendBatch {
+    bufs: Vec<Vec<u8>>,
+    len: usize,
+    /// Where a datagram to a room peer is sealed, see `session::seal_all`.
+    scratch: Vec<u8>,
+}
+
+impl SendBatch {
+    pub fn new() -> Self {
+        SendBatch {
+            bufs: Vec::new(),
+            len: 0,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// An empty buffer for the next datagram.
+    pub fn buffer(&mut self) -> &mut Vec<u8> {
+        if self.len == self.bufs.len() {
+            self.bufs.push(Vec::new());
+        }
+        self.len += 1;
+        let buf = &mut self.bufs[self.len - 1];
+        buf.clear();
+        buf
+    }
+
+    /// Sends the queued datagrams in order and empties the batch.
+    pub async fn send(&mut self, socket: &UdpSocket, addr: SocketAddr) -> io::Result<()> {
+        session::seal_all(addr, &mut self.bufs[..self.len], &mut self.scratch)?;
+        let queued = &self.bufs[..self.len];
+        self.len = 0;
+        #[cfg(target_os = "linux")]
+        for batch in queued.chunks(BATCH) {
+            let mut sent = 0;
+            while sent < batch.len() {
+                socket.writable().await?;
+                match socket.try_io(tokio::io::Interest::WRITABLE, || mmsg::send(socket, addr, &batch[sent..])) {
+                    Ok(n) => sent += n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        for buf in queued {
+            socket.send_to(buf, addr).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::{
+        io, mem,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+        os::fd::AsRawFd,
+        ptr,
+    };
+    use tokio::net::UdpSocket;
+
+    use super::BATCH;
+
+    fn to_raw(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // SAFETY: all-zero is a valid sockaddr_storage
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(a) => {
+                let sin = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: a.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(a.ip().octets()),
+                    },
+                    sin_zero: [0; 8],
+                };
+                // SAFETY: sockaddr_storage is large enough for any address
+                unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(a) => {
+                let sin6 = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: a.port().to_be(),
+                    sin6_flowinfo: a.flowinfo(),
+                    sin6_addr: libc::in6_addr { s6_addr: a.ip().octets() },
+                    sin6_scope_id: a.scope_id(),
+                };
+                // SAFETY: as above
+                unsafe { ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    fn from_raw(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: the family says it holds a sockaddr_in
+                let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+                Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the family says it holds a sockaddr_in6
+                let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                let port = u16::from_be(sin6.sin6_port);
+                Some(SocketAddr::V6(SocketAddrV6::new(ip, port, sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+            }
+            _ => None,
+        }
+    }
+
+    /// One sendmmsg call, returns how many of `bufs` went out.
+    pub fn send(socket: &UdpSocket, addr: SocketAddr, bufs: &[Vec<u8>]) -> io::Result<usize> {
+        let n = bufs.len().min(BATCH);
+        let (mut name, name_len) = to_raw(addr);
+        let mut iovs: Vec<libc::iovec> = bufs[..n]
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .map(|iov| {
+                // SAFETY: all-zero is a valid mmsghdr
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = &mut name as *mut _ as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = name_len;
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+        // SAFETY: every header points at a live address and buffer
+        let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), n as _, libc::MSG_DONTWAIT as _) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    /// One recvmmsg call filling `bufs`, lengths and senders go to `received`.
+    pub fn recv(socket: &UdpSocket, bufs: &mut [Vec<u8>], received: &mut Vec<(usize, SocketAddr)>) -> io::Result<()> {
+        // SAFETY: all-zero is a valid sockaddr_storage
+        let mut names: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+        let mut iovs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .zip(names.iter_mut())
+            .map(|(iov, name)| {
+                // SAFETY: all-zero is a valid mmsghdr
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_name = name as *mut _ as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+        // SAFETY: every header points at a live buffer and address slot
+        let n = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as _,
+                libc::MSG_DONTWAIT as _,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (msg, name) in msgs.iter().zip(&names).take(n as usize) {
+            if let Some(addr) = from_raw(name) {
+                received.push((msg.msg_len as usize, addr));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loopback throughput of plain and batched I/O, `connect-p2p bench`.
+pub async fn bench() -> io::Result<()> {
+    for size in BENCH_SIZES {
+        println!("{} datagrams of {} over loopback", BENCH_COUNT, HumanBytes(size as u64));
+        for batched in [false, true] {
+            let receiver = UdpSocket::bind("127.0.0.1:0").await?;
+            let sender = UdpSocket::bind("127.0.0.1:0").await?;
+            let addr = receiver.local_addr()?;
+            let (count_tx, count_rx) = watch::channel(0);
+            let start = Instant::now();
+            let (sent, received) = tokio::join!(
+                bench_send(&sender, addr, size, batched, count_rx),
+                bench_recv(&receiver, batched, count_tx)
+            );
+            sent?;
+            let (count, last) = received?;
+            let secs = last.duration_since(start).as_secs_f64();
+            println!(
+                "  {:<8} {} received in {:.2}s, {} datagrams/s, {}/s",
+                if batched { "batched" } else { "plain" },
+                count,
+                secs,
+                (count as f64 / secs) as u64,
+                HumanBytes(((count * size) as f64 / secs) as u64)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Sends `BENCH_COUNT` datagrams, keeping at most `BENCH_INFLIGHT` bytes
+/// ahead of the receiver so none are dropped for a full receive buffer.
+async fn bench_send(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    size: usize,
+    batched: bool,
+    mut received: watch::Receiver<usize>,
+) -> io::Result<()> {
+    let datagram = vec![0xAB; size];
+    // Small datagrams take more of the buffer than their length
+    let inflight = (BENCH_INFLIGHT / size).clamp(1, 2 * BATCH);
+    let per_call = if batched { (inflight / 2).clamp(1, BATCH) } else { 1 };
+    let (mut buf, mut batch) = (Vec::new(), SendBatch::new());
+    let mut sent = 0;
+    while sent < BENCH_COUNT {
+        let n = per_call.min(BENCH_COUNT - sent);
+        let room = received.wait_for(|r| sent + n <= r + inflight);
+        if timeout(BENCH_IDLE, room).await.is_err() {
+            break;
+        }
+        if batched {
+            for _ in 0..n {
+                batch.buffer().extend_from_slice(&datagram);
+            }
+            batch.send(socket, addr).await?;
+        } else {
+            buf.clear();
+            buf.extend_from_slice(&datagram);
+            socket.send_to(&buf, addr).await?;
+        }
+        sent += n;
+    }
+    Ok(())
+}
+
+/// Datagrams received and when the last one arrived.
+async fn bench_recv(socket: &UdpSocket, batched: bool, count_tx: watch::Sender<usize>) -> io::Result<(usize, Instant)> {
+    let (mut count, mut last) = (0, Instant::now());
+    let mut buf = vec![0; PACKET_SIZE];
+    let mut batch = RecvBatch::new();
+    while count < BENCH_COUNT {
+        let received = if batched {
+            timeout(BENCH_IDLE, batch.recv(socket)).await.map(|res| res.map(|()| batch.iter().count()))
+        } else {
+            timeout(BENCH_IDLE, socket.recv_from(&mut buf)).await.map(|res| res.map(|_| 1))
+        };
+        match received {
+            Ok(n) => {
+                count += n?;
+                last = Instant::now();
+                let _ = count_tx.send(count);
+            }
+            Err(_) => break,
+        }
+    }
+    Ok((count, last))
+}
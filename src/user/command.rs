@@ -1,28 +1,45 @@
-use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressState, ProgressStyle};
 use std::{
-    collections::HashSet, fmt::Write, net::SocketAddr, path::Path, sync::Arc, time::Duration,
+    collections::HashSet, fmt::Write, net::SocketAddr, sync::Arc, time::Duration,
 };
 use tokio::{
-    fs::File,
-    io::{AsyncReadExt, BufReader},
     net::UdpSocket,
-    time::timeout,
+    sync::Mutex,
+    time::{timeout_at, Instant},
 };
 
-use super::{Packet, ReceiverAck, User};
+use super::history::{Outcome, Record};
+use super::transfers::{Direction, Job, Shared};
+use super::{Packet, Peer, User};
+use crate::routes::{Route, Routes};
+use crate::packet::{
+    cache::ChunkCache,
+    compress,
+    delta::{self, Signatures, MAX_DELTA_SIZE},
+    dir::Manifest,
+    fec,
+    file::{CHUNK_SIZE, OFFER_TIMEOUT},
+    source::Source,
+    swarm::WantPacket,
+    transfer::{ErrorCode, TransferState},
+    tree::HashTree,
+};
+use crate::transport::{SendBatch, BATCH};
 
-const CHUNK_SIZE: usize = 59 * 1024;
+/// How long a sender waits for the next chunk request or the outcome.
+const SERVE_TIMEOUT: Duration = Duration::from_secs(20);
 
 pub enum Command {
     Connect(SocketAddr),
     Disconnect(SocketAddr),
-    File(String),
+    /// Path, recipients and the id of the `get:` request it answers.
+    File(String, Vec<Peer>, Option<u64>),
 }
 
 impl Command {
-    pub async fn handle_connect(&self, socket: &UdpSocket, name: String) {
+    pub async fn handle_connect(&self, socket: &UdpSocket, user: &User, nonce: u64) {
         if let Command::Connect(addr) = self {
-            let packet = Packet::create_binding_req(true, name);
+            let packet = Packet::create_binding_req(true, user, nonce);
             if let Err(e) = packet.send_packet(socket, addr).await {
                 eprintln!("Error sending connection packet: {:?}", e);
             }
@@ -31,9 +48,33 @@ impl Command {
         }
     }
 
-    pub async fn handle_disconnect(&self, socket: &UdpSocket, name: String) {
+    pub async fn handle_room_connect(&self, socket: &UdpSocket, user_lock: Arc<Mutex<User>>) {
+        if let Command::Connect(addr) = self {
+            let Some(key) = user_lock.lock().await.room_key() else {
+                eprintln!("Not in a room");
+                return;
+            };
+            // Stretching the passphrase takes a moment, not on the runtime
+            let hello = tokio::task::spawn_blocking(move || Packet::create_room_hello(&key)).await;
+            let Ok(Some((packet, state))) = hello else {
+                eprintln!("Error starting the room handshake");
+                return;
+            };
+            if !user_lock.lock().await.room_pending.insert(*addr, state) {
+                eprintln!("Too many room handshakes waiting, try again shortly");
+                return;
+            }
+            if let Err(e) = packet.send_packet(socket, addr).await {
+                eprintln!("Error sending room packet: {:?}", e);
+            }
+        } else {
+            eprintln!("Invalid command: Expected `Connect`");
+        }
+    }
+
+    pub async fn handle_disconnect(&self, socket: &UdpSocket, user: &User, nonce: u64) {
         if let Command::Disconnect(addr) = self {
-            let packet = Packet::create_binding_req(false, name);
+            let packet = Packet::create_binding_req(false, user, nonce);
             if let Err(e) = packet.send_packet(socket, addr).await {
                 eprintln!("Error sending connection packet: {:?}", e);
             }
@@ -42,14 +83,17 @@ impl Command {
         }
     }
 
+    /// Offers a file or folder to the peers of a `Command::File` and sends
+    /// it to each that accepts. Returns how it ended for every peer that
+    /// answered, the ones that didn't are left out.
     pub async fn read_file(
         &self,
         socket: Arc<UdpSocket>,
-        user: &mut User,
-        mut ack_rx: ReceiverAck,
-    ) -> tokio::io::Result<()> {
-        let path = match self {
-            Command::File(p) => p.to_string(),
+        user_lock: Arc<Mutex<User>>,
+        routes: Routes,
+    ) -> tokio::io::Result<Vec<(SocketAddr, Outcome)>> {
+        let (path, peers, request) = match self {
+            Command::File(p, peers, request) => (p.to_string(), peers, *request),
             _ => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::Other,
@@ -58,12 +102,8 @@ impl Command {
             }
         };
 
-        let file = File::open(&path).await?;
-        let metadata = file.metadata().await?;
-        let total_size = metadata.len() as usize;
-        let total_chunks = (total_size + CHUNK_SIZE - 1) / CHUNK_SIZE;
-
-        let file_name = match Path::new(&path).file_name() {
+        let path = tokio::fs::canonicalize(&path).await?;
+        let file_name = match path.file_name() {
             Some(name) => name.to_string_lossy().to_string(),
             None => {
                 return Err(std::io::Error::new(
@@ -73,12 +113,84 @@ impl Command {
             }
         };
 
-        let packet = Packet::create_filemetadata(file_name.clone(), total_chunks);
-        let mut interested_peer = HashSet::with_capacity(user.connected.len());
-        let mut tasks = Vec::with_capacity(user.connected.len());
+        let metadata = tokio::fs::metadata(&path).await?;
+        println!("Hashing {}", path.display());
+        let (source, manifest) = if metadata.is_dir() {
+            // A folder fetched from a share never carries its dotfiles
+            let (manifest, files) = Manifest::scan(&path, request.is_none()).await?;
+            let bytes = manifest.serialize();
+            let manifest_len = bytes.len() as u64;
+            let source = Source::Dir {
+                manifest: Arc::new(bytes),
+                files: Arc::new(files),
+            };
+            (source, Some((manifest, manifest_len)))
+        } else {
+            (Source::File(path.clone()), None)
+        };
+        let tree = Arc::new(HashTree::from_source(&source).await?);
+        let total_size = tree.size();
+        // Every peer's sender reads through it, and so do helpers of a swarm
+        let chunks = Arc::new(ChunkCache::new(source.clone(), tree.clone()));
+        let name = {
+            let mut user = user_lock.lock().await;
+            let shared = Shared {
+                name: file_name.clone(),
+                source: source.clone(),
+                chunks: Some(chunks.clone()),
+            };
+            user.share(tree.root(), shared);
+            user.get_name()
+        };
+
+        let mut packet = match &manifest {
+            Some((manifest, manifest_len)) => Packet::create_dirmetadata(
+                name.clone(),
+                file_name.clone(),
+                total_size,
+                tree.root(),
+                &metadata,
+                manifest,
+                *manifest_len,
+            ),
+            None => Packet::create_filemetadata(
+                name.clone(),
+                file_name.clone(),
+                total_size,
+                tree.root(),
+                &metadata,
+            ),
+        };
+        let compression = compress::sample(&source, total_size).await?;
+        let offer_id = match &mut packet {
+            Packet::Metadata(offer) => {
+                offer.compression = compression;
+                // The requester recognises its get by the id
+                if let Some(id) = request {
+                    offer.id = id;
+                }
+                offer.id
+            }
+            _ => unreachable!("offers are metadata packets"),
+        };
+        let peer_name = |addr: SocketAddr| {
+            peers
+                .iter()
+                .find(|p| p.get_addr() == addr)
+                .map(|p| p.get_name())
+        };
+        let declined = |peer: &str, addr: SocketAddr, reason: Option<String>| {
+            let (name, peer) = (file_name.clone(), peer.to_string());
+            Record::rejected(Direction::Send, name, peer, addr, total_size, tree.root(), reason)
+        };
+        let mut interested_peer = HashSet::with_capacity(peers.len());
+        let mut answered = HashSet::with_capacity(peers.len());
+        let mut tasks = Vec::with_capacity(peers.len());
+        let mut outcomes = Vec::with_capacity(peers.len());
 
-        let user_clone = user.clone();
-        for peer in user_clone.connected {
+        // Answers from every recipient, each accepted one gets its own route
+        let mut answers = routes.open(offer_id, None);
+        for peer in peers {
             let socket_clone = socket.clone();
             let packet_clone = packet.clone();
             let _ = packet_clone
@@ -93,106 +205,426 @@ impl Command {
         .progress_chars("#>-");
 
         println!("waiting for peer to respond");
+        let deadline = Instant::now() + OFFER_TIMEOUT + Duration::from_secs(2);
         loop {
-            match timeout(Duration::from_secs(5), ack_rx.recv()).await {
-                Ok(Ok(Some((pac, addr)))) => {
+            match timeout_at(deadline, answers.recv()).await {
+                Ok(Some((Packet::Reject(_), addr))) => {
+                    if let Some(peer) = peer_name(addr) {
+                        if answered.insert(addr) {
+                            outcomes.push((addr, Outcome::Rejected));
+                            println!("{} declined {}", peer, file_name);
+                            let record = declined(peer, addr, None);
+                            user_lock.lock().await.record(record);
+                        }
+                    }
+                }
+                Ok(Some((Packet::Error(err), addr))) => {
+                    if let Some(peer) = peer_name(addr) {
+                        if answered.insert(addr) {
+                            outcomes.push((addr, Outcome::Rejected));
+                            println!("{} refused {}, {}: {}", peer, file_name, err.code, err.reason);
+                            let record = declined(peer, addr, Some(format!("{}: {}", err.code, err.reason)));
+                            user_lock.lock().await.record(record);
+                        }
+                    }
+                }
+                Ok(Some((pac, addr))) => {
                     if let Packet::MdRes(res) = pac {
                         if res.verify(&packet) {
-                            if interested_peer.insert(addr) {
+                            let recipient = peers.iter().any(|p| p.get_addr() == addr);
+                            if recipient && interested_peer.insert(addr) {
+                                answered.insert(addr);
                                 let socket_clone = socket.clone();
-                                let file_name_clone = file_name.clone();
-                                let path_clone = path.clone();
-                                let res_rx = ack_rx.resubscribe();
-                                let pb =
-                                    m.add(ProgressBar::new((total_chunks * CHUNK_SIZE) as u64));
+                                let chunks_clone = chunks.clone();
+                                let route = routes.open(offer_id, Some(addr));
+                                let pb = m.add(ProgressBar::new(total_size));
                                 pb.set_style(sty.clone());
-                                pb.set_message( format!("{}", user.get_name()));
+                                pb.set_message(name.clone());
+                                let mut job = {
+                                    let mut user = user_lock.lock().await;
+                                    let peer = user.peer_name(&addr).unwrap_or_default();
+                                    let mut job = user.transfers.add(
+                                        res.id,
+                                        file_name.clone(),
+                                        peer,
+                                        addr,
+                                        Direction::Send,
+                                        total_size,
+                                    );
+                                    job.throttle = user.throttle(&addr, Direction::Send);
+                                    job.key = user.peer_key(&addr);
+                                    job
+                                };
+                                job.compress = compression.is_some() && res.compression == compression;
+                                let user_lock = user_lock.clone();
                                 let thread = tokio::spawn(async move {
-                                    if let Err(e) = handle_peer(
+                                    let id = job.id;
+                                    let res = send_job(
                                         &socket_clone,
-                                        total_chunks,
-                                        file_name_clone,
-                                        path_clone,
-                                        addr,
-                                        res_rx,
+                                        chunks_clone,
+                                        job,
+                                        route,
                                         pb,
+                                        res.basis,
                                     )
-                                    .await
-                                    {
-                                        eprintln!("Err Sending file to peer, \n{}", e);
+                                    .await;
+                                    let mut user = user_lock.lock().await;
+                                    user.transfers.remove(id);
+                                    match res {
+                                        Ok(record) => {
+                                            let outcome = record.outcome;
+                                            user.record(record);
+                                            outcome
+                                        }
+                                        Err(e) => {
+                                            eprintln!("Err Sending file to peer, \n{}", e);
+                                            Outcome::Failed
+                                        }
                                     }
                                 });
-                                tasks.push(thread);
+                                tasks.push((addr, thread));
                             }
                         }
                     }
                 }
-                _ => break,
+                Ok(_) => continue,
+                Err(_) => break,
             }
-            if interested_peer.len() == user.connected.len() {
+            if answered.len() == peers.len() {
                 break;
             }
         }
-        if interested_peer.len() == 0 {
+        drop(answers);
+        if answered.is_empty() {
             println!("No Peer Responded");
+        } else if interested_peer.is_empty() {
+            println!("No peer accepted {}", file_name);
         } else {
             println!("Stop Reciving Response");
             println!("total {} peer responded", interested_peer.len());
 
             let total_task = tasks.len();
-            for task in tasks {
-                if let Err(e) = task.await {
-                    println!("Error compelting task, {}", e);
+            for (addr, task) in tasks {
+                match task.await {
+                    Ok(outcome) => outcomes.push((addr, outcome)),
+                    Err(e) => println!("Error compelting task, {}", e),
                 }
             }
             if total_task > 0 {
                 println!("Sending Completed");
             }
         }
-        Ok(())
+        Ok(outcomes)
     }
 }
 
-async fn handle_peer(
+/// Waits for a free transfer slot, telling the peer while queued, then streams.
+/// A receiver holding an older version gets only the delta when it is smaller.
+async fn send_job(
     socket: &UdpSocket,
-    total_chunks: usize,
-    file_name: String,
-    path: String,
-    addr: SocketAddr,
-    mut ack_rx: ReceiverAck,
+    chunks: Arc<ChunkCache>,
+    mut job: Job,
+    route: Route,
     pb: ProgressBar,
+    basis: Option<Signatures>,
+) -> tokio::io::Result<Record> {
+    let _permit = match job.try_slot() {
+        Some(permit) => permit,
+        None => {
+            println!("Transfer {}: {} queued", job.id, job.name);
+            let queued = Packet::create_transfer_packet(job.wire, TransferState::Queued);
+            queued.send_packet(socket, &job.addr).await?;
+            job.slot().await
+        }
+    };
+    if job.start() {
+        let running = Packet::create_transfer_packet(job.wire, TransferState::Running);
+        running.send_packet(socket, &job.addr).await?;
+    }
+    // Time spent queued doesn't count toward the throughput
+    job.started = std::time::Instant::now();
+    // Held until the transfer ends when a delta is sent, it stays in memory that long
+    let mut slot = basis.as_ref().and_then(|_| delta::slot());
+    let chunks = match (basis, chunks.source()) {
+        (Some(basis), Source::File(path)) if slot.is_some() && chunks.tree().size() <= MAX_DELTA_SIZE => {
+            match delta::encode(path.clone(), basis).await? {
+                Some(bytes) => {
+                    let delta = Source::Memory(Arc::new(bytes));
+                    let delta_tree = Arc::new(HashTree::from_source(&delta).await?);
+                    println!(
+                        "Transfer {}: {} has an older {}, sending {} of changes",
+                        job.id,
+                        job.peer,
+                        job.name,
+                        HumanBytes(delta_tree.size())
+                    );
+                    pb.set_length(delta_tree.size());
+                    job.set_size(delta_tree.size());
+                    job.content = Some(chunks.tree().root());
+                    Packet::create_delta(job.wire, delta_tree.root(), delta_tree.size())
+                        .send_packet(socket, &job.addr)
+                        .await?;
+                    Arc::new(ChunkCache::new(delta, delta_tree))
+                }
+                None => {
+                    slot = None;
+                    chunks
+                }
+            }
+        }
+        _ => {
+            slot = None;
+            chunks
+        }
+    };
+    if job.compress {
+        println!("Transfer {}: sending {}, compressed", job.id, job.name);
+    } else {
+        println!("Transfer {}: sending {}", job.id, job.name);
+    }
+    let record = serve_job(socket, chunks, job, route, pb, true).await;
+    drop(slot);
+    Ok(record)
+}
+
+/// Answers a peer asking for content by hash, when it was offered or received
+/// here, by serving its chunk requests next to the original sender.
+pub async fn handle_want(
+    socket: Arc<UdpSocket>,
+    user_lock: Arc<Mutex<User>>,
+    addr: SocketAddr,
+    want: WantPacket,
+    route: Route,
 ) -> tokio::io::Result<()> {
-    let mut buf = [0; CHUNK_SIZE];
-
-    let file = File::open(&path).await?;
-    let mut reader = BufReader::new(file);
-    let mut index = 1;
-    let mut n = reader.read(&mut buf).await?;
-    let mut attemp = 0;
-
-    loop {
-        if n == 0 || attemp >= 3 {
-            break;
-        }
-        let packet =
-            Packet::create_file_packet(file_name.clone(), index, total_chunks, buf[..n].to_vec());
-        packet.send_packet(socket, &addr).await?;
-        match timeout(Duration::from_secs(3), ack_rx.recv()).await {
-            Ok(Ok(Some((pac, receiver_addr)))) => {
-                if let Packet::Ack(ack) = pac {
-                    if (ack.chunk_index == index + 1) && (addr == receiver_addr) {
-                        index += 1;
-                        pb.set_position((index * CHUNK_SIZE) as u64);
-                        n = reader.read(&mut buf).await?;
+    let (shared, peer) = {
+        let user = user_lock.lock().await;
+        let peer = match user.peer_name(&addr) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+        match user.shared.get(&want.hash) {
+            Some(shared) => (shared.clone(), peer),
+            None => return Ok(()),
+        }
+    };
+    let chunks = match shared.chunks {
+        Some(chunks) => chunks,
+        None => {
+            let tree = Arc::new(HashTree::from_source(&shared.source).await?);
+            let mut user = user_lock.lock().await;
+            if tree.root() != want.hash {
+                // Changed on disk since it was received
+                user.shared.remove(&want.hash);
+                return Ok(());
+            }
+            let chunks = Arc::new(ChunkCache::new(shared.source.clone(), tree));
+            if let Some(entry) = user.shared.get_mut(&want.hash) {
+                entry.chunks = Some(chunks.clone());
+            }
+            chunks
+        }
+    };
+    let tree = chunks.tree();
+    if tree.size() != want.size {
+        return Ok(());
+    }
+    let mut job = {
+        let mut user = user_lock.lock().await;
+        let mut job = user.transfers.add(
+            want.id,
+            shared.name,
+            peer,
+            addr,
+            Direction::Send,
+            tree.size(),
+        );
+        job.throttle = user.throttle(&addr, Direction::Send);
+        job.key = user.peer_key(&addr);
+        job
+    };
+    if want.compression.is_some() {
+        job.compress = compress::sample(&shared.source, tree.size()).await? == want.compression;
+    }
+    job.start();
+    Packet::create_have(want.id, want.hash)
+        .send_packet(&socket, &addr)
+        .await?;
+    println!("Transfer {}: {} also fetches {} from here", job.id, job.peer, job.name);
+    let id = job.id;
+    let record = serve_job(&socket, chunks, job, route, ProgressBar::hidden(), false).await;
+    let mut user = user_lock.lock().await;
+    user.transfers.remove(id);
+    user.record(record);
+    Ok(())
+}
+
+/// Sends the chunks the receiver asks for until it reports the outcome,
+/// which is returned for the history.
+/// The first chunk is pushed without a request when `push_first` is set.
+async fn serve_job(
+    socket: &UdpSocket,
+    chunks: Arc<ChunkCache>,
+    mut job: Job,
+    mut route: Route,
+    pb: ProgressBar,
+    push_first: bool,
+) -> Record {
+    // Chunks and repair symbols answering the requests waiting at once go
+    // out in one batch
+    let mut batch = SendBatch::new();
+    let total_chunks = chunks.tree().total_chunks();
+    let addr = job.addr;
+    let (wire, name) = (job.wire, job.name.clone());
+    let content = job.content.unwrap_or(chunks.tree().root());
+
+    let res: tokio::io::Result<Option<Packet>> = async {
+        let mut served = vec![false; total_chunks];
+        let mut next = Vec::new();
+        if push_first && total_chunks > 0 {
+            next.push(1..=1);
+        }
+        let mut repair = Vec::new();
+        let mut deadline = Instant::now() + SERVE_TIMEOUT;
+        loop {
+            if job.state() != TransferState::Running {
+                if !job.wait_running().await {
+                    return Ok(Some(Packet::create_transfer_packet(wire, TransferState::Cancelled)));
+                }
+                deadline = Instant::now() + SERVE_TIMEOUT;
+            }
+            let mut data = 0;
+            let mut sent_zeros = None;
+            for index in next.drain(..).flatten() {
+                // Zeros go as one range, it covers more than was asked for
+                let (offset, len) = match chunks.tree().zero_range(index - 1) {
+                    Some(range) if sent_zeros == Some(range) => continue,
+                    Some((offset, len)) => {
+                        let proof = chunks.tree().subtree_proof(offset, len);
+                        Packet::create_zero(wire, offset, len, proof).encode_into(batch.buffer());
+                        sent_zeros = Some((offset, len));
+                        (offset, len)
+                    }
+                    None => {
+                        let n = queue_chunk(&chunks, &job, index, &mut batch).await?;
+                        data += n as u64;
+                        ((index as u64 - 1) * CHUNK_SIZE as u64, n as u64)
+                    }
+                };
+                let first = (offset / CHUNK_SIZE as u64) as usize;
+                let last = (offset + len).div_ceil(CHUNK_SIZE as u64) as usize;
+                for (i, done) in served.iter_mut().enumerate().take(last).skip(first) {
+                    if !*done {
+                        *done = true;
+                        let n = (len + offset - i as u64 * CHUNK_SIZE as u64).min(CHUNK_SIZE as u64);
+                        pb.inc(n);
+                        job.progress(n);
                     }
                 }
             }
-            _ => {
-                println!("Error getting ack");
-                attemp += 1;
+            for (group, count) in repair.drain(..) {
+                for symbol in fec::repairs(&chunks, wire, group, count).await? {
+                    data += symbol.data.len() as u64;
+                    Packet::Repair(symbol).encode_into(batch.buffer());
+                }
+            }
+            job.throttle.take(data).await;
+            batch.send(socket, addr).await?;
+            tokio::select! {
+                received = timeout_at(deadline, route.recv()) => match received {
+                    Ok(Some((packet, _))) => {
+                        // Requests that queued up meanwhile are answered in the same batch
+                        let mut packet = Some(packet);
+                        while let Some(pac) = packet.take() {
+                            match pac {
+                                Packet::Ack(ack) => {
+                                    if (1..=total_chunks).contains(&ack.chunk_index) {
+                                        next.push(ack.chunk_index..=ack.chunk_index);
+                                    }
+                                    deadline = Instant::now() + SERVE_TIMEOUT;
+                                }
+                                Packet::Group(req) => {
+                                    let chunks = fec::group_chunks(req.group, total_chunks);
+                                    if !chunks.is_empty() {
+                                        next.push(chunks);
+                                        repair.push((req.group, req.repair));
+                                    }
+                                    deadline = Instant::now() + SERVE_TIMEOUT;
+                                }
+                                Packet::Complete(_) | Packet::Error(_) => return Ok(Some(pac)),
+                                _ => {}
+                            }
+                            if next.len() < BATCH {
+                                packet = route.try_recv().map(|(pac, _)| pac);
+                            }
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(_) => return Ok(None),
+                },
+                _ = job.changed() => {}
             }
         }
     }
+    .await;
     pb.finish_and_clear();
-    Ok(())
+
+    let mut receipt = None;
+    let (outcome, reason) = match res {
+        Ok(Some(Packet::Complete(done))) if done.hash == content => {
+            println!(
+                "Transfer {}: {} verified {} ({})",
+                job.id,
+                job.peer,
+                name,
+                blake3::Hash::from(done.hash).to_hex()
+            );
+            receipt = done.receipt;
+            (Outcome::Verified, None)
+        }
+        Ok(Some(Packet::Complete(done))) => {
+            println!("Transfer {}: {} saved {} with a different hash", job.id, job.peer, name);
+            let reason = format!("saved with hash {}", blake3::Hash::from(done.hash).to_hex());
+            (Outcome::Failed, Some(reason))
+        }
+        Ok(Some(Packet::Error(err))) => {
+            println!(
+                "Transfer {} of {} failed on {}, {}: {}",
+                job.id, name, job.peer, err.code, err.reason
+            );
+            (Outcome::Failed, Some(format!("{}: {}", err.code, err.reason)))
+        }
+        Ok(Some(_)) => {
+            println!("Transfer {} of {} cancelled", job.id, name);
+            (Outcome::Cancelled, None)
+        }
+        Ok(None) => {
+            println!("Transfer {} of {} failed, {} stopped answering", job.id, name, job.peer);
+            let reason = "no requests".to_string();
+            let packet = Packet::create_error(wire, ErrorCode::Timeout, reason.clone());
+            if let Err(e) = packet.send_packet(socket, &addr).await {
+                println!("Error telling {} about the timeout, {}", job.peer, e);
+            }
+            (Outcome::Failed, Some(format!("{}: {}", ErrorCode::Timeout, reason)))
+        }
+        Err(e) => {
+            println!("Transfer {} of {} failed, {}", job.id, name, e);
+            (Outcome::Failed, Some(e.to_string()))
+        }
+    };
+    let mut record = Record::of(&job, content, outcome, reason);
+    record.receipt = receipt;
+    record
+}
+
+/// Queues one chunk with its proof, returns its raw length.
+async fn queue_chunk(
+    chunks: &ChunkCache,
+    job: &Job,
+    index: usize,
+    batch: &mut SendBatch,
+) -> tokio::io::Result<usize> {
+    let chunk = chunks.get(index).await?;
+    let total_chunks = chunks.tree().total_chunks();
+    chunk.encode(batch.buffer(), job.wire, index, total_chunks, job.compress);
+    Ok(chunk.raw.len())
 }
@@ -0,0 +1,65 @@
+use rustyline::{
+    completion::Completer, highlight::Highlighter, hint::Hinter, validate::Validator, Context,
+    Helper,
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use super::User;
+
+/// Completes peer and group names after `@`, `,`, `dis:`, `browse:`, `get:` and `sync:`.
+pub struct PeerCompleter {
+    user_lock: Arc<Mutex<User>>,
+}
+
+impl PeerCompleter {
+    pub fn new(user_lock: Arc<Mutex<User>>) -> Self {
+        PeerCompleter { user_lock }
+    }
+}
+
+impl Completer for PeerCompleter {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let head = &line[..pos];
+        let start = head
+            .rfind(|c: char| c == '@' || c == ',' || c == ':' || c.is_whitespace())
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let selector = match head[..start].chars().last() {
+            Some('@') | Some(',') => true,
+            Some(':') => matches!(head[..start].trim_start(), "dis:" | "browse:" | "get:" | "sync:"),
+            _ => false,
+        };
+        if !selector {
+            return Ok((pos, Vec::new()));
+        }
+        // The receiver may hold the lock, completing nothing is better than blocking
+        let names = match self.user_lock.try_lock() {
+            Ok(user) => user.completions(),
+            Err(_) => return Ok((pos, Vec::new())),
+        };
+        let word = head[start..].to_lowercase();
+        let matches = names
+            .into_iter()
+            .filter(|n| n.to_lowercase().starts_with(&word))
+            .collect();
+        Ok((start, matches))
+    }
+}
+
+impl Hinter for PeerCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for PeerCompleter {}
+
+impl Validator for PeerCompleter {}
+
+impl Helper for PeerCompleter {}
@@ -0,0 +1,365 @@
+use chrono::{Local, TimeZone};
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+use super::transfers::{Direction, Job};
+use crate::config;
+use crate::packet::receipt::Receipt;
+use crate::user::identity::PublicKey;
+
+const HISTORY_FILE: &str = "history.jsonl";
+/// Entries `history:` shows when no count is given.
+const DEFAULT_SHOWN: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// The receiver confirmed the content hash.
+    Verified,
+    Failed,
+    /// Declined or refused before any data moved.
+    Rejected,
+    Cancelled,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Outcome::Verified => "verified",
+            Outcome::Failed => "failed",
+            Outcome::Rejected => "rejected",
+            Outcome::Cancelled => "cancelled",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+/// How a transfer ended, filled in by the task that ran it. The peer's
+/// fingerprint is looked up when it is written if its key wasn't kept.
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub direction: Direction,
+    pub name: String,
+    pub peer: String,
+    pub addr: SocketAddr,
+    /// The peer's identity key, `None` when it wasn't known.
+    pub key: Option<PublicKey>,
+    pub size: u64,
+    pub hash: [u8; 32],
+    /// Bytes that went over the wire, less than `size` for a delta or a
+    /// transfer that stopped early.
+    pub moved: u64,
+    pub elapsed: Duration,
+    pub outcome: Outcome,
+    pub reason: Option<String>,
+    /// Signed by the receiver of a file sent from here.
+    pub receipt: Option<Receipt>,
+}
+
+impl Record {
+    pub fn of(job: &Job, hash: [u8; 32], outcome: Outcome, reason: Option<String>) -> Self {
+        Record {
+            direction: job.direction,
+            name: job.name.clone(),
+            peer: job.peer.clone(),
+            addr: job.addr,
+            key: job.key,
+            size: job.total,
+            hash,
+            moved: job.done(),
+            elapsed: job.started.elapsed(),
+            outcome,
+            reason,
+            receipt: None,
+        }
+    }
+
+    /// An offer that was turned down, nothing moved.
+    pub fn rejected(
+        direction: Direction,
+        name: String,
+        peer: String,
+        addr: SocketAddr,
+        size: u64,
+        hash: [u8; 32],
+        reason: Option<String>,
+    ) -> Self {
+        Record {
+            direction,
+            name,
+            peer,
+            addr,
+            key: None,
+            size,
+            hash,
+            moved: 0,
+            elapsed: Duration::ZERO,
+            outcome: Outcome::Rejected,
+            reason,
+            receipt: None,
+        }
+    }
+}
+
+/// One line of `history.jsonl`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub id: u64,
+    /// Unix seconds when the transfer ended.
+    pub time: i64,
+    pub peer: String,
+    /// `None` when the peer wasn't connected with a key.
+    pub fingerprint: Option<String>,
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    pub name: String,
+    pub size: u64,
+    /// BLAKE3 root of the content, hex.
+    pub hash: String,
+    pub moved: u64,
+    /// Seconds.
+    pub duration: f64,
+    /// Average bytes per second of `moved`.
+    pub throughput: u64,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Base58 encoded, see `Receipt::encode`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<String>,
+}
+
+impl Entry {
+    fn new(id: u64, record: Record, fingerprint: Option<String>) -> Self {
+        let duration = record.elapsed.as_secs_f64();
+        let throughput = if duration > 0.0 {
+            (record.moved as f64 / duration) as u64
+        } else {
+            0
+        };
+        Entry {
+            id,
+            time: Local::now().timestamp(),
+            peer: record.peer,
+            fingerprint,
+            addr: record.addr,
+            direction: record.direction,
+            name: record.name,
+            size: record.size,
+            hash: blake3::Hash::from(record.hash).to_hex().to_string(),
+            moved: record.moved,
+            duration,
+            throughput,
+            outcome: record.outcome,
+            reason: record.reason,
+            receipt: record.receipt.map(|r| r.encode()),
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = Local
+            .timestamp_opt(self.time, 0)
+            .single()
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let (arrow, side) = match self.direction {
+            Direction::Send => ("->", "to"),
+            Direction::Receive => ("<-", "from"),
+        };
+        write!(
+            f,
+            "{:>4} {} {} {} {} {}",
+            self.id, time, arrow, self.name, side, self.peer
+        )?;
+        if let Some(fp) = &self.fingerprint {
+            write!(f, " ({})", fp)?;
+        }
+        write!(f, "  {}", HumanBytes(self.size))?;
+        if self.moved > 0 {
+            write!(
+                f,
+                " in {:.1}s ({}/s)",
+                self.duration,
+                HumanBytes(self.throughput)
+            )?;
+        }
+        write!(f, "  {}", self.outcome)?;
+        if let Some(reason) = &self.reason {
+            write!(f, ", {}", reason)?;
+        }
+        if self.receipt.is_some() {
+            write!(f, ", receipt:{}", self.id)?;
+        }
+        write!(f, "\n       blake3: {}", self.hash)
+    }
+}
+
+/// What `history:` keeps, every word narrows it down.
+#[derive(Default)]
+struct Filter {
+    direction: Option<Direction>,
+    outcome: Option<Outcome>,
+    /// Peer name or fingerprint prefix.
+    peer: Option<String>,
+    /// Part of the file name.
+    name: Option<String>,
+    count: Option<usize>,
+}
+
+impl Filter {
+    fn parse(arg: &str) -> Self {
+        let mut filter = Filter::default();
+        for word in arg.split_whitespace() {
+            match word {
+                "sent" => filter.direction = Some(Direction::Send),
+                "received" => filter.direction = Some(Direction::Receive),
+                "verified" => filter.outcome = Some(Outcome::Verified),
+                "failed" => filter.outcome = Some(Outcome::Failed),
+                "rejected" => filter.outcome = Some(Outcome::Rejected),
+                "cancelled" => filter.outcome = Some(Outcome::Cancelled),
+                "all" => filter.count = Some(usize::MAX),
+                _ => match (word.strip_prefix('@'), word.parse()) {
+                    (Some(peer), _) => filter.peer = Some(peer.to_string()),
+                    (None, Ok(count)) => filter.count = Some(count),
+                    (None, Err(_)) => filter.name = Some(word.to_lowercase()),
+                },
+            }
+        }
+        filter
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        self.direction.is_none_or(|d| d == entry.direction)
+            && self.outcome.is_none_or(|o| o == entry.outcome)
+            && self.peer.as_ref().is_none_or(|p| {
+                entry.peer.eq_ignore_ascii_case(p)
+                    || (p.len() >= 4 && entry.fingerprint.as_ref().is_some_and(|fp| fp.starts_with(p.as_str())))
+            })
+            && self.name.as_ref().is_none_or(|n| entry.name.to_lowercase().contains(n))
+    }
+}
+
+/// Append-only log of every transfer in `history.jsonl` next to the config,
+/// one JSON object per line so it can be read and audited with other tools.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    next_id: u64,
+}
+
+impl History {
+    fn path() -> io::Result<PathBuf> {
+        config::state_path(HISTORY_FILE)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))
+    }
+
+    /// Reads every entry, lines that don't parse are skipped.
+    pub fn entries() -> io::Result<Vec<Entry>> {
+        let text = match fs::read_to_string(Self::path()?) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(text
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    pub fn load() -> io::Result<Self> {
+        let next_id = Self::entries()?.iter().map(|e| e.id).max().map_or(1, |id| id + 1);
+        Ok(History { next_id })
+    }
+
+    /// Appends a transfer, returns its id.
+    pub fn append(&mut self, record: Record, fingerprint: Option<String>) -> io::Result<u64> {
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let entry = Entry::new(self.next_id.max(1), record, fingerprint);
+        let mut line = serde_json::to_string(&entry)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        line.push('\n');
+        // One write per line keeps concurrent appends from interleaving
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(line.as_bytes())?;
+        self.next_id = entry.id + 1;
+        Ok(entry.id)
+    }
+
+    /// Prints the latest entries matching the words of `history:`.
+    pub fn display(arg: &str) {
+        let entries = match Self::entries() {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("Error reading the history, {}", e);
+                return;
+            }
+        };
+        let filter = Filter::parse(arg);
+        let matching: Vec<&Entry> = entries.iter().filter(|e| filter.matches(e)).collect();
+        if matching.is_empty() {
+            println!("No transfers in the history");
+            return;
+        }
+        let count = filter.count.unwrap_or(DEFAULT_SHOWN);
+        let skipped = matching.len().saturating_sub(count);
+        if skipped > 0 {
+            println!("{} older transfers not shown, add 'all' or a count", skipped);
+        }
+        for entry in matching.iter().skip(skipped) {
+            println!("{}", entry);
+        }
+    }
+
+    /// Prints the receipt kept with a history entry, or one pasted as text,
+    /// and checks its signature. Needs no connection.
+    pub fn display_receipt(arg: &str) {
+        let arg = arg.trim();
+        let (receipt, entry) = match arg.parse::<u64>() {
+            Ok(id) => {
+                let entry = match Self::entries() {
+                    Ok(entries) => entries.into_iter().find(|e| e.id == id),
+                    Err(e) => {
+                        println!("Error reading the history, {}", e);
+                        return;
+                    }
+                };
+                match entry.as_ref().and_then(|e| e.receipt.as_deref()) {
+                    Some(text) => (Receipt::decode(text), entry.clone()),
+                    None => {
+                        println!("No receipt for transfer {} in the history", id);
+                        return;
+                    }
+                }
+            }
+            Err(_) if arg.is_empty() => {
+                println!("Usage: receipt:<history id> or receipt:<receipt text>");
+                return;
+            }
+            Err(_) => (Receipt::decode(arg), None),
+        };
+        let Some(receipt) = receipt else {
+            println!("Not a readable receipt");
+            return;
+        };
+        println!("Delivery receipt\n{}", receipt);
+        if let Some(entry) = entry {
+            let matches = entry.hash == blake3::Hash::from(receipt.hash).to_hex().as_str() && entry.size == receipt.size;
+            if !matches {
+                println!("  The receipt doesn't match history entry {}", entry.id);
+            }
+            println!("  text:      {}", entry.receipt.unwrap_or_default());
+        }
+    }
+}
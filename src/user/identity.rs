@@ -0,0 +1,104 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, io, path::PathBuf};
+
+use crate::config::Config;
+
+const KEY_FILE: &str = "identity.key";
+
+pub type PublicKey = [u8; 32];
+
+/// Long lived signing key of this user, kept next to the config file.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Identity {
+    secret: [u8; 32],
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({})", fingerprint(&self.public()))
+    }
+}
+
+impl Identity {
+    fn path() -> Option<PathBuf> {
+        Config::path().and_then(|p| p.parent().map(|d| d.join(KEY_FILE)))
+    }
+
+    pub fn load_or_create() -> io::Result<Self> {
+        let path = Self::path()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let secret = <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "identity key is corrupt")
+                })?;
+                Ok(Identity { secret })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Identity {
+                    secret: rand::random(),
+                };
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                write_private(&path, &identity.secret)?;
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Used when the key file can't be read, peers will see a new fingerprint each run.
+    pub fn ephemeral() -> Self {
+        Identity {
+            secret: rand::random(),
+        }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        SigningKey::from_bytes(&self.secret)
+            .verifying_key()
+            .to_bytes()
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        SigningKey::from_bytes(&self.secret)
+            .sign(msg)
+            .to_bytes()
+            .to_vec()
+    }
+}
+
+pub fn verify(key: &PublicKey, msg: &[u8], sig: &[u8]) -> bool {
+    let sig = match <[u8; 64]>::try_from(sig) {
+        Ok(s) => Signature::from_bytes(&s),
+        Err(_) => return false,
+    };
+    match VerifyingKey::from_bytes(key) {
+        Ok(key) => key.verify(msg, &sig).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Short form of a public key shown to users, base58 like addresses.
+pub fn fingerprint(key: &PublicKey) -> String {
+    bs58::encode(&blake3::hash(key).as_bytes()[..8]).into_string()
+}
+
+#[cfg(unix)]
+fn write_private(path: &PathBuf, bytes: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(bytes)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &PathBuf, bytes: &[u8]) -> io::Result<()> {
+    fs::write(path, bytes)
+}
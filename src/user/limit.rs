@@ -0,0 +1,330 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::config::{LimitConfig, Rate, RateLimit};
+
+use super::peer::Peer;
+use super::transfers::Direction;
+
+/// Data a bucket may send at once after being idle, as time at its rate.
+const BURST: Duration = Duration::from_millis(250);
+
+/// Token bucket, unlimited without a rate. Taking more than it holds
+/// leaves it in debt, which is waited off before the next send.
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<Rate>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<Rate>) -> Self {
+        Bucket {
+            rate,
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(Rate(rate)) = self.rate {
+            let burst = rate as f64 * BURST.as_secs_f64();
+            let earned = now.duration_since(self.last).as_secs_f64() * rate as f64;
+            self.tokens = (self.tokens + earned).min(burst);
+        }
+        self.last = now;
+    }
+
+    /// Savings are dropped, debt carries over to the new rate unless the
+    /// cap is lifted.
+    fn set_rate(&mut self, rate: Option<Rate>) {
+        self.refill(Instant::now());
+        self.rate = rate;
+        self.tokens = if rate.is_some() { self.tokens.min(0.0) } else { 0.0 };
+    }
+
+    fn take(&mut self, n: u64) {
+        self.refill(Instant::now());
+        if self.rate.is_some() {
+            self.tokens -= n as f64;
+        }
+    }
+
+    /// Time until the debt is paid off.
+    fn wait(&mut self) -> Duration {
+        self.refill(Instant::now());
+        match self.rate {
+            Some(Rate(rate)) if self.tokens < 0.0 => Duration::from_secs_f64(-self.tokens / rate as f64),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pair {
+    upload: Bucket,
+    download: Bucket,
+}
+
+impl Pair {
+    fn new(limit: RateLimit) -> Self {
+        Pair {
+            upload: Bucket::new(limit.upload),
+            download: Bucket::new(limit.download),
+        }
+    }
+
+    fn get(&mut self, direction: Direction) -> &mut Bucket {
+        match direction {
+            Direction::Send => &mut self.upload,
+            Direction::Receive => &mut self.download,
+        }
+    }
+
+    fn limit(&self) -> RateLimit {
+        RateLimit {
+            upload: self.upload.rate,
+            download: self.download.rate,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    all: Pair,
+    /// By the peer fingerprint or key from the config. Never by name, a
+    /// peer could rename itself out of its cap.
+    peers: BTreeMap<String, Pair>,
+}
+
+/// Rate limits shared by every transfer, kept in step with the config.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::new(&LimitConfig::default())
+    }
+}
+
+impl Limits {
+    pub fn new(config: &LimitConfig) -> Self {
+        let all = Pair::new(RateLimit {
+            upload: config.upload,
+            download: config.download,
+        });
+        let peers = config
+            .peers
+            .iter()
+            .map(|(selector, limit)| (selector.clone(), Pair::new(*limit)))
+            .collect();
+        Limits {
+            buckets: Arc::new(Mutex::new(Buckets { all, peers })),
+        }
+    }
+
+    /// Changes a limit of all transfers, or of one peer's, running ones included.
+    pub fn set(&self, selector: Option<&str>, direction: Direction, rate: Option<Rate>) {
+        let mut buckets = self.buckets.lock().expect("limits poisoned");
+        match selector {
+            None => buckets.all.get(direction).set_rate(rate),
+            Some(selector) => {
+                let pair = buckets
+                    .peers
+                    .entry(selector.to_string())
+                    .or_insert_with(|| Pair::new(RateLimit::default()));
+                pair.get(direction).set_rate(rate);
+                if pair.limit() == RateLimit::default() {
+                    buckets.peers.remove(selector);
+                }
+            }
+        }
+    }
+
+    /// Throttle for a transfer with `peer`, `None` when it isn't connected.
+    pub fn throttle(&self, peer: Option<Peer>, direction: Direction) -> Throttle {
+        Throttle {
+            limits: Some(self.clone()),
+            peer,
+            direction,
+        }
+    }
+}
+
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buckets = self.buckets.lock().expect("limits poisoned");
+        let show = |rate: Option<Rate>| match rate {
+            Some(rate) => format!("{}/s", rate),
+            None => "unlimited".to_string(),
+        };
+        let all = buckets.all.limit();
+        write!(f, "all peers -> up {}, down {}", show(all.upload), show(all.download))?;
+        for (selector, pair) in buckets.peers.iter() {
+            let limit = pair.limit();
+            write!(f, "\n{} -> up {}, down {}", selector, show(limit.upload), show(limit.download))?;
+        }
+        Ok(())
+    }
+}
+
+/// Holds back the file data of one transfer to the global cap and the cap
+/// of its peer, whichever is lower.
+#[derive(Debug)]
+pub struct Throttle {
+    limits: Option<Limits>,
+    peer: Option<Peer>,
+    direction: Direction,
+}
+
+impl Throttle {
+    /// A throttle that never waits.
+    pub fn unlimited(direction: Direction) -> Self {
+        Throttle {
+            limits: None,
+            peer: None,
+            direction,
+        }
+    }
+
+    /// Counts `n` bytes of file data against every bucket that applies.
+    pub fn consume(&self, n: u64) {
+        self.each(|bucket| bucket.take(n));
+    }
+
+    /// Waits until every bucket that applies is out of debt.
+    pub async fn ready(&self) {
+        loop {
+            let mut wait = Duration::ZERO;
+            self.each(|bucket| wait = wait.max(bucket.wait()));
+            if wait.is_zero() {
+                return;
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub async fn take(&self, n: u64) {
+        self.consume(n);
+        self.ready().await;
+    }
+
+    fn each(&self, mut f: impl FnMut(&mut Bucket)) {
+        let Some(limits) = &self.limits else { return };
+        let mut buckets = limits.buckets.lock().expect("limits poisoned");
+        f(buckets.all.get(self.direction));
+        if let Some(peer) = &self.peer {
+            for (selector, pair) in buckets.peers.iter_mut() {
+                if peer.is_identity(selector) {
+                    f(pair.get(self.direction));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = 1000;
+
+    fn secs(wait: Duration) -> f64 {
+        (wait.as_secs_f64() * 1000.0).round() / 1000.0
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn debt_is_waited_off_at_the_rate() {
+        let mut bucket = Bucket::new(Some(Rate(RATE)));
+        bucket.take(RATE);
+        assert_eq!(secs(bucket.wait()), 1.0);
+        tokio::time::advance(Duration::from_millis(400)).await;
+        assert_eq!(secs(bucket.wait()), 0.6);
+        tokio::time::advance(Duration::from_millis(600)).await;
+        assert_eq!(bucket.wait(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_time_saves_up_one_burst() {
+        let mut bucket = Bucket::new(Some(Rate(RATE)));
+        tokio::time::advance(Duration::from_secs(60)).await;
+        let burst = (RATE as f64 * BURST.as_secs_f64()) as u64;
+        bucket.take(burst);
+        assert_eq!(bucket.wait(), Duration::ZERO);
+        bucket.take(RATE);
+        assert_eq!(secs(bucket.wait()), 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_changes_apply_to_the_debt() {
+        let mut bucket = Bucket::new(Some(Rate(RATE)));
+        bucket.take(RATE);
+        bucket.set_rate(Some(Rate(2 * RATE)));
+        assert_eq!(secs(bucket.wait()), 0.5);
+        // Lifting the cap forgives the debt
+        bucket.set_rate(None);
+        assert_eq!(bucket.wait(), Duration::ZERO);
+        bucket.take(RATE);
+        // Capped again, without savings from the unlimited time
+        tokio::time::advance(Duration::from_secs(60)).await;
+        bucket.set_rate(Some(Rate(RATE)));
+        bucket.take(RATE);
+        assert_eq!(secs(bucket.wait()), 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttle_holds_to_the_lower_cap() {
+        let peer = Peer::new("alice".to_string(), "127.0.0.1:5000".parse().unwrap(), [7; 32]);
+        let mut config = LimitConfig {
+            upload: Some(Rate(4 * RATE)),
+            ..LimitConfig::default()
+        };
+        let capped = RateLimit {
+            upload: Some(Rate(RATE)),
+            download: None,
+        };
+        config.peers.insert(peer.fingerprint(), capped);
+        let limits = Limits::new(&config);
+
+        let start = Instant::now();
+        limits.throttle(Some(peer.clone()), Direction::Send).take(RATE).await;
+        assert_eq!(secs(start.elapsed()), 1.0);
+        // Anyone else only has the global cap
+        let limits = Limits::new(&config);
+        let start = Instant::now();
+        limits.throttle(None, Direction::Send).take(RATE).await;
+        assert_eq!(secs(start.elapsed()), 0.25);
+        // Downloads from the peer are not capped
+        let start = Instant::now();
+        limits.throttle(Some(peer), Direction::Receive).take(RATE).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_caps_follow_the_key_not_the_name() {
+        let mut config = LimitConfig::default();
+        let capped = RateLimit {
+            upload: Some(Rate(RATE)),
+            download: None,
+        };
+        config.peers.insert("alice".to_string(), capped);
+        let limits = Limits::new(&config);
+        let alice = Peer::new("alice".to_string(), "127.0.0.1:5000".parse().unwrap(), [7; 32]);
+        let start = Instant::now();
+        limits.throttle(Some(alice.clone()), Direction::Send).take(RATE).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        limits.set(Some(&alice.fingerprint()), Direction::Send, Some(Rate(RATE)));
+        let renamed = Peer::new("mallory".to_string(), alice.get_addr(), [7; 32]);
+        limits.throttle(Some(renamed), Direction::Send).take(RATE).await;
+        assert_eq!(secs(start.elapsed()), 1.0);
+    }
+}
@@ -1,15 +1,36 @@
 mod command;
+pub use command::handle_want;
+pub mod complete;
+pub mod identity;
+pub mod history;
+pub mod limit;
+pub mod outbox;
 pub mod peer;
+pub mod pipe;
+mod shares;
+pub use shares::{handle_browse, handle_get};
+pub mod sync;
+pub mod transfers;
 
 
-use crate::ReceiverAck;
+use crate::config::{Config, Rate, ShareConfig};
+use crate::routes::Routes;
 
+use super::packet::room::{derive_room_key, Handshakes, RoomKey};
+use super::packet::transfer::{TransferPacket, TransferState};
 use super::packet::Packet;
 use command::Command;
+use identity::{fingerprint, Identity, PublicKey};
+use history::{History, Record};
+use limit::{Limits, Throttle};
+use outbox::{Item, Outbox};
 use peer::Peer;
+use sync::SyncDb;
+use transfers::{Direction, Shared, Transfers};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::{collections::HashSet, net::SocketAddr};
 use tokio::net::UdpSocket;
@@ -22,16 +43,62 @@ pub struct User {
     ip_to_peer: HashMap<SocketAddr, Peer>,
     chat_on: bool,
     res: bool,
+    room: Option<RoomKey>,
+    #[serde(skip)]
+    pub room_pending: Handshakes,
+    /// Nonce of the binding packet sent to each address that waits for an
+    /// answer, with the key that has to sign it when it is known.
+    #[serde(skip)]
+    binds: HashMap<SocketAddr, (u64, Option<PublicKey>)>,
+    config: Config,
+    identity: Identity,
+    #[serde(skip)]
+    pub transfers: Transfers,
+    #[serde(skip)]
+    pub shared: HashMap<[u8; 32], Shared>,
+    /// Ids of `get:` requests waiting for an offer, with the peer asked
+    /// and where a synced file goes.
+    #[serde(skip)]
+    pub gets: HashMap<u64, (SocketAddr, Option<PathBuf>)>,
+    #[serde(skip)]
+    pub sync: SyncDb,
+    #[serde(skip)]
+    pub outbox: Outbox,
+    #[serde(skip)]
+    pub limits: Limits,
+    #[serde(skip)]
+    pub history: History,
 }
 
 impl User {
-    pub fn new(name: String) -> Self {
+    pub fn new(
+        name: String,
+        config: Config,
+        identity: Identity,
+        sync: SyncDb,
+        outbox: Outbox,
+        history: History,
+    ) -> Self {
+        let transfers = Transfers::new(config.max_transfers);
+        let limits = Limits::new(&config.limits);
         let user = User {
             name,
             connected: HashSet::new(),
             ip_to_peer: HashMap::new(),
             chat_on: false,
             res: false,
+            room: None,
+            room_pending: Handshakes::default(),
+            binds: HashMap::new(),
+            config,
+            identity,
+            transfers,
+            shared: HashMap::new(),
+            gets: HashMap::new(),
+            sync,
+            outbox,
+            limits,
+            history,
         };
         user
     }
@@ -54,12 +121,57 @@ impl User {
         !v
     }
 
+    pub fn set_room(&mut self, key: Option<RoomKey>) {
+        self.room = key;
+        self.room_pending.clear();
+    }
+
+    pub fn room_key(&self) -> Option<RoomKey> {
+        self.room
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn set_download_dir(&mut self, dir: PathBuf) -> std::io::Result<()> {
+        self.config.download_dir = dir;
+        self.config.save()
+    }
+
     pub fn get_name(&self) -> String {
         self.name.clone()
     }
 
-    pub fn add_peer(&mut self, addr: SocketAddr, name: String) {
-        let peer = Peer::new(name, addr);
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// A fresh nonce for a binding packet to `addr` that waits for an answer.
+    pub fn bind_nonce(&mut self, addr: SocketAddr, key: Option<PublicKey>) -> u64 {
+        let nonce = rand::random::<u64>().max(1);
+        self.binds.insert(addr, (nonce, key));
+        nonce
+    }
+
+    /// Takes the nonce sent to `addr` if `echo` answers it, signed by `key`.
+    pub fn take_bind(&mut self, addr: &SocketAddr, echo: u64, key: &PublicKey) -> Result<(), &'static str> {
+        match self.binds.get(addr) {
+            None => return Err("nothing was sent there that waits for an answer"),
+            Some((nonce, _)) if *nonce != echo => return Err("it doesn't answer the latest request sent there"),
+            Some((_, Some(expected))) if expected != key => return Err("it is signed by another key than the request"),
+            Some(_) => {}
+        }
+        self.binds.remove(addr);
+        Ok(())
+    }
+
+    pub fn add_peer(&mut self, addr: SocketAddr, name: String, key: PublicKey) {
+        if let Some(old) = self.ip_to_peer.remove(&addr) {
+            self.connected.remove(&old);
+        }
+        let peer = Peer::new(name, addr, key);
+        self.remember(&peer);
         self.connected.insert(peer.clone());
         self.ip_to_peer.insert(addr, peer);
     }
@@ -70,20 +182,336 @@ impl User {
         self.ip_to_peer.remove(&addr);
     }
 
+    pub fn connected_addrs(&self) -> Vec<SocketAddr> {
+        self.connected.iter().map(|p| p.get_addr()).collect()
+    }
+
+    pub fn share(&mut self, hash: [u8; 32], shared: Shared) {
+        self.shared.insert(hash, shared);
+    }
+
+    /// Rate limits for a transfer with the peer at `addr`.
+    pub fn throttle(&self, addr: &SocketAddr, direction: Direction) -> Throttle {
+        self.limits.throttle(self.ip_to_peer.get(addr).cloned(), direction)
+    }
+
+    /// Sets an upload or download cap, for everyone or for one peer, kept
+    /// by its fingerprint. Running transfers follow right away.
+    pub fn set_limit(
+        &mut self,
+        selector: Option<&str>,
+        direction: Direction,
+        rate: Option<Rate>,
+    ) -> Result<std::io::Result<()>, String> {
+        let selector = match selector {
+            // An entry already in the config can always be changed or lifted
+            Some(selector) if self.config.limits.peers.contains_key(selector) => Some(selector.to_string()),
+            Some(selector) => Some(self.peer_entry(selector)?),
+            None => None,
+        };
+        let selector = selector.as_deref();
+        self.limits.set(selector, direction, rate);
+        let limits = &mut self.config.limits;
+        let slot = match selector {
+            None => match direction {
+                Direction::Send => &mut limits.upload,
+                Direction::Receive => &mut limits.download,
+            },
+            Some(selector) => {
+                let peer = limits.peers.entry(selector.to_string()).or_default();
+                match direction {
+                    Direction::Send => &mut peer.upload,
+                    Direction::Receive => &mut peer.download,
+                }
+            }
+        };
+        *slot = rate;
+        limits.peers.retain(|_, limit| limit.upload.is_some() || limit.download.is_some());
+        Ok(self.config.save())
+    }
+
+    /// Adds a finished transfer to the history, with the fingerprint of
+    /// the key the peer had when the transfer started. A receipt is kept
+    /// only when that key signed it for this content and for us.
+    pub fn record(&mut self, mut record: Record) {
+        if record.key.is_none() {
+            record.key = self.peer_key(&record.addr);
+        }
+        let fingerprint = record.key.as_ref().map(fingerprint);
+        if let Some(receipt) = record.receipt.take() {
+            let valid = record
+                .key
+                .is_some_and(|key| receipt.confirms(&self.identity.public(), &key, record.hash, record.size));
+            if valid {
+                record.receipt = Some(receipt);
+            } else {
+                println!("{} sent a receipt for {} that doesn't verify, not kept", record.peer, record.name);
+            }
+        }
+        let (name, peer, receipt) = (record.name.clone(), record.peer.clone(), record.receipt.is_some());
+        match self.history.append(record, fingerprint) {
+            Ok(id) if receipt => println!("Signed receipt from {} saved, show it with receipt:{}", peer, id),
+            Ok(_) => {}
+            Err(e) => println!("Error adding {} to the history, {}", name, e),
+        }
+    }
+
+    pub fn peer_key(&self, addr: &SocketAddr) -> Option<PublicKey> {
+        self.ip_to_peer.get(addr).map(|p| p.get_key())
+    }
+
+    pub fn peer_name(&self, addr: &SocketAddr) -> Option<String> {
+        self.ip_to_peer.get(addr).map(|p| p.get_name().to_string())
+    }
+
     fn display_members(&self) {
         if self.connected.is_empty() {
             println!("No Peer Connected");
         }
         for peer in self.connected.iter() {
-            println!("{} -> Port: {}", peer.get_name(), peer.get_port());
+            println!(
+                "{} -> Port: {}, Fingerprint: {}",
+                peer.get_name(),
+                peer.get_port(),
+                peer.fingerprint()
+            );
         }
     }
 
+    /// Answers aren't waited for, the program is leaving.
     pub async fn disconnect_all(&self, socket: &UdpSocket) {
         for i in self.connected.iter() {
             let dis = Command::Disconnect(i.get_addr());
-            dis.handle_disconnect(&socket, self.get_name()).await;
+            dis.handle_disconnect(&socket, self, rand::random()).await;
+        }
+    }
+
+    /// Resolves a comma separated list of peer names, fingerprint prefixes
+    /// and group names. Offline group members are left out.
+    pub fn select_peers(&self, spec: &str) -> Result<Vec<Peer>, String> {
+        let mut selected: Vec<Peer> = Vec::new();
+        for token in spec.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let (members, is_group) = match self.config.groups.get(token) {
+                Some(m) => (m.clone(), true),
+                None => (vec![token.to_string()], false),
+            };
+            for member in members {
+                let found: Vec<&Peer> = self.connected.iter().filter(|p| p.matches(&member)).collect();
+                if found.is_empty() && !is_group {
+                    return Err(format!("No connected peer matches {}", member));
+                }
+                for peer in found {
+                    if !selected.contains(peer) {
+                        selected.push(peer.clone());
+                    }
+                }
+            }
+        }
+        if selected.is_empty() {
+            return Err("No connected peer selected".to_string());
+        }
+        Ok(selected)
+    }
+
+    async fn send_chat(&self, socket: &UdpSocket, peers: &[Peer], msg: String) {
+        let chat = Packet::chat_packet(self.get_name(), msg);
+        for peer in peers {
+            if let Err(e) = chat.send_packet(socket, &peer.get_addr()).await {
+                println!("Error sending {}, {}", peer.get_name(), e);
+            }
+        }
+    }
+
+    pub fn set_group(&mut self, name: &str, members: Option<Vec<String>>) -> std::io::Result<()> {
+        match members {
+            Some(m) => self.config.groups.insert(name.to_string(), m),
+            None => self.config.groups.remove(name),
+        };
+        self.config.save()
+    }
+
+    fn display_groups(&self) {
+        if self.config.groups.is_empty() {
+            println!("No groups, create one with group:<name> <peer>,<peer>");
+        }
+        for (name, members) in self.config.groups.iter() {
+            println!("{}: {}", name, members.join(", "));
+        }
+    }
+
+    pub fn set_share(&mut self, name: &str, path: Option<PathBuf>) -> std::io::Result<()> {
+        match path {
+            Some(path) => {
+                let share = self.config.shares.entry(name.to_string()).or_insert(ShareConfig {
+                    path: path.clone(),
+                    public: false,
+                    peers: Vec::new(),
+                });
+                share.path = path;
+            }
+            None => {
+                self.config.shares.remove(name);
+            }
+        }
+        self.config.save()
+    }
+
+    /// `*` makes a share public, `-` closes it to everyone, anything else
+    /// is the list of peers and groups that may browse it.
+    fn allow_share(&mut self, name: &str, spec: &str) -> Result<std::io::Result<()>, String> {
+        if !self.config.shares.contains_key(name) {
+            return Err(format!("No share named {}", name));
+        }
+        let (public, peers) = match spec {
+            "*" => (true, None),
+            "-" => (false, Some(Vec::new())),
+            peers => {
+                let mut allowed = Vec::new();
+                for entry in peers.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                    allowed.push(self.share_entry(entry)?);
+                }
+                (false, Some(allowed))
+            }
+        };
+        if let Some(share) = self.config.shares.get_mut(name) {
+            share.public = public;
+            if let Some(peers) = peers {
+                share.peers = peers;
+            }
+        }
+        Ok(self.config.save())
+    }
+
+    /// What `allow:` stores for one entry. Groups stay by name, a peer is
+    /// stored by its fingerprint so only its key grants access.
+    fn share_entry(&self, entry: &str) -> Result<String, String> {
+        if self.config.groups.contains_key(entry) {
+            return Ok(entry.to_string());
+        }
+        self.peer_entry(entry)
+    }
+
+    /// Fingerprint of the connected peer `entry` names, or `entry` itself
+    /// when it is a fingerprint or key, for settings that must follow the
+    /// peer's key rather than the name it picked.
+    fn peer_entry(&self, entry: &str) -> Result<String, String> {
+        if let Some(peer) = self.connected.iter().find(|p| p.is_identity(entry)) {
+            return Ok(peer.fingerprint());
+        }
+        let found: Vec<&Peer> = self.connected.iter().filter(|p| p.matches(entry)).collect();
+        match found.as_slice() {
+            [peer] => Ok(peer.fingerprint()),
+            [] if entry.len() >= 8 && bs58::decode(entry).into_vec().is_ok() => Ok(entry.to_string()),
+            [] => Err(format!("No connected peer matches {}, give its fingerprint", entry)),
+            _ => Err(format!("{} matches more than one peer, give a fingerprint", entry)),
+        }
+    }
+
+    fn display_shares(&self) {
+        if self.config.shares.is_empty() {
+            println!("No shared folders, add one with share:<name> <path>");
+        }
+        for (name, share) in self.config.shares.iter() {
+            let access = if share.public {
+                "public".to_string()
+            } else if share.peers.is_empty() {
+                "nobody".to_string()
+            } else {
+                let label = |entry: &String| match self.connected.iter().find(|p| p.is_identity(entry)) {
+                    Some(peer) => format!("{} ({})", peer.get_name(), entry),
+                    None => entry.clone(),
+                };
+                share.peers.iter().map(label).collect::<Vec<_>>().join(", ")
+            };
+            println!("{} -> {} ({})", name, share.path.display(), access);
+        }
+    }
+
+    /// Shares the peer at `addr` may browse, with whether they are public.
+    /// Private shares list peers by fingerprint or key, group members too.
+    pub fn visible_shares(&self, addr: &SocketAddr) -> Vec<(String, ShareConfig)> {
+        let peer = match self.ip_to_peer.get(addr) {
+            Some(peer) => peer,
+            None => return Vec::new(),
+        };
+        let listed = |entry: &String| match self.config.groups.get(entry) {
+            Some(members) => members.iter().any(|m| peer.is_identity(m)),
+            None => peer.is_identity(entry),
+        };
+        self.config
+            .shares
+            .iter()
+            .filter(|(_, share)| share.public || share.peers.iter().any(listed))
+            .map(|(name, share)| (name.clone(), share.clone()))
+            .collect()
+    }
+
+    /// The one connected peer `spec` selects.
+    fn select_peer(&self, spec: &str) -> Result<Peer, String> {
+        let mut peers = self.select_peers(spec)?;
+        if peers.len() > 1 {
+            return Err(format!("{} selects more than one peer", spec));
+        }
+        Ok(peers.remove(0))
+    }
+
+    /// Applies a pause, resume or cancel from the command line and tells the peer.
+    async fn control_transfer(
+        &self,
+        socket: &UdpSocket,
+        user_lock: &Mutex<User>,
+        id: &str,
+        state: TransferState,
+    ) {
+        let id = match id.trim().parse::<u32>() {
+            Ok(id) => id,
+            Err(_) => {
+                println!("Invalid transfer id {:?}, see 'transfers:'", id.trim());
+                return;
+            }
+        };
+        let transfer = match user_lock.lock().await.transfers.get(id) {
+            Some(t) => t.clone(),
+            None => {
+                println!("No transfer {}", id);
+                return;
+            }
+        };
+        let current = transfer.state();
+        let allowed = match state {
+            TransferState::Paused => matches!(current, TransferState::Running | TransferState::Queued),
+            TransferState::Running => current == TransferState::Paused,
+            _ => current != TransferState::Cancelled,
+        };
+        if !allowed {
+            println!("Transfer {} is {}", id, current);
+            return;
         }
+        transfer.set_state(state);
+        let packet = Packet::create_transfer_packet(transfer.wire, state);
+        if let Err(e) = packet.send_packet(socket, &transfer.addr).await {
+            println!("Error sending transfer state to {}, {}", transfer.peer, e);
+        }
+        println!("Transfer {} {}", id, state);
+    }
+
+    /// Applies a state change sent by the other side of a transfer.
+    pub fn remote_transfer(&self, addr: SocketAddr, packet: &TransferPacket) {
+        if let Some((id, transfer)) = self.transfers.find(addr, packet.id) {
+            if transfer.state() != packet.state {
+                transfer.set_state(packet.state);
+                println!("Transfer {} {} by {}", id, packet.state, transfer.peer);
+            }
+        }
+    }
+
+    /// Names offered by tab completion.
+    pub fn completions(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.connected.iter().map(|p| p.get_name().to_string()).collect();
+        names.extend(self.config.groups.keys().cloned());
+        names.sort();
+        names.dedup();
+        names
     }
 
     pub async fn handle_input(
@@ -91,14 +519,35 @@ impl User {
         socket: Arc<UdpSocket>,
         buf: String,
         user_lock: Arc<Mutex<User>>,
-        ack_rx: ReceiverAck,
+        routes: Routes,
     ) {
+        // Chat lines starting with @ only go to the listed peers
+        if self.chat_on {
+            if let (Some(spec), msg) = split_recipients(&buf) {
+                match self.select_recipients(spec) {
+                    Ok((peers, offline)) => {
+                        self.send_chat(&socket, &peers, msg.to_string()).await;
+                        if !offline.is_empty() {
+                            user_lock.lock().await.queue(offline, Item::Chat(msg.trim().to_string()));
+                        }
+                    }
+                    Err(e) => println!("{}", e),
+                }
+                return;
+            }
+        }
+
         let cmd = buf.split_once(":");
         match cmd {
             Some(("con", addrstr)) => {
                 if let Some(addr) = base58_to_addr(addrstr.trim().to_string()) {
                     let cnt = Command::Connect(addr);
-                    cnt.handle_connect(&socket, self.get_name()).await;
+                    if self.room.is_some() {
+                        cnt.handle_room_connect(&socket, user_lock).await;
+                    } else {
+                        let nonce = user_lock.lock().await.bind_nonce(addr, None);
+                        cnt.handle_connect(&socket, self, nonce).await;
+                    }
                 } else {
                     println!("Error parsing the ip addrs")
                 }
@@ -106,13 +555,25 @@ impl User {
             Some(("dis", name)) => {
                 for i in self.connected.iter() {
                     if i.get_name().to_lowercase() == name.trim().to_lowercase() {
+                        let nonce = user_lock.lock().await.bind_nonce(i.get_addr(), Some(i.get_key()));
                         let dis = Command::Disconnect(i.get_addr());
-                        dis.handle_disconnect(&socket, self.get_name()).await;
+                        dis.handle_disconnect(&socket, self, nonce).await;
                     }
                 }
             }
             Some(("ls", _)) => self.display_members(),
 
+            Some(("room", pass)) => {
+                let pass = pass.trim();
+                if pass.is_empty() {
+                    user_lock.lock().await.set_room(None);
+                    println!("Left the room");
+                    return;
+                }
+                user_lock.lock().await.set_room(Some(derive_room_key(pass)));
+                println!("Room set, peers with the same passphrase join without approval");
+            }
+
             Some(("chat", _)) => {
                 let mut lock = user_lock.lock().await;
                 if lock.toggle_chat() {
@@ -123,6 +584,18 @@ impl User {
             }
 
             Some(("file", arg)) => {
+                let (spec, arg) = split_recipients(arg);
+                let peers = match spec {
+                    Some(spec) => self.select_recipients(spec),
+                    None => Ok((self.connected.iter().cloned().collect(), Vec::new())),
+                };
+                let (peers, offline) = match peers {
+                    Ok(p) => p,
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                };
                 let mut path = String::with_capacity(arg.len());
                 let mut flag = false;
                 let mut escape_char = false;
@@ -143,11 +616,267 @@ impl User {
                     path.push(i);
                 }
                 // println!("{}", path);
-                let cmd = Command::File(path);
-                if let Err(e) = cmd.read_file(socket, self, ack_rx).await {
-                    println!("Error in File handeling, {}", e);
+                if !offline.is_empty() {
+                    match std::fs::canonicalize(&path) {
+                        Ok(full) => user_lock.lock().await.queue(offline, Item::File(full)),
+                        Err(e) => println!("Can't queue {}, {}", path, e),
+                    }
+                    if peers.is_empty() {
+                        return;
+                    }
                 }
+                let cmd = Command::File(path, peers, None);
+                // Runs in the background so the prompt stays usable
+                tokio::spawn(async move {
+                    if let Err(e) = cmd.read_file(socket, user_lock, routes).await {
+                        println!("Error in File handeling, {}", e);
+                    }
+                });
             },
+            Some(("dir", dir)) => {
+                let dir = dir.trim();
+                if dir.is_empty() {
+                    println!("Download dir: {}", self.config.download_dir.display());
+                    return;
+                }
+                let mut lock = user_lock.lock().await;
+                match lock.set_download_dir(PathBuf::from(dir)) {
+                    Ok(_) => println!("Download dir set to {}", dir),
+                    Err(e) => println!("Download dir set for this session, error saving config, {}", e),
+                }
+            }
+            Some(("group", arg)) => {
+                let (name, members) = match arg.trim().split_once(char::is_whitespace) {
+                    Some((name, members)) => (name, members.trim()),
+                    None => (arg.trim(), ""),
+                };
+                let mut lock = user_lock.lock().await;
+                if name.is_empty() {
+                    lock.display_groups();
+                    return;
+                }
+                if members.is_empty() {
+                    match lock.config.groups.get(name) {
+                        Some(m) => println!("{}: {}", name, m.join(", ")),
+                        None => println!("No group named {}", name),
+                    }
+                    return;
+                }
+                let members = if members == "-" {
+                    None
+                } else {
+                    Some(members.split(',').map(|m| m.trim().to_string()).filter(|m| !m.is_empty()).collect())
+                };
+                match lock.set_group(name, members) {
+                    Ok(_) => println!("Group {} saved", name),
+                    Err(e) => println!("Group {} set for this session, error saving config, {}", name, e),
+                }
+            }
+            Some(("share", arg)) => {
+                let (name, path) = match arg.trim().split_once(char::is_whitespace) {
+                    Some((name, path)) => (name, path.trim().trim_matches(['\'', '"'])),
+                    None => (arg.trim(), ""),
+                };
+                let mut lock = user_lock.lock().await;
+                if name.is_empty() {
+                    lock.display_shares();
+                    return;
+                }
+                if path.is_empty() {
+                    println!("Usage: share:<name> <path>, share:<name> - removes it");
+                    return;
+                }
+                let path = if path == "-" {
+                    None
+                } else {
+                    match std::fs::canonicalize(path) {
+                        Ok(p) if p.is_dir() => Some(p),
+                        Ok(_) => {
+                            println!("{} is not a directory", path);
+                            return;
+                        }
+                        Err(e) => {
+                            println!("Error opening {}, {}", path, e);
+                            return;
+                        }
+                    }
+                };
+                match lock.set_share(name, path) {
+                    Ok(_) => println!("Share {} saved", name),
+                    Err(e) => println!("Share {} set for this session, error saving config, {}", name, e),
+                }
+            }
+            Some(("allow", arg)) => {
+                let (name, spec) = match arg.trim().split_once(char::is_whitespace) {
+                    Some((name, spec)) => (name, spec.trim()),
+                    None => (arg.trim(), ""),
+                };
+                if name.is_empty() || spec.is_empty() {
+                    println!("Usage: allow:<share> <peers>, '*' for everyone, '-' for nobody");
+                    return;
+                }
+                match user_lock.lock().await.allow_share(name, spec) {
+                    Ok(Ok(_)) => println!("Access to {} saved", name),
+                    Ok(Err(e)) => println!("Access to {} set for this session, error saving config, {}", name, e),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Some(("browse", arg)) => {
+                let (spec, page) = match arg.trim().split_once(char::is_whitespace) {
+                    Some((spec, page)) => (spec, page.trim()),
+                    None => (arg.trim(), "1"),
+                };
+                let page = match page.parse::<u32>() {
+                    Ok(page) if page > 0 => page,
+                    _ => {
+                        println!("Invalid page {:?}", page);
+                        return;
+                    }
+                };
+                let peer = match self.select_peer(spec) {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                };
+                tokio::spawn(async move {
+                    if let Err(e) = shares::browse(&socket, peer, page, &routes).await {
+                        println!("Error browsing, {}", e);
+                    }
+                });
+            }
+            Some(("get", arg)) => {
+                let (spec, path) = match arg.trim().split_once(char::is_whitespace) {
+                    Some((spec, path)) => (spec, path.trim().trim_matches(['\'', '"'])),
+                    None => (arg.trim(), ""),
+                };
+                if path.is_empty() {
+                    println!("Usage: get:<peer> <share>/<path>");
+                    return;
+                }
+                let peer = match self.select_peer(spec) {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                };
+                let path = path.to_string();
+                tokio::spawn(async move {
+                    if let Err(e) = shares::get(&socket, &user_lock, peer, path, &routes).await {
+                        println!("Error requesting file, {}", e);
+                    }
+                });
+            }
+            Some(("sync", arg)) => {
+                let (spec, dir) = match arg.trim().split_once(char::is_whitespace) {
+                    Some((spec, dir)) => (spec, dir.trim().trim_matches(['\'', '"'])),
+                    None => (arg.trim(), ""),
+                };
+                if spec.is_empty() {
+                    user_lock.lock().await.display_syncs();
+                    return;
+                }
+                if dir.is_empty() {
+                    println!("Usage: sync:<peers> <dir>");
+                    return;
+                }
+                let peers = match self.select_peers(spec) {
+                    Ok(peers) => peers.iter().map(|p| p.fingerprint()).collect(),
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                };
+                let root = match std::fs::canonicalize(dir) {
+                    Ok(p) if p.is_dir() => p,
+                    Ok(_) => {
+                        println!("{} is not a directory", dir);
+                        return;
+                    }
+                    Err(e) => {
+                        println!("Error opening {}, {}", dir, e);
+                        return;
+                    }
+                };
+                // Peers match folders by name, so both sides sync a folder named alike
+                let name = match root.file_name() {
+                    Some(name) => name.to_string_lossy().to_string(),
+                    None => {
+                        println!("Can't sync {}", root.display());
+                        return;
+                    }
+                };
+                match user_lock.lock().await.add_sync(&name, root, peers) {
+                    Ok(true) => sync::start(socket, user_lock.clone(), name),
+                    Ok(false) => println!("Peers of {} updated", name),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Some(("unsync", name)) => {
+                let name = name.trim();
+                if user_lock.lock().await.remove_sync(name) {
+                    println!("Stopped syncing {}", name);
+                } else {
+                    println!("No synced folder named {}", name);
+                }
+            }
+            Some(("outbox", _)) => self.display_outbox(),
+            Some(("unqueue", id)) => match id.trim().parse() {
+                Ok(id) if user_lock.lock().await.unqueue(id) => println!("Removed {} from the outbox", id),
+                Ok(id) => println!("Nothing queued as {}", id),
+                Err(_) => println!("Usage: unqueue:<id>"),
+            },
+            Some(("transfers", _)) => user_lock.lock().await.transfers.display(),
+            Some(("history", arg)) => History::display(arg),
+            Some(("receipt", arg)) => History::display_receipt(arg),
+            Some(("limit", arg)) => {
+                let args: Vec<&str> = arg.split_whitespace().collect();
+                let (selector, direction, rate) = match args.as_slice() {
+                    [] => {
+                        println!("{}", self.limits);
+                        return;
+                    }
+                    [direction, rate] => (None, *direction, *rate),
+                    [peer, direction, rate] => (Some(*peer), *direction, *rate),
+                    _ => {
+                        println!("Usage: limit:[peer] <up|down> <rate>, like 'limit:up 2M', '0' lifts it");
+                        return;
+                    }
+                };
+                let direction = match direction {
+                    "up" => Direction::Send,
+                    "down" => Direction::Receive,
+                    _ => {
+                        println!("Limit 'up' or 'down', not {:?}", direction);
+                        return;
+                    }
+                };
+                let rate = match Rate::parse(rate) {
+                    Ok(rate) => rate,
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                };
+                let shown = rate.map(|r| format!("{}/s", r)).unwrap_or_else(|| "unlimited".to_string());
+                let target = selector.unwrap_or("all peers");
+                match user_lock.lock().await.set_limit(selector, direction, rate) {
+                    Ok(Ok(_)) => println!("Limit for {} set to {}", target, shown),
+                    Ok(Err(e)) => println!("Limit for {} set for this session, error saving config, {}", target, e),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Some(("pause", id)) => {
+                self.control_transfer(&socket, &user_lock, id, TransferState::Paused).await
+            }
+            Some(("resume", id)) => {
+                self.control_transfer(&socket, &user_lock, id, TransferState::Running).await
+            }
+            Some(("cancel", id)) => {
+                self.control_transfer(&socket, &user_lock, id, TransferState::Cancelled).await
+            }
             Some(("help", _)) => handle_help(),
 
             Some(_) => println!("Not ImpleMented Yet"),
@@ -158,12 +887,8 @@ impl User {
                         println!("No peer Connected");
                         return;
                     }
-                    let chat = Packet::chat_packet(self.get_name(), buf);
-                    for peer in self.connected.iter() {
-                        if let Err(e) = chat.send_packet(&socket, &peer.get_addr()).await {
-                            println!("Error sending {}, {}", peer.get_name(), e);
-                        }
-                    }
+                    let peers: Vec<Peer> = self.connected.iter().cloned().collect();
+                    self.send_chat(&socket, &peers, buf).await;
                 } else {
                     println!("Not a feature");
                 }
@@ -184,7 +909,31 @@ Available Commands:
   dis:<name>         - Disconnect from a connected peer.
   ls:                - List all connected peers.
   chat:              - Toggle chat mode ON/OFF.
-  file:<path>        - Send a file to connected peers (use 'path' inside quotes).
+  room:<passphrase>  - Enter a private room, 'con:' then joins room members without approval.
+  room:              - Leave the private room.
+  file:<path>        - Send a file or directory to connected peers (use 'path' inside quotes).
+  file:@<peers> <path> - Send only to the listed peers, groups or fingerprints (comma separated).
+  @<peers> <message> - In chat mode, send a message only to the listed peers.
+                       Files and messages for known peers that are offline are queued.
+  outbox:            - List queued files and messages.
+  unqueue:<id>       - Remove an item from the outbox.
+  group:<name> <peers> - Save a group of peer names or fingerprints, '-' deletes it.
+  group:             - List all groups.
+  share:<name> <path> - Share a folder under a name, '-' stops sharing it.
+  share:             - List shared folders and who may browse them.
+  allow:<name> <peers> - Peers, by fingerprint when offline, or groups that may browse a share, '*' makes it public, '-' nobody.
+  browse:<peer> [page] - List the files a peer shares with you.
+  get:<peer> <path>  - Fetch a file or folder listed by 'browse:'.
+  sync:<peers> <dir> - Keep a folder mirrored with peers that sync a folder of the same name.
+  sync:              - List synced folders.
+  unsync:<name>      - Stop syncing a folder, the files stay.
+  transfers:         - List running and queued transfers with their ids.
+  pause:<id>         - Pause a transfer, the peer pauses too.
+  resume:<id>        - Resume a paused transfer.
+  cancel:<id>        - Cancel a transfer on both sides.
+  limit:[peer] <up|down> <rate> - Cap uploads or downloads, like 'limit:up 2M', '0' lifts the cap.
+  limit:             - Show the rate limits.
+  dir:<path>         - Set the download directory, shows the current one without a path.
   help:              - Show this help message.";
 
     let mut stdout = std::io::stdout();
@@ -200,6 +949,18 @@ Available Commands:
 }
 
 
+/// Splits a leading `@peer,peer` selector off `arg`.
+fn split_recipients(arg: &str) -> (Option<&str>, &str) {
+    let arg = arg.trim_start();
+    match arg.strip_prefix('@') {
+        Some(rest) => match rest.split_once(char::is_whitespace) {
+            Some((spec, rest)) => (Some(spec), rest),
+            None => (Some(rest), ""),
+        },
+        None => (None, arg),
+    }
+}
+
 fn base58_to_addr(addr: String) -> Option<SocketAddr> {
     let (ip, port) = addr.split_once("/")?;
 
@@ -218,3 +979,47 @@ fn base58_to_addr(addr: String) -> Option<SocketAddr> {
     let port = u16::from_be_bytes(<[u8; 2]>::try_from(port_bytes).ok()?);
     Some(SocketAddr::new(IpAddr::V6(ipv6), port))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str) -> User {
+        User::new(
+            name.to_string(),
+            Config::default(),
+            Identity::ephemeral(),
+            SyncDb::default(),
+            Outbox::default(),
+            History::default(),
+        )
+    }
+
+    #[test]
+    fn binding_answers_must_sign_the_nonce() {
+        let (mut alice, bob) = (user("alice"), user("bob"));
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let key = bob.identity().public();
+        // Nothing was asked
+        assert!(alice.take_bind(&addr, 1, &key).is_err());
+
+        let nonce = alice.bind_nonce(addr, None);
+        let Packet::Bind(answer) = Packet::create_binding_res(true, &bob, 9, nonce) else {
+            unreachable!()
+        };
+        assert!(answer.verify());
+        assert!(alice.take_bind(&addr, nonce ^ 1, &key).is_err());
+        assert!(alice.take_bind(&addr, answer.echo, &answer.key).is_ok());
+        // Replayed
+        assert!(alice.take_bind(&addr, answer.echo, &answer.key).is_err());
+
+        let mut tampered = answer.clone();
+        tampered.echo ^= 1;
+        assert!(!tampered.verify());
+
+        // A challenge only counts when the challenged key answers it
+        let nonce = alice.bind_nonce(addr, Some(key));
+        assert!(alice.take_bind(&addr, nonce, &alice.identity().public()).is_err());
+        assert!(alice.take_bind(&addr, nonce, &key).is_ok());
+    }
+}
@@ -0,0 +1,439 @@
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::Mutex,
+    time::{interval, timeout},
+};
+
+use super::{command::Command, history::Outcome, identity::PublicKey, peer::Peer, Packet, User};
+use crate::config;
+use crate::routes::Routes;
+
+const DB_FILE: &str = "outbox.db";
+/// How often queued items are matched against the connected peers.
+const DELIVER_INTERVAL: Duration = Duration::from_secs(2);
+const DAY: i64 = 24 * 60 * 60;
+/// Wait after a failed delivery before the peer gets the item again.
+const RETRY_DELAY: i64 = 60;
+/// A queued message is sent this many times while no ack comes back.
+const CHAT_TRIES: usize = 3;
+const CHAT_ACK_TIMEOUT: Duration = Duration::from_secs(3);
+/// Ids of queued messages received lately, a repeat whose ack got lost
+/// is not shown twice.
+const SEEN_CHATS: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Item {
+    Chat(String),
+    File(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Queued {
+    pub id: u64,
+    /// Fingerprint of the peer it goes to.
+    pub to: String,
+    pub item: Item,
+    pub queued: i64,
+}
+
+impl Queued {
+    /// Id a queued message goes out with. The same on every try, also
+    /// after a restart, and distinct between senders.
+    fn wire_id(&self, from: &PublicKey) -> u64 {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(from);
+        hasher.update(&self.id.to_le_bytes());
+        hasher.update(&self.queued.to_le_bytes());
+        let hash = hasher.finalize();
+        u64::from_le_bytes(hash.as_bytes()[..8].try_into().expect("8 bytes"))
+    }
+}
+
+/// Files and messages for peers that are offline, kept in `outbox.db` next
+/// to the config file and delivered the next time the peer connects.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Outbox {
+    next_id: u64,
+    /// Names of every peer seen, by fingerprint, so they can be addressed while offline.
+    pub known: BTreeMap<String, String>,
+    pub items: Vec<Queued>,
+    /// Items being delivered now, they stay queued until it succeeds.
+    #[serde(skip)]
+    sending: HashSet<u64>,
+    /// When items whose delivery failed are tried again.
+    #[serde(skip)]
+    retry_at: HashMap<u64, i64>,
+    #[serde(skip)]
+    seen: VecDeque<u64>,
+}
+
+impl Outbox {
+    pub fn load() -> io::Result<Self> {
+        config::load_state(DB_FILE)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        config::save_state(DB_FILE, self)
+    }
+
+    /// Known fingerprints matching a selector the way `Peer::matches` does.
+    fn matching(&self, selector: &str) -> Vec<String> {
+        self.known
+            .iter()
+            .filter(|(fp, name)| {
+                name.eq_ignore_ascii_case(selector) || (selector.len() >= 4 && fp.starts_with(selector))
+            })
+            .map(|(fp, _)| fp.clone())
+            .collect()
+    }
+
+    /// Last moment an item queued at `queued` is kept, `None` when
+    /// `days` reaches past what a timestamp holds.
+    fn expires(queued: i64, days: u64) -> Option<i64> {
+        i64::try_from(days)
+            .ok()
+            .and_then(|days| days.checked_mul(DAY))
+            .and_then(|expiry| queued.checked_add(expiry))
+    }
+}
+
+impl User {
+    /// Records the name a peer connected with, keyed by its fingerprint.
+    pub fn remember(&mut self, peer: &Peer) {
+        let fp = peer.fingerprint();
+        if self.outbox.known.get(&fp).map(String::as_str) == Some(peer.get_name()) {
+            return;
+        }
+        self.outbox.known.insert(fp, peer.get_name().to_string());
+        if let Err(e) = self.outbox.save() {
+            println!("Error saving known peers, {}", e);
+        }
+    }
+
+    fn known_name(&self, fp: &str) -> String {
+        match self.outbox.known.get(fp) {
+            Some(name) => format!("{} ({})", name, fp),
+            None => fp.to_string(),
+        }
+    }
+
+    /// Like `select_peers`, but peers seen before that are offline now are
+    /// returned by fingerprint instead of failing the selection.
+    pub fn select_recipients(&self, spec: &str) -> Result<(Vec<Peer>, Vec<String>), String> {
+        let (mut online, mut offline): (Vec<Peer>, Vec<String>) = (Vec::new(), Vec::new());
+        for token in spec.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let (members, is_group) = match self.config.groups.get(token) {
+                Some(m) => (m.clone(), true),
+                None => (vec![token.to_string()], false),
+            };
+            for member in members {
+                let found: Vec<&Peer> = self.connected.iter().filter(|p| p.matches(&member)).collect();
+                let away: Vec<String> = self
+                    .outbox
+                    .matching(&member)
+                    .into_iter()
+                    .filter(|fp| !self.connected.iter().any(|p| p.fingerprint() == *fp))
+                    .collect();
+                if found.is_empty() && away.is_empty() && !is_group {
+                    return Err(format!("No connected or known peer matches {}", member));
+                }
+                for peer in found {
+                    if !online.contains(peer) {
+                        online.push(peer.clone());
+                    }
+                }
+                for fp in away {
+                    if !offline.contains(&fp) {
+                        offline.push(fp);
+                    }
+                }
+            }
+        }
+        if online.is_empty() && offline.is_empty() {
+            return Err("No peer selected".to_string());
+        }
+        Ok((online, offline))
+    }
+
+    /// Queues an item for each offline peer.
+    pub fn queue(&mut self, to: Vec<String>, item: Item) {
+        let queued = Local::now().timestamp();
+        for fp in to {
+            self.outbox.next_id += 1;
+            let id = self.outbox.next_id;
+            println!("{} is offline, queued as {}", self.known_name(&fp), id);
+            self.outbox.items.push(Queued {
+                id,
+                to: fp,
+                item: item.clone(),
+                queued,
+            });
+        }
+        if let Err(e) = self.outbox.save() {
+            println!("Queued for this session, error saving the outbox, {}", e);
+        }
+    }
+
+    pub fn unqueue(&mut self, id: u64) -> bool {
+        let before = self.outbox.items.len();
+        self.outbox.items.retain(|q| q.id != id);
+        if let Err(e) = self.outbox.save() {
+            println!("Error saving the outbox, {}", e);
+        }
+        self.outbox.items.len() != before
+    }
+
+    pub fn display_outbox(&self) {
+        if self.outbox.items.is_empty() {
+            println!("Outbox is empty");
+        }
+        let format = |time: Option<i64>| {
+            time.and_then(|time| Local.timestamp_opt(time, 0).single())
+                .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        };
+        for q in self.outbox.items.iter() {
+            let what = match &q.item {
+                Item::Chat(msg) => format!("message \"{}\"", msg),
+                Item::File(path) => format!("file {}", path.display()),
+            };
+            let expires = Outbox::expires(q.queued, self.config.outbox_days);
+            println!(
+                "[{}] {} to {}, queued {}, expires {}",
+                q.id,
+                what,
+                self.known_name(&q.to),
+                format(Some(q.queued)).unwrap_or_else(|| "unknown".to_string()),
+                format(expires).unwrap_or_else(|| "never".to_string()),
+            );
+        }
+    }
+
+    /// Removes expired items and returns the ones whose peer is connected
+    /// now with their peer. Those stay queued, marked as being sent until
+    /// `delivered` says how it went.
+    fn take_due(&mut self, now: i64) -> Vec<(Peer, Queued)> {
+        let days = self.config.outbox_days;
+        self.outbox.retry_at.retain(|_, at| *at > now);
+        let (mut due, mut expired) = (Vec::new(), Vec::new());
+        for q in self.outbox.items.iter() {
+            if self.outbox.sending.contains(&q.id) {
+                continue;
+            }
+            if Outbox::expires(q.queued, days).is_some_and(|at| at <= now) {
+                expired.push(q.id);
+            } else if self.outbox.retry_at.contains_key(&q.id) {
+                continue;
+            } else if let Some(peer) = self.connected.iter().find(|p| p.fingerprint() == q.to) {
+                due.push((peer.clone(), q.clone()));
+            }
+        }
+        for (_, q) in due.iter() {
+            self.outbox.sending.insert(q.id);
+        }
+        if !expired.is_empty() {
+            for q in self.outbox.items.iter().filter(|q| expired.contains(&q.id)) {
+                println!("Queued item {} for {} expired", q.id, self.known_name(&q.to));
+            }
+            self.outbox.items.retain(|q| !expired.contains(&q.id));
+            if let Err(e) = self.outbox.save() {
+                println!("Error saving the outbox, {}", e);
+            }
+        }
+        due
+    }
+
+    /// Ends a delivery started by `take_due`. A delivered item leaves the
+    /// outbox, any other is tried again after `RETRY_DELAY`.
+    fn delivered(&mut self, id: u64, done: bool, now: i64) {
+        self.outbox.sending.remove(&id);
+        if !done {
+            let at = now.saturating_add(RETRY_DELAY);
+            self.outbox.retry_at.insert(id, at);
+            return;
+        }
+        self.outbox.items.retain(|q| q.id != id);
+        if let Err(e) = self.outbox.save() {
+            println!("Error saving the outbox, {}", e);
+        }
+    }
+
+    /// False for a queued message that was already shown.
+    pub fn first_delivery(&mut self, id: u64) -> bool {
+        let seen = &mut self.outbox.seen;
+        if seen.contains(&id) {
+            return false;
+        }
+        if seen.len() == SEEN_CHATS {
+            seen.pop_front();
+        }
+        seen.push_back(id);
+        true
+    }
+}
+
+/// Hands queued items over once their peer is connected.
+pub async fn run(socket: Arc<UdpSocket>, user_lock: Arc<Mutex<User>>, routes: Routes) {
+    let mut tick = interval(DELIVER_INTERVAL);
+    loop {
+        tick.tick().await;
+        let (due, name, key) = {
+            let mut user = user_lock.lock().await;
+            (user.take_due(Local::now().timestamp()), user.get_name(), user.identity.public())
+        };
+        for (peer, q) in due {
+            let (socket, user_lock, routes) = (socket.clone(), user_lock.clone(), routes.clone());
+            let name = name.clone();
+            tokio::spawn(async move {
+                let done = match &q.item {
+                    Item::Chat(msg) => {
+                        let id = q.wire_id(&key);
+                        let chat = Packet::create_queued_chat(name, msg.clone(), q.queued, id);
+                        send_chat(&socket, &routes, &peer, &q, chat, id).await
+                    }
+                    Item::File(path) => send_file(socket, user_lock.clone(), routes, &peer, &q, path).await,
+                };
+                user_lock.lock().await.delivered(q.id, done, Local::now().timestamp());
+            });
+        }
+    }
+}
+
+/// Sends a queued message until the peer acks it, false if it never does.
+async fn send_chat(socket: &UdpSocket, routes: &Routes, peer: &Peer, q: &Queued, chat: Packet, id: u64) -> bool {
+    let mut acks = routes.open(id, Some(peer.get_addr()));
+    for _ in 0..CHAT_TRIES {
+        if let Err(e) = chat.send_packet(socket, &peer.get_addr()).await {
+            println!("Error sending {}, {}", peer.get_name(), e);
+            return false;
+        }
+        loop {
+            match timeout(CHAT_ACK_TIMEOUT, acks.recv()).await {
+                Ok(Some((Packet::Ack(_), _))) => {
+                    println!("Delivered queued message {} to {}", q.id, peer.get_name());
+                    return true;
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) => return false,
+                Err(_) => break,
+            }
+        }
+    }
+    println!("{} didn't confirm queued message {}, trying again later", peer.get_name(), q.id);
+    false
+}
+
+/// Offers a queued file, true once it needs no more tries: the peer has
+/// it or turned it down.
+async fn send_file(
+    socket: Arc<UdpSocket>,
+    user_lock: Arc<Mutex<User>>,
+    routes: Routes,
+    peer: &Peer,
+    q: &Queued,
+    path: &std::path::Path,
+) -> bool {
+    println!("Delivering queued file {} to {}", path.display(), peer.get_name());
+    let cmd = Command::File(path.to_string_lossy().to_string(), vec![peer.clone()], None);
+    let outcome = match cmd.read_file(socket, user_lock, routes).await {
+        Ok(outcomes) => outcomes.into_iter().find(|(addr, _)| *addr == peer.get_addr()).map(|(_, o)| o),
+        Err(e) => {
+            println!("Error in File handeling, {}", e);
+            None
+        }
+    };
+    match outcome {
+        Some(Outcome::Verified) => true,
+        Some(Outcome::Rejected) | Some(Outcome::Cancelled) => {
+            println!("{} turned down queued file {}, dropped from the outbox", peer.get_name(), q.id);
+            true
+        }
+        Some(Outcome::Failed) | None => {
+            println!("Queued file {} didn't reach {}, trying again later", q.id, peer.get_name());
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::user::{history::History, identity::Identity, sync::SyncDb};
+
+    const NOW: i64 = 1_700_000_000;
+
+    /// A user with bob connected and a message for him queued, kept off
+    /// disk since nothing here expires or gets delivered.
+    fn user() -> (User, Peer) {
+        let mut user = User::new(
+            "alice".to_string(),
+            Config::default(),
+            Identity::ephemeral(),
+            SyncDb::default(),
+            Outbox::default(),
+            History::default(),
+        );
+        let bob = Peer::new("bob".to_string(), "127.0.0.1:5000".parse().unwrap(), [2; 32]);
+        user.connected.insert(bob.clone());
+        user.outbox.items.push(Queued {
+            id: 1,
+            to: bob.fingerprint(),
+            item: Item::Chat("hi".to_string()),
+            queued: NOW,
+        });
+        (user, bob)
+    }
+
+    #[test]
+    fn items_stay_queued_until_delivered() {
+        let (mut user, bob) = user();
+        let due = user.take_due(NOW);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, bob);
+        // Still queued, but not handed out twice while it is being sent
+        assert_eq!(user.outbox.items.len(), 1);
+        assert!(user.take_due(NOW).is_empty());
+
+        user.delivered(1, false, NOW);
+        assert_eq!(user.outbox.items.len(), 1);
+        assert!(user.take_due(NOW + RETRY_DELAY - 1).is_empty());
+        assert_eq!(user.take_due(NOW + RETRY_DELAY).len(), 1);
+    }
+
+    #[test]
+    fn expiry_does_not_overflow() {
+        assert_eq!(Outbox::expires(NOW, 1), Some(NOW + DAY));
+        assert_eq!(Outbox::expires(NOW, u64::MAX), None);
+        assert_eq!(Outbox::expires(NOW, i64::MAX as u64 / DAY as u64), None);
+
+        let (mut user, _) = user();
+        user.config.outbox_days = u64::MAX;
+        assert_eq!(user.take_due(NOW).len(), 1);
+        user.display_outbox();
+    }
+
+    #[test]
+    fn repeated_messages_are_shown_once() {
+        let (mut user, _) = user();
+        let q = user.outbox.items[0].clone();
+        let key = user.identity.public();
+        assert_eq!(q.wire_id(&key), q.wire_id(&key));
+        assert_ne!(q.wire_id(&key), q.wire_id(&[3; 32]));
+
+        assert!(user.first_delivery(q.wire_id(&key)));
+        assert!(!user.first_delivery(q.wire_id(&key)));
+        for id in 0..SEEN_CHATS as u64 {
+            assert!(user.first_delivery(id));
+        }
+        assert!(user.first_delivery(q.wire_id(&key)));
+    }
+}
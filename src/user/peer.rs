@@ -1,16 +1,19 @@
 use std::net::{SocketAddr, IpAddr};
 use serde::{Serialize, Deserialize};
 
+use super::identity::{fingerprint, PublicKey};
+
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Peer {
     name: String,
     addr: SocketAddr,
+    key: PublicKey,
 }
 
 impl Peer {
-    pub fn new(name: String, addr: SocketAddr) -> Self {
-        Peer { name, addr }
+    pub fn new(name: String, addr: SocketAddr, key: PublicKey) -> Self {
+        Peer { name, addr, key }
     }
 
     pub fn get_name(&self) -> &str {
@@ -28,4 +31,40 @@ impl Peer {
     pub fn get_addr(&self) -> SocketAddr {
         self.addr
     }
-}
\ No newline at end of file
+
+    pub fn get_key(&self) -> PublicKey {
+        self.key
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.key)
+    }
+
+    /// Matches a name, case insensitive, or a fingerprint prefix of at least 4 characters.
+    pub fn matches(&self, selector: &str) -> bool {
+        self.name.eq_ignore_ascii_case(selector)
+            || (selector.len() >= 4 && self.fingerprint().starts_with(selector))
+    }
+
+    /// Matches only the full fingerprint or the base58 public key. Names
+    /// can be picked by anyone, so they never grant access.
+    pub fn is_identity(&self, entry: &str) -> bool {
+        entry == self.fingerprint()
+            || bs58::decode(entry).into_vec().is_ok_and(|key| key == self.key)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_never_grant_access() {
+        let peer = Peer::new("alice".to_string(), "127.0.0.1:4000".parse().unwrap(), [7; 32]);
+        assert!(peer.matches("Alice"));
+        assert!(!peer.is_identity("alice"));
+        assert!(!peer.is_identity(&peer.fingerprint()[..6]));
+        assert!(peer.is_identity(&peer.fingerprint()));
+        assert!(peer.is_identity(&bs58::encode([7u8; 32]).into_string()));
+        assert!(!peer.is_identity(&bs58::encode([8u8; 32]).into_string()));
+    }
+}
@@ -0,0 +1,449 @@
+use indicatif::HumanBytes;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io,
+    net::SocketAddr,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    sync::mpsc,
+    time::{interval, timeout_at, Instant},
+};
+
+use super::identity::{fingerprint, PublicKey};
+use super::{base58_to_addr, User};
+use crate::packet::compress;
+use crate::packet::file::{CHUNK_SIZE, PACKET_SIZE};
+use crate::packet::frame::{self, Header};
+use crate::packet::stream::{StreamEnd, StreamOffer};
+use crate::packet::transfer::ErrorCode;
+use crate::packet::Packet;
+
+/// Chunks the receiver asks for before the first one arrived.
+const INFLIGHT: usize = 4;
+/// Chunks the sender keeps read ahead of what the receiver has written.
+const READ_AHEAD: usize = 64;
+/// Unanswered chunk requests are sent again after this.
+const RETRY: Duration = Duration::from_millis(500);
+/// Silence after which the other side is given up on.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the end marker is repeated until the receiver confirms.
+const END_RETRIES: u32 = 5;
+/// How long the receiver keeps confirming a finished stream.
+const LINGER: Duration = Duration::from_secs(5);
+
+pub const USAGE: &str = "Usage:
+  connect-p2p send --stdin <address> [--name <name>]
+  connect-p2p recv --stdout [--from <fingerprint>] [--name <name>]
+  connect-p2p bench";
+
+/// Non-interactive modes for shell pipelines, `tar c dir | connect-p2p send --stdin <address>`
+/// on one side and `connect-p2p recv --stdout | tar x` on the other.
+pub enum Mode {
+    /// Connects to the address and streams stdin to it.
+    Send(SocketAddr),
+    /// Takes the first connection, or only the given fingerprint's, and writes the stream to stdout.
+    Recv(Option<String>),
+}
+
+pub struct Pipe {
+    pub mode: Mode,
+    pub name: String,
+}
+
+impl Pipe {
+    /// Reads the mode from the command line, `None` without arguments.
+    pub fn from_args(args: &[String]) -> Result<Option<Pipe>, String> {
+        let Some(command) = args.first() else {
+            return Ok(None);
+        };
+        let mut name = std::env::var("USER").unwrap_or_else(|_| "pipe".to_string());
+        let (mut stdin, mut stdout) = (false, false);
+        let (mut from, mut addr) = (None, None);
+        let mut rest = args[1..].iter();
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--stdin" => stdin = true,
+                "--stdout" => stdout = true,
+                "--from" => from = Some(rest.next().ok_or("--from needs a fingerprint")?.clone()),
+                "--name" => name = rest.next().ok_or("--name needs a name")?.clone(),
+                other if addr.is_none() && !other.starts_with("--") => addr = Some(other.to_string()),
+                other => return Err(format!("Unexpected argument {}", other)),
+            }
+        }
+        let mode = match command.as_str() {
+            "send" if stdin => {
+                let addr = addr.ok_or("send needs the receiver's address")?;
+                Mode::Send(base58_to_addr(addr).ok_or("Error parsing the address")?)
+            }
+            "recv" if stdout && addr.is_none() => Mode::Recv(from),
+            _ => return Err(USAGE.to_string()),
+        };
+        if name.trim().is_empty() {
+            return Err("Name cannot be empty".to_string());
+        }
+        Ok(Some(Pipe { mode, name }))
+    }
+
+    pub async fn run(&self, socket: &UdpSocket, user: &User) -> io::Result<()> {
+        match &self.mode {
+            Mode::Send(addr) => send_stdin(socket, user, *addr).await,
+            Mode::Recv(from) => recv_stdout(socket, user, from.as_deref()).await,
+        }
+    }
+}
+
+fn timed_out(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, what.to_string())
+}
+
+/// Sends a signed connection request until the peer answers. Returns the
+/// confirmation of the peer's challenge, resent until the stream starts.
+async fn connect(socket: &UdpSocket, user: &User, addr: SocketAddr) -> io::Result<Packet> {
+    let deadline = Instant::now() + STREAM_TIMEOUT;
+    let nonce = rand::random::<u64>().max(1);
+    let request = Packet::create_binding_req(true, user, nonce);
+    let mut tick = interval(Duration::from_secs(1));
+    let mut buf = vec![0; PACKET_SIZE];
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {
+                if Instant::now() >= deadline {
+                    return Err(timed_out("no answer to the connection request"));
+                }
+                request.send_packet(socket, &addr).await?;
+            }
+            res = socket.recv_from(&mut buf) => {
+                let (len, from) = res?;
+                if let Some(Packet::Bind(bind)) = Packet::deserialize(&buf[..len]) {
+                    if from != addr || bind.req {
+                        continue;
+                    }
+                    if !bind.verify() || bind.echo != nonce {
+                        eprintln!("Ignored a connection answer from {} that doesn't sign the request", from);
+                        continue;
+                    }
+                    if !bind.accept {
+                        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "connection denied"));
+                    }
+                    eprintln!("Connected to {} ({})", bind.name, fingerprint(&bind.key));
+                    return Ok(Packet::create_binding_res(true, user, 0, bind.nonce));
+                }
+            }
+        }
+    }
+}
+
+/// Reads the input in whole chunks, the channel closes at the end of it.
+async fn read_chunks(mut input: impl AsyncRead + Unpin, tx: mpsc::Sender<io::Result<Vec<u8>>>) {
+    loop {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut len = 0;
+        while len < CHUNK_SIZE {
+            match input.read(&mut chunk[len..]).await {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            }
+        }
+        if len == 0 {
+            return;
+        }
+        chunk.truncate(len);
+        if tx.send(Ok(chunk)).await.is_err() || len < CHUNK_SIZE {
+            return;
+        }
+    }
+}
+
+async fn send_stdin(socket: &UdpSocket, user: &User, addr: SocketAddr) -> io::Result<()> {
+    let confirm = connect(socket, user, addr).await?;
+    let (tx, mut rx) = mpsc::channel(8);
+    tokio::spawn(read_chunks(tokio::io::stdin(), tx));
+
+    let id = rand::random();
+    let offer = Packet::create_stream_offer(id, user.get_name());
+    let mut frame_buf = Vec::new();
+    let mut send_chunk = async |index: usize, data: &[u8]| {
+        let packed = compress::compress(data);
+        let header = Header {
+            id,
+            index: index as u32,
+            total: 0,
+            raw_len: data.len() as u32,
+            compressed: packed.is_some(),
+        };
+        frame::encode(&mut frame_buf, &header, &[], packed.as_deref().unwrap_or(data));
+        socket.send_to(&frame_buf, addr).await
+    };
+    // Chunks `base..=read` are kept until the receiver has written them
+    let mut chunks: VecDeque<Vec<u8>> = VecDeque::new();
+    let (mut base, mut read, mut size) = (1, 0, 0);
+    let mut hasher = blake3::Hasher::new();
+    let mut end: Option<Packet> = None;
+    let mut wanted = BTreeSet::new();
+    let (mut accepted, mut end_sent, mut end_retries) = (false, None, 0);
+    let mut last_heard = Instant::now();
+    let mut tick = interval(Duration::from_secs(1));
+    let mut buf = vec![0; PACKET_SIZE];
+    loop {
+        tokio::select! {
+            chunk = rx.recv(), if end.is_none() && chunks.len() < READ_AHEAD => match chunk {
+                Some(Ok(data)) => {
+                    read += 1;
+                    size += data.len() as u64;
+                    hasher.update(&data);
+                    if wanted.remove(&read) {
+                        send_chunk(read, &data).await?;
+                    }
+                    chunks.push_back(data);
+                }
+                Some(Err(e)) => {
+                    let error = Packet::create_error(id, ErrorCode::Io, e.to_string());
+                    error.send_packet(socket, &addr).await?;
+                    return Err(e);
+                }
+                None => {
+                    let packet = Packet::create_stream_end(id, read, size, *hasher.finalize().as_bytes());
+                    if wanted.iter().any(|i| *i > read) {
+                        packet.send_packet(socket, &addr).await?;
+                        end_sent = Some(Instant::now());
+                    }
+                    wanted.clear();
+                    end = Some(packet);
+                }
+            },
+            _ = tick.tick() => {
+                if !accepted && last_heard.elapsed() < STREAM_TIMEOUT {
+                    confirm.send_packet(socket, &addr).await?;
+                    offer.send_packet(socket, &addr).await?;
+                } else if last_heard.elapsed() >= STREAM_TIMEOUT {
+                    return Err(timed_out("the receiver stopped answering"));
+                }
+                // The receiver may have missed the end marker or its confirmation got lost
+                if let (Some(packet), Some(t)) = (&end, end_sent) {
+                    if t.elapsed() >= Duration::from_secs(2) {
+                        if end_retries == END_RETRIES {
+                            return Err(timed_out("the receiver didn't confirm the stream"));
+                        }
+                        end_retries += 1;
+                        packet.send_packet(socket, &addr).await?;
+                        end_sent = Some(Instant::now());
+                    }
+                }
+            }
+            res = socket.recv_from(&mut buf) => {
+                let (len, from) = res?;
+                if from != addr {
+                    continue;
+                }
+                match Packet::deserialize(&buf[..len]) {
+                    Some(Packet::StreamRequest(request)) if request.id == id => {
+                        if !accepted {
+                            eprintln!("Streaming stdin to {}", addr);
+                        }
+                        accepted = true;
+                        last_heard = Instant::now();
+                        while base < request.from && !chunks.is_empty() {
+                            chunks.pop_front();
+                            base += 1;
+                        }
+                        let mut past_end = false;
+                        for index in request.chunks {
+                            if index < base || index >= base + READ_AHEAD {
+                                continue;
+                            } else if index <= read {
+                                send_chunk(index, &chunks[index - base]).await?;
+                            } else if end.is_some() {
+                                past_end = true;
+                            } else {
+                                wanted.insert(index);
+                            }
+                        }
+                        if let (true, Some(packet)) = (past_end, &end) {
+                            packet.send_packet(socket, &addr).await?;
+                            end_sent = Some(Instant::now());
+                        }
+                    }
+                    Some(Packet::Complete(complete)) if complete.id == id && end.is_some() => {
+                        if complete.hash != *hasher.finalize().as_bytes() {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, "the receiver got different content"));
+                        }
+                        eprintln!("Sent {} in {} chunks", HumanBytes(size), read);
+                        return Ok(());
+                    }
+                    Some(Packet::Reject(reject)) if reject.id == id => {
+                        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the stream was refused"));
+                    }
+                    Some(Packet::Error(err)) if err.id == id => {
+                        return Err(io::Error::other(format!("receiver failed, {}: {}", err.code, err.reason)));
+                    }
+                    Some(Packet::Bind(bind)) if bind.req && !bind.accept => {
+                        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the receiver disconnected"));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Waits for a connection request and the stream offer that follows it.
+/// The sender is trusted once it signs back the challenge it was sent.
+async fn accept(
+    socket: &UdpSocket,
+    user: &User,
+    only: Option<&str>,
+) -> io::Result<(SocketAddr, StreamOffer)> {
+    let mut peer = None;
+    let mut challenge: Option<(SocketAddr, u64, PublicKey)> = None;
+    let mut buf = vec![0; PACKET_SIZE];
+    eprintln!("Waiting for a sender");
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        match Packet::deserialize(&buf[..len]) {
+            Some(Packet::Bind(bind)) if !bind.verify() => {
+                eprintln!("Ignored connection packet from {}, its signature doesn't match its key", from);
+            }
+            Some(Packet::Bind(bind)) if bind.req && bind.accept => {
+                let print = fingerprint(&bind.key);
+                let allowed = peer.is_none_or(|p| p == from) && only.is_none_or(|f| f == print);
+                if !allowed {
+                    eprintln!("Refused connection from {} ({})", bind.name, print);
+                    Packet::create_binding_res(false, user, 0, bind.nonce).send_packet(socket, &from).await?;
+                    continue;
+                }
+                // Resent requests get the same challenge
+                let nonce = match challenge {
+                    Some((addr, nonce, key)) if addr == from && key == bind.key => nonce,
+                    _ => rand::random::<u64>().max(1),
+                };
+                challenge = Some((from, nonce, bind.key));
+                Packet::create_binding_res(true, user, nonce, bind.nonce).send_packet(socket, &from).await?;
+            }
+            Some(Packet::Bind(bind)) if !bind.req && bind.accept && peer.is_none() => {
+                match challenge {
+                    Some((addr, nonce, key)) if addr == from && nonce == bind.echo && key == bind.key => {
+                        eprintln!("Connected to {} ({})", bind.name, fingerprint(&bind.key));
+                        peer = Some(from);
+                    }
+                    _ => eprintln!("Ignored a connection answer from {} that doesn't sign the challenge", from),
+                }
+            }
+            Some(Packet::StreamOffer(offer)) if peer == Some(from) => return Ok((from, offer)),
+            _ => {}
+        }
+    }
+}
+
+async fn recv_stdout(socket: &UdpSocket, user: &User, only: Option<&str>) -> io::Result<()> {
+    let (addr, offer) = accept(socket, user, only).await?;
+    let id = offer.id;
+    eprintln!("Receiving a stream from {}", offer.sender);
+
+    let mut out = tokio::io::stdout();
+    let (mut next_write, mut next_request, mut size) = (1, 1, 0u64);
+    let mut requested: BTreeMap<usize, Instant> = BTreeMap::new();
+    let mut ready: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+    let mut end: Option<StreamEnd> = None;
+    let mut hasher = blake3::Hasher::new();
+    let mut last_heard = Instant::now();
+    let mut buf = vec![0; PACKET_SIZE];
+    let end = loop {
+        if let Some(end) = end.take_if(|e| next_write > e.chunks) {
+            break end;
+        }
+        let now = Instant::now();
+        if now - last_heard >= STREAM_TIMEOUT {
+            let error = Packet::create_error(id, ErrorCode::Timeout, "no chunks arrived".to_string());
+            error.send_packet(socket, &addr).await?;
+            return Err(timed_out("the sender stopped answering"));
+        }
+        let mut wants = Vec::new();
+        for (index, sent) in requested.iter_mut() {
+            if *sent + RETRY <= now {
+                *sent = now;
+                wants.push(*index);
+            }
+        }
+        while requested.len() < INFLIGHT
+            && next_request < next_write + READ_AHEAD
+            && end.as_ref().is_none_or(|e| next_request <= e.chunks)
+        {
+            requested.insert(next_request, now);
+            wants.push(next_request);
+            next_request += 1;
+        }
+        if !wants.is_empty() {
+            Packet::create_stream_request(id, next_write, wants).send_packet(socket, &addr).await?;
+        }
+
+        let deadline = requested.values().min().map_or(now + RETRY, |t| *t + RETRY);
+        let (len, from) = match timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            Ok(res) => res?,
+            Err(_) => continue,
+        };
+        if from != addr {
+            continue;
+        }
+        match Packet::deserialize(&buf[..len]) {
+            Some(Packet::File(chunk)) if chunk.id == id => {
+                last_heard = Instant::now();
+                let index = chunk.chunk_index;
+                requested.remove(&index);
+                if index >= next_write && end.as_ref().is_none_or(|e| index <= e.chunks) {
+                    ready.insert(index, chunk.data);
+                }
+                while let Some(data) = ready.remove(&next_write) {
+                    if let Err(e) = out.write_all(&data).await {
+                        let error = Packet::create_error(id, ErrorCode::Io, e.to_string());
+                        error.send_packet(socket, &addr).await?;
+                        return Err(e);
+                    }
+                    hasher.update(&data);
+                    size += data.len() as u64;
+                    next_write += 1;
+                }
+            }
+            Some(Packet::StreamEnd(stream_end)) if stream_end.id == id => {
+                last_heard = Instant::now();
+                requested.retain(|i, _| *i <= stream_end.chunks);
+                ready.retain(|i, _| *i <= stream_end.chunks);
+                end = Some(stream_end);
+            }
+            Some(Packet::Error(err)) if err.id == id => {
+                return Err(io::Error::other(format!("sender failed, {}: {}", err.code, err.reason)));
+            }
+            Some(Packet::Bind(bind)) if bind.req && !bind.accept => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the sender disconnected"));
+            }
+            _ => {}
+        }
+    };
+    out.flush().await?;
+
+    let hash = *hasher.finalize().as_bytes();
+    if size != end.size || hash != end.hash {
+        let error = Packet::create_error(id, ErrorCode::Verify, "stream hash mismatch".to_string());
+        error.send_packet(socket, &addr).await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the stream doesn't match its hash"));
+    }
+    let complete = Packet::create_complete(id, hash, None);
+    complete.send_packet(socket, &addr).await?;
+    eprintln!("Received {} in {} chunks", HumanBytes(size), end.chunks);
+
+    // Confirms again in case the sender missed it and repeats the end marker
+    let linger = Instant::now() + LINGER;
+    while let Ok(res) = timeout_at(linger, socket.recv_from(&mut buf)).await {
+        let (len, from) = res?;
+        if let (true, Some(Packet::StreamEnd(_))) = (from == addr, Packet::deserialize(&buf[..len])) {
+            complete.send_packet(socket, &addr).await?;
+        }
+    }
+    Ok(())
+}
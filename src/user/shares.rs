@@ -0,0 +1,174 @@
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::Mutex,
+    time::{timeout, timeout_at, Instant},
+};
+
+use super::command::Command;
+use super::{Packet, Peer, User};
+use crate::packet::{
+    browse::{self, BrowsePacket, GetPacket, ListingPacket, BROWSE_TIMEOUT, GET_TIMEOUT},
+    file::OFFER_TIMEOUT,
+    transfer::ErrorCode,
+};
+use crate::routes::Routes;
+use crate::ReceiverRes;
+
+/// Sends a peer the page it asked for of the files it may see.
+pub async fn handle_browse(
+    socket: &UdpSocket,
+    user_lock: &Mutex<User>,
+    addr: SocketAddr,
+    request: &BrowsePacket,
+) -> io::Result<()> {
+    let shares = {
+        let user = user_lock.lock().await;
+        if user.peer_name(&addr).is_none() {
+            return Ok(());
+        }
+        user.visible_shares(&addr)
+    };
+    let mut files = Vec::new();
+    for (name, share) in shares {
+        match browse::list_share(&name, &share.path).await {
+            Ok(listed) => files.extend(listed),
+            Err(e) => println!("Error listing share {}, {}", name, e),
+        }
+    }
+    files.sort();
+    let listing = ListingPacket::build(request.id, request.page, &files).await?;
+    Packet::Listing(listing).send_packet(socket, &addr).await
+}
+
+/// Offers a shared file or folder to the peer that asked for it once the
+/// user confirms. Only peers a folder is synced with are served right away.
+pub async fn handle_get(
+    socket: Arc<UdpSocket>,
+    user_lock: Arc<Mutex<User>>,
+    addr: SocketAddr,
+    get: GetPacket,
+    mut res_rx: ReceiverRes,
+    routes: Routes,
+) -> io::Result<()> {
+    let (peer, shares, synced) = {
+        let user = user_lock.lock().await;
+        let synced = get.folder.as_ref().and_then(|f| user.sync_root(f, &addr));
+        match user.ip_to_peer.get(&addr) {
+            Some(peer) => (peer.clone(), user.visible_shares(&addr), synced),
+            None => return Ok(()),
+        }
+    };
+    let not_shared = || io::Error::new(io::ErrorKind::NotFound, "not shared");
+    // Public shares and folders synced with the peer are served without
+    // asking, private shares ask first
+    let found = match (synced, get.folder.is_some()) {
+        (Some(root), _) => browse::resolve(&root, &get.path).await.map(|path| (false, path)),
+        (None, true) => Err(not_shared()),
+        (None, false) => {
+            let (name, rel) = get.path.split_once('/').unwrap_or((get.path.as_str(), ""));
+            match shares.into_iter().find(|(n, _)| n == name) {
+                Some((_, share)) => {
+                    let ask = !share.public;
+                    browse::resolve(&share.path, rel).await.map(|path| (ask, path))
+                }
+                None => Err(not_shared()),
+            }
+        }
+    };
+    let (ask, path) = match found {
+        Ok(found) => found,
+        Err(e) => {
+            println!("Refused {:?} to {}, {}", get.path, peer.get_name(), e);
+            let packet = Packet::create_error(get.id, ErrorCode::Denied, "not shared".to_string());
+            return packet.send_packet(&socket, &addr).await;
+        }
+    };
+    if ask {
+        user_lock.lock().await.req_res();
+        print!("{} asks for {} : [y/n] -> ", peer.get_name(), get.path);
+        io::stdout().flush().unwrap();
+        let accepted = match timeout(OFFER_TIMEOUT, res_rx.recv()).await {
+            Ok(Ok(input)) => input.trim().to_lowercase().starts_with('y'),
+            _ => false,
+        };
+        user_lock.lock().await.req_resolve();
+        if !accepted {
+            println!("Request Denied");
+            return Packet::create_reject(get.id).send_packet(&socket, &addr).await;
+        }
+    }
+    println!("{} fetches {}", peer.get_name(), get.path);
+    let cmd = Command::File(path.to_string_lossy().to_string(), vec![peer], Some(get.id));
+    cmd.read_file(socket, user_lock, routes).await.map(drop)
+}
+
+/// Asks a peer for one page of its shares and prints it.
+pub async fn browse(
+    socket: &UdpSocket,
+    peer: Peer,
+    page: u32,
+    routes: &Routes,
+) -> io::Result<()> {
+    let id = rand::random();
+    let addr = peer.get_addr();
+    let mut route = routes.open(id, Some(addr));
+    Packet::create_browse(id, page).send_packet(socket, &addr).await?;
+    let deadline = Instant::now() + BROWSE_TIMEOUT;
+    loop {
+        match timeout_at(deadline, route.recv()).await {
+            Ok(Some((Packet::Listing(listing), _))) => {
+                listing.display(peer.get_name());
+                return Ok(());
+            }
+            Ok(_) => continue,
+            Err(_) => {
+                println!("{} did not answer", peer.get_name());
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Requests a file from a peer's shares. The offer that answers it is
+/// accepted without a prompt, see `receive_file`.
+pub async fn get(
+    socket: &UdpSocket,
+    user_lock: &Mutex<User>,
+    peer: Peer,
+    path: String,
+    routes: &Routes,
+) -> io::Result<()> {
+    let id = rand::random();
+    let addr = peer.get_addr();
+    let mut route = routes.open(id, Some(addr));
+    user_lock.lock().await.gets.insert(id, (addr, None));
+    Packet::create_get(id, path.clone(), None).send_packet(socket, &addr).await?;
+    println!("Asked {} for {}", peer.get_name(), path);
+    let deadline = Instant::now() + GET_TIMEOUT;
+    loop {
+        match timeout_at(deadline, route.recv()).await {
+            // `receive_file` takes the id off the list
+            Ok(Some((Packet::Metadata(_), _))) => return Ok(()),
+            Ok(Some((Packet::Reject(_), _))) => {
+                println!("{} declined {}", peer.get_name(), path);
+                break;
+            }
+            Ok(Some((Packet::Error(err), _))) => {
+                println!("{} refused {}, {}: {}", peer.get_name(), path, err.code, err.reason);
+                break;
+            }
+            Ok(_) => continue,
+            Err(_) => {
+                println!("{} did not answer the request for {}", peer.get_name(), path);
+                break;
+            }
+        }
+    }
+    user_lock.lock().await.gets.remove(&id);
+    Ok(())
+}
@@ -0,0 +1,707 @@
+use notify::{RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, Mutex},
+    time::Instant,
+};
+
+use super::{identity::fingerprint, Packet, User};
+use crate::config;
+use crate::packet::{
+    dir::{hash_file, modified_secs, sanitize_path},
+    sync::{Causality, SyncEntry, SyncPacket, MANIFEST_PAGE},
+};
+
+const DB_FILE: &str = "sync.db";
+/// Folders are rescanned and announced this often even without events,
+/// which also catches peers that reconnected or missed a manifest.
+const SCAN_INTERVAL: Duration = Duration::from_secs(30);
+/// Filesystem events are collected this long before scanning.
+const SETTLE: Duration = Duration::from_millis(500);
+/// A fetch that did not land by then is asked for again with the next manifest.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Synced folders and the last known state of their files, kept in
+/// `sync.db` next to the config file so syncing resumes after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncDb {
+    pub folders: BTreeMap<String, Folder>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Folder {
+    pub root: PathBuf,
+    /// Fingerprints of the peers the folder is mirrored with.
+    pub peers: Vec<String>,
+    pub files: BTreeMap<String, SyncEntry>,
+    /// Versions being fetched, adopted once the file on disk matches.
+    #[serde(skip)]
+    pending: HashMap<String, (SyncEntry, Instant)>,
+}
+
+impl Folder {
+    pub fn new(root: PathBuf, peers: Vec<String>) -> Self {
+        Folder {
+            root,
+            peers,
+            files: BTreeMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl SyncDb {
+    pub fn load() -> io::Result<Self> {
+        config::load_state(DB_FILE)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        config::save_state(DB_FILE, self)
+    }
+}
+
+/// What a manifest entry from a peer means for the local copy.
+enum Action {
+    /// Record the entry, the file on disk already matches.
+    Adopt(SyncEntry),
+    Fetch(SyncEntry),
+    Delete(SyncEntry),
+    /// Keep the local file as a conflict copy, then fetch the peer's version.
+    Conflict(SyncEntry),
+}
+
+fn decide(local: Option<&SyncEntry>, remote: SyncEntry, me: &str) -> Option<Action> {
+    let local = match local {
+        Some(local) => local,
+        None if remote.deleted => return Some(Action::Adopt(remote)),
+        None => return Some(Action::Fetch(remote)),
+    };
+    match local.version.compare(&remote.version) {
+        Causality::Equal | Causality::Newer => None,
+        Causality::Older if local.same_content(&remote) => Some(Action::Adopt(remote)),
+        Causality::Older if remote.deleted => Some(Action::Delete(remote)),
+        Causality::Older => Some(Action::Fetch(remote)),
+        Causality::Concurrent => {
+            let version = local.version.merge(&remote.version);
+            if local.same_content(&remote) {
+                Some(Action::Adopt(SyncEntry { version, ..local.clone() }))
+            } else if remote.deleted {
+                // An edit wins over a deletion, and has to dominate it to spread
+                let mut kept = SyncEntry { version, ..local.clone() };
+                kept.version.bump(me);
+                kept.by = me.to_string();
+                Some(Action::Adopt(kept))
+            } else if local.deleted {
+                Some(Action::Fetch(SyncEntry { version, ..remote }))
+            } else if remote.wins_over(local) {
+                Some(Action::Conflict(SyncEntry { version, ..remote }))
+            } else {
+                // The peer keeps ours and moves its own edit aside
+                None
+            }
+        }
+    }
+}
+
+/// `name.sync-conflict-<time>.ext` beside `path`.
+fn conflict_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let time = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    path.with_file_name(format!("{}.sync-conflict-{}{}", stem, time, ext))
+}
+
+/// Regular files below `root` as `(relative path, full path, metadata)`.
+/// Hidden entries, symlinks and partial downloads are not synced.
+async fn walk(root: &Path) -> io::Result<Vec<(String, PathBuf, fs::Metadata)>> {
+    let mut files = Vec::new();
+    let mut stack = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, rel)) = stack.pop() {
+        let mut read_dir = tokio::fs::read_dir(&dir).await?;
+        while let Some(child) = read_dir.next_entry().await? {
+            let name = child.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || name.ends_with(".part") {
+                continue;
+            }
+            let child_rel = if rel.is_empty() {
+                name
+            } else {
+                format!("{}/{}", rel, name)
+            };
+            let metadata = tokio::fs::symlink_metadata(child.path()).await?;
+            if metadata.is_dir() {
+                stack.push((child.path(), child_rel));
+            } else if metadata.is_file() {
+                files.push((child_rel, child.path(), metadata));
+            }
+        }
+    }
+    Ok(files)
+}
+
+impl User {
+    fn fingerprint(&self) -> String {
+        fingerprint(&self.identity.public())
+    }
+
+    /// Adds a folder or replaces the peers of an existing one, true when it is new.
+    pub fn add_sync(&mut self, name: &str, root: PathBuf, peers: Vec<String>) -> Result<bool, String> {
+        let new = match self.sync.folders.get_mut(name) {
+            Some(folder) if folder.root != root => {
+                return Err(format!("{} is already synced from {}", name, folder.root.display()))
+            }
+            Some(folder) => {
+                folder.peers = peers;
+                false
+            }
+            None => {
+                self.sync.folders.insert(name.to_string(), Folder::new(root, peers));
+                true
+            }
+        };
+        if let Err(e) = self.sync.save() {
+            println!("Sync set for this session, error saving sync state, {}", e);
+        }
+        Ok(new)
+    }
+
+    pub fn remove_sync(&mut self, name: &str) -> bool {
+        let removed = self.sync.folders.remove(name).is_some();
+        if let Err(e) = self.sync.save() {
+            println!("Error saving sync state, {}", e);
+        }
+        removed
+    }
+
+    pub fn display_syncs(&self) {
+        if self.sync.folders.is_empty() {
+            println!("No synced folders, add one with sync:<peers> <dir>");
+        }
+        for (name, folder) in self.sync.folders.iter() {
+            let peers: Vec<String> = folder
+                .peers
+                .iter()
+                .map(|fp| match self.connected.iter().find(|p| p.fingerprint() == *fp) {
+                    Some(peer) => peer.get_name().to_string(),
+                    None => format!("{} (offline)", fp),
+                })
+                .collect();
+            let files = folder.files.values().filter(|f| !f.deleted).count();
+            println!(
+                "{} -> {} with {} ({} files)",
+                name,
+                folder.root.display(),
+                peers.join(", "),
+                files
+            );
+        }
+    }
+
+    /// Root of a synced folder shared with the peer at `addr`.
+    pub fn sync_root(&self, name: &str, addr: &SocketAddr) -> Option<PathBuf> {
+        let peer = self.ip_to_peer.get(addr)?;
+        let folder = self.sync.folders.get(name)?;
+        folder
+            .peers
+            .contains(&peer.fingerprint())
+            .then(|| folder.root.clone())
+    }
+}
+
+/// Starts watching every folder in the sync database.
+pub async fn start_all(socket: &Arc<UdpSocket>, user_lock: &Arc<Mutex<User>>) {
+    let names: Vec<String> = user_lock.lock().await.sync.folders.keys().cloned().collect();
+    for name in names {
+        start(socket.clone(), user_lock.clone(), name);
+    }
+}
+
+pub fn start(socket: Arc<UdpSocket>, user_lock: Arc<Mutex<User>>, name: String) {
+    tokio::spawn(async move {
+        if let Err(e) = run(&socket, &user_lock, &name).await {
+            println!("Sync of {} stopped, {}", name, e);
+        }
+    });
+}
+
+/// Rescans the folder after filesystem events and on a timer, and tells
+/// the peers about its state, until the folder is removed.
+async fn run(socket: &UdpSocket, user_lock: &Mutex<User>, name: &str) -> io::Result<()> {
+    let root = match user_lock.lock().await.sync.folders.get(name) {
+        Some(folder) => folder.root.clone(),
+        None => return Ok(()),
+    };
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok() {
+            let _ = tx.send(());
+        }
+    })
+    .map_err(io::Error::other)?;
+    watcher
+        .watch(&root, RecursiveMode::Recursive)
+        .map_err(io::Error::other)?;
+    println!("Syncing {} ({})", name, root.display());
+
+    let mut timer = tokio::time::interval(SCAN_INTERVAL);
+    loop {
+        let periodic = tokio::select! {
+            _ = timer.tick() => true,
+            Some(()) = rx.recv() => {
+                tokio::time::sleep(SETTLE).await;
+                while rx.try_recv().is_ok() {}
+                false
+            }
+        };
+        if !user_lock.lock().await.sync.folders.contains_key(name) {
+            return Ok(());
+        }
+        let changed = match scan(user_lock, name, &root).await {
+            Ok(changed) => changed,
+            Err(e) => {
+                println!("Error scanning {}, {}", root.display(), e);
+                false
+            }
+        };
+        if changed || periodic {
+            announce(socket, user_lock, name).await;
+        }
+    }
+}
+
+/// Records local edits and deletions, true when anything changed.
+async fn scan(user_lock: &Mutex<User>, name: &str, root: &Path) -> io::Result<bool> {
+    let (files, pending, me) = {
+        let user = user_lock.lock().await;
+        match user.sync.folders.get(name) {
+            Some(folder) => (folder.files.clone(), folder.pending.clone(), user.fingerprint()),
+            None => return Ok(false),
+        }
+    };
+    let mut seen = HashSet::new();
+    let mut updates = Vec::new();
+    let mut landed = Vec::new();
+    for (rel, full, metadata) in walk(root).await? {
+        seen.insert(rel.clone());
+        let modified = modified_secs(&metadata);
+        let known = files.get(&rel);
+        if known.is_some_and(|k| !k.deleted && k.size == metadata.len() && k.modified == modified) {
+            continue;
+        }
+        // Gone again or still being written
+        let hash = match hash_file(&full).await {
+            Ok(hash) => hash,
+            Err(_) => continue,
+        };
+        let unchanged = known.is_some_and(|k| !k.deleted && k.hash == hash);
+        let mut base = known.map(|k| k.version.clone()).unwrap_or_default();
+        if let Some((fetched, _)) = pending.get(&rel) {
+            if fetched.hash == hash {
+                updates.push(SyncEntry { modified, ..fetched.clone() });
+                landed.push(rel);
+                continue;
+            }
+            // Fetched files are renamed into place, the old content means it didn't land yet
+            if unchanged {
+                continue;
+            }
+            // Otherwise it landed and was edited since
+            landed.push(rel.clone());
+            base = fetched.version.clone();
+        }
+        let entry = match known {
+            Some(k) if unchanged => SyncEntry {
+                modified,
+                size: metadata.len(),
+                ..k.clone()
+            },
+            _ => {
+                base.bump(&me);
+                SyncEntry {
+                    path: rel,
+                    hash,
+                    size: metadata.len(),
+                    modified,
+                    deleted: false,
+                    version: base,
+                    by: me.clone(),
+                }
+            }
+        };
+        updates.push(entry);
+    }
+    for (rel, known) in files.iter() {
+        if known.deleted || seen.contains(rel) || pending.contains_key(rel) {
+            continue;
+        }
+        let mut gone = known.clone();
+        gone.deleted = true;
+        gone.modified = Some(chrono::Local::now().timestamp());
+        gone.version.bump(&me);
+        gone.by = me.clone();
+        updates.push(gone);
+    }
+
+    let mut user = user_lock.lock().await;
+    let User { sync, gets, .. } = &mut *user;
+    let folder = match sync.folders.get_mut(name) {
+        Some(folder) => folder,
+        None => return Ok(false),
+    };
+    let mut changed = false;
+    for entry in updates {
+        // Skipped when a peer's manifest changed the entry meanwhile
+        let base = files.get(&entry.path).map(|k| &k.version);
+        if folder.files.get(&entry.path).map(|k| &k.version) != base {
+            continue;
+        }
+        folder.files.insert(entry.path.clone(), entry);
+        changed = true;
+    }
+    for rel in landed {
+        folder.pending.remove(&rel);
+    }
+    let now = Instant::now();
+    let expired: Vec<String> = folder
+        .pending
+        .iter()
+        .filter(|(_, (_, since))| now - *since > PENDING_TIMEOUT)
+        .map(|(rel, _)| rel.clone())
+        .collect();
+    for rel in expired {
+        folder.pending.remove(&rel);
+        let dest = Some(folder.root.join(&rel));
+        gets.retain(|_, (_, d)| *d != dest);
+    }
+    if changed {
+        sync.save()?;
+    }
+    Ok(changed)
+}
+
+/// Sends the whole folder state to every connected sync peer.
+async fn announce(socket: &UdpSocket, user_lock: &Mutex<User>, name: &str) {
+    let (entries, addrs) = {
+        let user = user_lock.lock().await;
+        let folder = match user.sync.folders.get(name) {
+            Some(folder) => folder,
+            None => return,
+        };
+        let addrs: Vec<SocketAddr> = user
+            .connected
+            .iter()
+            .filter(|p| folder.peers.contains(&p.fingerprint()))
+            .map(|p| p.get_addr())
+            .collect();
+        (folder.files.values().cloned().collect::<Vec<_>>(), addrs)
+    };
+    for page in entries.chunks(MANIFEST_PAGE) {
+        let packet = Packet::Sync(SyncPacket {
+            folder: name.to_string(),
+            entries: page.to_vec(),
+        });
+        for addr in addrs.iter() {
+            if let Err(e) = packet.send_packet(socket, addr).await {
+                println!("Error sending sync state to {}, {}", addr, e);
+            }
+        }
+    }
+}
+
+/// Applies a page of a peer's folder state: fetches newer files, deletes
+/// files deleted there and moves conflicting edits aside.
+pub async fn handle_sync(
+    socket: &UdpSocket,
+    user_lock: &Mutex<User>,
+    addr: SocketAddr,
+    packet: SyncPacket,
+) -> io::Result<()> {
+    let (root, actions) = {
+        let mut user = user_lock.lock().await;
+        let me = user.fingerprint();
+        let root = match user.sync_root(&packet.folder, &addr) {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+        let folder = match user.sync.folders.get_mut(&packet.folder) {
+            Some(folder) => folder,
+            None => return Ok(()),
+        };
+        let mut actions = Vec::new();
+        let mut changed = false;
+        for remote in packet.entries {
+            let valid = matches!(sanitize_path(&remote.path, false), Ok(Some(_)));
+            if !valid || remote.path.ends_with(".part") || folder.pending.contains_key(&remote.path) {
+                continue;
+            }
+            match decide(folder.files.get(&remote.path), remote, &me) {
+                Some(Action::Adopt(entry)) => {
+                    folder.files.insert(entry.path.clone(), entry);
+                    changed = true;
+                }
+                Some(action) => actions.push(action),
+                None => {}
+            }
+        }
+        if changed {
+            user.sync.save()?;
+        }
+        (root, actions)
+    };
+
+    // One entry that can't be applied doesn't hold up the rest of the page
+    for action in actions {
+        let path = match &action {
+            Action::Adopt(entry) | Action::Fetch(entry) | Action::Delete(entry) | Action::Conflict(entry) => {
+                entry.path.clone()
+            }
+        };
+        if let Err(e) = apply(socket, user_lock, addr, &packet.folder, &root, action).await {
+            println!("Sync {}: error updating {}, {}", packet.folder, path, e);
+            unmark_pending(user_lock, &packet.folder, &path).await;
+        }
+    }
+    Ok(())
+}
+
+/// Carries out what `decide` asked for one entry of a peer's manifest.
+async fn apply(
+    socket: &UdpSocket,
+    user_lock: &Mutex<User>,
+    addr: SocketAddr,
+    name: &str,
+    root: &Path,
+    action: Action,
+) -> io::Result<()> {
+    let entry = match action {
+        Action::Delete(entry) => {
+            let path = root.join(&entry.path);
+            let known = {
+                let user = user_lock.lock().await;
+                let folder = user.sync.folders.get(name);
+                folder.and_then(|f| f.files.get(&entry.path).cloned())
+            };
+            // Local edits not scanned yet win over the deletion on the next scan
+            let unchanged = match (tokio::fs::metadata(&path).await, known) {
+                (Ok(m), Some(k)) => m.len() == k.size && modified_secs(&m) == k.modified,
+                (Err(_), _) => true,
+                _ => false,
+            };
+            if !unchanged {
+                return Ok(());
+            }
+            if path.exists() {
+                tokio::fs::remove_file(&path).await?;
+                println!("Sync {}: removed {}", name, entry.path);
+            }
+            let mut user = user_lock.lock().await;
+            if let Some(folder) = user.sync.folders.get_mut(name) {
+                folder.files.insert(entry.path.clone(), entry);
+            }
+            return user.sync.save();
+        }
+        Action::Conflict(entry) => {
+            let path = root.join(&entry.path);
+            let copy = conflict_path(&path);
+            // Marked pending first so the scan doesn't see a deletion
+            mark_pending(user_lock, name, &entry).await;
+            tokio::fs::rename(&path, &copy).await?;
+            println!(
+                "Sync {}: {} changed on both sides, kept yours as {}",
+                name,
+                entry.path,
+                copy.file_name().unwrap_or_default().to_string_lossy()
+            );
+            entry
+        }
+        Action::Fetch(entry) => {
+            mark_pending(user_lock, name, &entry).await;
+            entry
+        }
+        Action::Adopt(_) => return Ok(()),
+    };
+    let dest = root.join(&entry.path);
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let id = rand::random();
+    user_lock.lock().await.gets.insert(id, (addr, Some(dest)));
+    let get = Packet::create_get(id, entry.path.clone(), Some(name.to_string()));
+    get.send_packet(socket, &addr).await
+}
+
+async fn mark_pending(user_lock: &Mutex<User>, name: &str, entry: &SyncEntry) {
+    if let Some(folder) = user_lock.lock().await.sync.folders.get_mut(name) {
+        folder
+            .pending
+            .insert(entry.path.clone(), (entry.clone(), Instant::now()));
+    }
+}
+
+/// Forgets a fetch that could not be started, the next manifest asks again.
+async fn unmark_pending(user_lock: &Mutex<User>, name: &str, rel: &str) {
+    let mut user = user_lock.lock().await;
+    let User { sync, gets, .. } = &mut *user;
+    if let Some(folder) = sync.folders.get_mut(name) {
+        folder.pending.remove(rel);
+        let dest = Some(folder.root.join(rel));
+        gets.retain(|_, (_, d)| *d != dest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::packet::sync::VersionVector;
+    use crate::user::{history::History, identity::Identity, outbox::Outbox, peer::Peer};
+
+    fn entry(path: &str, version: &[(&str, u64)], hash: u8, deleted: bool) -> SyncEntry {
+        let mut vv = VersionVector::default();
+        for (who, n) in version {
+            for _ in 0..*n {
+                vv.bump(who);
+            }
+        }
+        SyncEntry {
+            path: path.to_string(),
+            hash: [hash; 32],
+            size: 1,
+            modified: Some(hash as i64),
+            deleted,
+            version: vv,
+            by: version.last().map(|(who, _)| who.to_string()).unwrap_or_default(),
+        }
+    }
+
+    fn kind(action: Option<Action>) -> &'static str {
+        match action {
+            None => "keep",
+            Some(Action::Adopt(_)) => "adopt",
+            Some(Action::Fetch(_)) => "fetch",
+            Some(Action::Delete(_)) => "delete",
+            Some(Action::Conflict(_)) => "conflict",
+        }
+    }
+
+    #[test]
+    fn manifest_entries_decide_the_action() {
+        let base = entry("a", &[("me", 1)], 1, false);
+        let mut newer = base.clone();
+        newer.version.bump("peer");
+        newer.hash = [2; 32];
+        newer.by = "peer".to_string();
+        let mut gone = newer.clone();
+        gone.deleted = true;
+        let mut edited = base.clone();
+        edited.version.bump("me");
+        edited.hash = [3; 32];
+        edited.by = "me".to_string();
+        let mut same_edit = newer.clone();
+        same_edit.hash = edited.hash;
+        let mut deleted_here = edited.clone();
+        deleted_here.deleted = true;
+
+        let cases = [
+            (None, &newer, "fetch"),
+            (None, &gone, "adopt"),
+            (Some(&base), &base, "keep"),
+            (Some(&newer), &base, "keep"),
+            (Some(&base), &newer, "fetch"),
+            (Some(&base), &gone, "delete"),
+            // Concurrent edits to the same content need no transfer
+            (Some(&edited), &same_edit, "adopt"),
+            // An edit wins over a concurrent deletion on both sides
+            (Some(&edited), &gone, "adopt"),
+            (Some(&deleted_here), &newer, "fetch"),
+        ];
+        for (local, remote, expected) in cases {
+            assert_eq!(kind(decide(local, remote.clone(), "me")), expected, "{:?} <- {:?}", local, remote);
+        }
+
+        // Concurrent edits: exactly one side takes the other's version
+        let here = kind(decide(Some(&edited), newer.clone(), "me"));
+        let there = kind(decide(Some(&newer), edited.clone(), "peer"));
+        let mut sides = [here, there];
+        sides.sort();
+        assert_eq!(sides, ["conflict", "keep"]);
+    }
+
+    #[test]
+    fn kept_edit_dominates_the_deletion() {
+        let base = entry("a", &[("me", 1)], 1, false);
+        let mut edited = base.clone();
+        edited.version.bump("me");
+        let mut gone = base.clone();
+        gone.version.bump("peer");
+        gone.deleted = true;
+        let Some(Action::Adopt(kept)) = decide(Some(&edited), gone.clone(), "me") else {
+            panic!("the edit is kept");
+        };
+        assert_eq!(kept.version.compare(&gone.version), Causality::Newer);
+        assert!(!kept.deleted);
+    }
+
+    #[tokio::test]
+    async fn failed_entries_do_not_stop_the_page() {
+        let root = std::env::temp_dir().join(format!("connect-p2p-sync-{:x}", rand::random::<u64>()));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer_socket.local_addr().unwrap();
+        let peer = Peer::new("bob".to_string(), addr, [2; 32]);
+        let mut user = User::new(
+            "alice".to_string(),
+            Config::default(),
+            Identity::ephemeral(),
+            SyncDb::default(),
+            Outbox::default(),
+            History::default(),
+        );
+        let me = user.fingerprint();
+        let mut folder = Folder::new(root.clone(), vec![peer.fingerprint()]);
+        // Known here but gone from disk, so moving it aside fails
+        let local = entry("lost.txt", &[(&me, 1)], 1, false);
+        folder.files.insert(local.path.clone(), local.clone());
+        user.sync.folders.insert("docs".to_string(), folder);
+        user.ip_to_peer.insert(addr, peer);
+        let user_lock = Mutex::new(user);
+
+        let mut conflicting = local.clone();
+        conflicting.version = VersionVector::default();
+        conflicting.version.bump("zzzz");
+        conflicting.by = "zzzz".to_string();
+        conflicting.hash = [9; 32];
+        conflicting.modified = Some(i64::MAX);
+        assert_eq!(kind(decide(Some(&local), conflicting.clone(), &me)), "conflict");
+        let fresh = entry("new.txt", &[("zzzz", 1)], 4, false);
+        let packet = SyncPacket {
+            folder: "docs".to_string(),
+            entries: vec![conflicting, fresh],
+        };
+        handle_sync(&socket, &user_lock, addr, packet).await.unwrap();
+
+        let user = user_lock.lock().await;
+        let folder = &user.sync.folders["docs"];
+        assert!(!folder.pending.contains_key("lost.txt"));
+        assert!(folder.pending.contains_key("new.txt"));
+        let dests: Vec<_> = user.gets.values().map(|(_, d)| d.clone()).collect();
+        assert_eq!(dests, [Some(root.join("new.txt"))]);
+        let mut buf = [0; 2048];
+        // Only the get for the new file went out
+        peer_socket.recv(&mut buf).await.unwrap();
+        assert!(peer_socket.try_recv(&mut buf).is_err());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,277 @@
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+
+use crate::packet::{cache::ChunkCache, source::Source, transfer::TransferState};
+
+use super::identity::PublicKey;
+use super::limit::Throttle;
+
+/// Transfers moving data at the same time when not set in the config.
+pub const DEFAULT_MAX_TRANSFERS: usize = 3;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+/// Entry of the list shown by `transfers:`.
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    pub wire: u64,
+    pub name: String,
+    pub peer: String,
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    size: Arc<AtomicU64>,
+    done: Arc<AtomicU64>,
+    state: Arc<watch::Sender<TransferState>>,
+}
+
+impl Transfer {
+    pub fn state(&self) -> TransferState {
+        *self.state.borrow()
+    }
+
+    pub fn set_state(&self, state: TransferState) {
+        self.state.send_replace(state);
+    }
+}
+
+/// Content offered or received in this session, peers can fetch it by hash.
+/// For received files the tree and chunk cache are built on the first request.
+#[derive(Clone, Debug)]
+pub struct Shared {
+    pub name: String,
+    pub source: Source,
+    pub chunks: Option<Arc<ChunkCache>>,
+}
+
+/// Handle held by the task moving the data of a transfer.
+pub struct Job {
+    pub id: u32,
+    pub wire: u64,
+    pub addr: SocketAddr,
+    pub name: String,
+    pub peer: String,
+    pub direction: Direction,
+    /// Size of the content, `size` is the delta's when one is served instead.
+    pub total: u64,
+    /// When data started moving, for the history.
+    pub started: Instant,
+    /// Identity of the peer when the transfer started, receipts are
+    /// checked against it even after the peer is gone.
+    pub key: Option<PublicKey>,
+    /// Content hash the receiver confirms when a delta is served instead.
+    pub content: Option<[u8; 32]>,
+    /// Chunks are compressed when that makes them smaller.
+    pub compress: bool,
+    /// Rate limits on the file data, see `limit.rs`.
+    pub throttle: Throttle,
+    size: Arc<AtomicU64>,
+    done: Arc<AtomicU64>,
+    state: Arc<watch::Sender<TransferState>>,
+    rx: watch::Receiver<TransferState>,
+    slots: Arc<Semaphore>,
+}
+
+impl Job {
+    pub fn state(&self) -> TransferState {
+        *self.rx.borrow()
+    }
+
+    pub fn progress(&self, n: u64) {
+        self.done.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// Bytes actually moved differ from the content size when sending a delta.
+    pub fn set_size(&self, size: u64) {
+        self.size.store(size, Ordering::Relaxed);
+    }
+
+    /// Takes a transfer slot if one is free right away.
+    pub fn try_slot(&self) -> Option<OwnedSemaphorePermit> {
+        self.slots.clone().try_acquire_owned().ok()
+    }
+
+    /// Waits in the queue for a transfer slot.
+    pub async fn slot(&self) -> OwnedSemaphorePermit {
+        self.slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("transfer slots are never closed")
+    }
+
+    /// Moves a queued job to running, a job paused while queued stays paused.
+    pub fn start(&self) -> bool {
+        self.state.send_if_modified(|s| {
+            if *s == TransferState::Queued {
+                *s = TransferState::Running;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Waits while the transfer is queued or paused, false once it is cancelled.
+    pub async fn wait_running(&mut self) -> bool {
+        loop {
+            match *self.rx.borrow_and_update() {
+                TransferState::Running => return true,
+                TransferState::Cancelled => return false,
+                _ => {}
+            }
+            if self.rx.changed().await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    /// Resolves on the next state change, meant for `select!`.
+    pub async fn changed(&mut self) {
+        if self.rx.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Running and queued transfers in both directions, keyed by a small local id.
+#[derive(Clone, Debug)]
+pub struct Transfers {
+    next: u32,
+    jobs: BTreeMap<u32, Transfer>,
+    /// Limits how many outgoing transfers stream at once.
+    slots: Arc<Semaphore>,
+}
+
+impl Default for Transfers {
+    fn default() -> Self {
+        Transfers::new(DEFAULT_MAX_TRANSFERS)
+    }
+}
+
+impl Transfers {
+    pub fn new(max: usize) -> Self {
+        Transfers {
+            next: 1,
+            jobs: BTreeMap::new(),
+            slots: Arc::new(Semaphore::new(max.max(1))),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        wire: u64,
+        name: String,
+        peer: String,
+        addr: SocketAddr,
+        direction: Direction,
+        size: u64,
+    ) -> Job {
+        let id = self.next;
+        self.next += 1;
+        // Outgoing transfers wait for a free slot first
+        let state = match direction {
+            Direction::Send => TransferState::Queued,
+            Direction::Receive => TransferState::Running,
+        };
+        let (tx, rx) = watch::channel(state);
+        let state = Arc::new(tx);
+        let total = size;
+        let size = Arc::new(AtomicU64::new(size));
+        let done = Arc::new(AtomicU64::new(0));
+        self.jobs.insert(
+            id,
+            Transfer {
+                wire,
+                name: name.clone(),
+                peer: peer.clone(),
+                addr,
+                direction,
+                size: size.clone(),
+                done: done.clone(),
+                state: state.clone(),
+            },
+        );
+        Job {
+            id,
+            wire,
+            addr,
+            name,
+            peer,
+            direction,
+            total,
+            started: Instant::now(),
+            key: None,
+            content: None,
+            compress: false,
+            throttle: Throttle::unlimited(direction),
+            size,
+            done,
+            state,
+            rx,
+            slots: self.slots.clone(),
+        }
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.jobs.remove(&id);
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Transfer> {
+        self.jobs.get(&id)
+    }
+
+    /// Looks up a transfer by the id both sides share.
+    pub fn find(&self, addr: SocketAddr, wire: u64) -> Option<(u32, &Transfer)> {
+        self.jobs
+            .iter()
+            .find(|(_, t)| t.addr == addr && t.wire == wire)
+            .map(|(id, t)| (*id, t))
+    }
+
+    pub fn display(&self) {
+        if self.jobs.is_empty() {
+            println!("No transfers");
+            return;
+        }
+        for (id, t) in self.jobs.iter() {
+            let done = t.done.load(Ordering::Relaxed);
+            let size = t.size.load(Ordering::Relaxed);
+            let percent = (done * 100).checked_div(size).unwrap_or(100);
+            let (arrow, side) = match t.direction {
+                Direction::Send => ("->", "to"),
+                Direction::Receive => ("<-", "from"),
+            };
+            println!(
+                "{:>3} {} {} {} {}  {}/{} ({}%)  {}",
+                id,
+                arrow,
+                t.name,
+                side,
+                t.peer,
+                HumanBytes(done),
+                HumanBytes(size),
+                percent,
+                t.state()
+            );
+        }
+    }
+}